@@ -0,0 +1,428 @@
+use std::future::Future;
+use std::path::PathBuf;
+
+use log::{info, warn};
+use tokio::sync::Mutex;
+
+use crate::api::models::auth::DeviceCredential;
+use crate::api::requests::{self, ApiConfig, ApiResult};
+use crate::executor::now_ms;
+use crate::localstore::{query_data, write_private, write_single};
+use crate::models::db::common::Id;
+use crate::models::HandlerError;
+
+pub const CREDENTIAL_KEY: &str = "device_credential";
+/// tokens are refreshed this long before they expire
+const REFRESH_MARGIN_SECS: u64 = 60;
+
+pub trait CredentialStore: Send + Sync {
+    fn load(&self) -> Option<DeviceCredential>;
+    fn save(&self, credential: &DeviceCredential) -> Result<(), HandlerError>;
+    /// the secret requests are signed with, see `api::signing`
+    fn load_signing_secret(&self) -> Option<String>;
+    fn save_signing_secret(&self, secret: &str) -> Result<(), HandlerError>;
+}
+
+/**
+ * credential kept as json under `device_credential` in the localstore, and the signing secret
+ * in its own file readable by the owner only
+ */
+pub struct LocalstoreCredentials {
+    signing_secret_path: PathBuf,
+}
+
+impl LocalstoreCredentials {
+    pub fn new(signing_secret_path: PathBuf) -> Self {
+        LocalstoreCredentials {
+            signing_secret_path,
+        }
+    }
+}
+
+impl CredentialStore for LocalstoreCredentials {
+    fn load(&self) -> Option<DeviceCredential> {
+        let data = query_data(CREDENTIAL_KEY).ok().flatten()?;
+        serde_json::from_str(&data).ok()
+    }
+
+    fn save(&self, credential: &DeviceCredential) -> Result<(), HandlerError> {
+        let data = serde_json::to_string(credential)?;
+        write_single(&data, CREDENTIAL_KEY)
+    }
+
+    fn load_signing_secret(&self) -> Option<String> {
+        let secret = std::fs::read_to_string(&self.signing_secret_path).ok()?;
+        Some(secret.trim().to_string()).filter(|secret| !secret.is_empty())
+    }
+
+    fn save_signing_secret(&self, secret: &str) -> Result<(), HandlerError> {
+        write_private(&self.signing_secret_path, secret.as_bytes())
+    }
+}
+
+/// what the daemon needs to re-enroll itself when its credential is rejected
+pub struct Enrollment {
+    pub device_id: Id,
+    pub user_id: Id,
+    pub user_secret: String,
+    pub device_name: String,
+}
+
+/**
+ * owns the device credential:
+ * - requests run through `authorized`, which attaches the bearer token
+ * - a token about to expire is refreshed first
+ * - a 401 re-enrolls the device with the stored user secret and retries once
+ *
+ * devices registered before the server issued credentials have none and stay unauthenticated
+ * until a 401 makes them re-enroll. the signing secret from registration (or a re-enrollment
+ * that issues a new one) is attached to every config as well.
+ */
+pub struct DeviceAuth {
+    config: ApiConfig,
+    enrollment: Enrollment,
+    store: Box<dyn CredentialStore>,
+    credential: Mutex<Option<DeviceCredential>>,
+    signing_secret: std::sync::Mutex<Option<String>>,
+}
+
+impl DeviceAuth {
+    pub fn new(config: ApiConfig, enrollment: Enrollment, store: Box<dyn CredentialStore>) -> Self {
+        let credential = store.load();
+        let signing_secret = store.load_signing_secret();
+        DeviceAuth {
+            config,
+            enrollment,
+            store,
+            credential: Mutex::new(credential),
+            signing_secret: std::sync::Mutex::new(signing_secret),
+        }
+    }
+
+    pub fn config(&self) -> &ApiConfig {
+        &self.config
+    }
+
+    pub fn signing_secret(&self) -> Option<String> {
+        self.signing_secret.lock().unwrap().clone()
+    }
+
+    /// current bearer token, refreshed first if it is about to expire
+    pub async fn token(&self) -> ApiResult<Option<String>> {
+        let mut credential = self.credential.lock().await;
+        let current = match credential.as_ref() {
+            None => return Ok(None),
+            Some(current) if !current.expires_within(now_secs(), REFRESH_MARGIN_SECS) => {
+                return Ok(Some(current.token.clone()))
+            }
+            Some(current) => current.clone(),
+        };
+
+        info!("device token expires soon, refreshing");
+        let config = self.with_token(Some(current.token.clone()));
+        let refreshed = match requests::refresh_device_token::refresh_device_token(
+            &self.enrollment.device_id,
+            &config,
+        )
+        .await
+        {
+            Ok(refreshed) => refreshed,
+            Err(HandlerError::Unauthorized) => self.enroll().await?,
+            Err(e) => return Err(e),
+        };
+        self.store.save(&refreshed)?;
+        *credential = Some(refreshed.clone());
+        Ok(Some(refreshed.token))
+    }
+
+    /// re-enrolls after `rejected` got a 401, unless another caller already replaced it
+    pub async fn reenroll(&self, rejected: Option<&str>) -> ApiResult<String> {
+        let mut credential = self.credential.lock().await;
+        if let Some(current) = credential.as_ref() {
+            if Some(current.token.as_str()) != rejected {
+                return Ok(current.token.clone());
+            }
+        }
+
+        let enrolled = self.enroll().await?;
+        self.store.save(&enrolled)?;
+        *credential = Some(enrolled.clone());
+        Ok(enrolled.token)
+    }
+
+    async fn enroll(&self) -> ApiResult<DeviceCredential> {
+        warn!(
+            "re-enrolling device {} with the user secret",
+            &self.enrollment.device_id
+        );
+        let response = requests::reenroll_device::reenroll_device(
+            &self.enrollment.device_id,
+            &self.enrollment.user_id,
+            &self.enrollment.user_secret,
+            self.enrollment.device_name.clone(),
+            &self.config,
+        )
+        .await?;
+        if let Some(secret) = &response.signing_secret {
+            self.store.save_signing_secret(secret)?;
+            *self.signing_secret.lock().unwrap() = Some(secret.clone());
+        }
+        response.credential.ok_or(HandlerError::Unauthorized)
+    }
+
+    /// runs `call` with an authorized config, re-enrolling and retrying once on a 401
+    pub async fn authorized<T, F, Fut>(&self, call: F) -> ApiResult<T>
+    where
+        F: Fn(ApiConfig) -> Fut,
+        Fut: Future<Output = ApiResult<T>>,
+    {
+        let token = self.token().await?;
+        let config = self.with_token(token.clone());
+        match call(config).await {
+            Err(HandlerError::Unauthorized) => {
+                let token = self.reenroll(token.as_deref()).await?;
+                call(self.with_token(Some(token))).await
+            }
+            other => other,
+        }
+    }
+
+    fn with_token(&self, token: Option<String>) -> ApiConfig {
+        ApiConfig {
+            token,
+            signing_secret: self.signing_secret(),
+            ..self.config.clone()
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    now_ms() / 1000
+}
+
+#[cfg(test)]
+pub mod test_auth {
+    use std::sync::Mutex;
+
+    use crate::api::models::auth::DeviceCredential;
+    use crate::api::requests::ApiConfig;
+    use crate::models::HandlerError;
+
+    use super::{CredentialStore, DeviceAuth, Enrollment};
+
+    /// the signing secret `with_token` hands out
+    pub const SIGNING_SECRET: &str = "testsigningsecret";
+
+    /// keeps the credential and signing secret in memory instead of the localstore
+    #[derive(Default)]
+    pub struct MemoryCredentials(
+        pub Mutex<Option<DeviceCredential>>,
+        pub Mutex<Option<String>>,
+    );
+
+    impl CredentialStore for MemoryCredentials {
+        fn load(&self) -> Option<DeviceCredential> {
+            self.0.lock().unwrap().clone()
+        }
+
+        fn save(&self, credential: &DeviceCredential) -> Result<(), HandlerError> {
+            *self.0.lock().unwrap() = Some(credential.clone());
+            Ok(())
+        }
+
+        fn load_signing_secret(&self) -> Option<String> {
+            self.1.lock().unwrap().clone()
+        }
+
+        fn save_signing_secret(&self, secret: &str) -> Result<(), HandlerError> {
+            *self.1.lock().unwrap() = Some(secret.to_string());
+            Ok(())
+        }
+    }
+
+    /// auth already holding `token`, which never expires, and `SIGNING_SECRET`
+    pub fn with_token(config: ApiConfig, token: &str) -> DeviceAuth {
+        let credential = DeviceCredential {
+            token: token.to_string(),
+            expires_at: None,
+        };
+        let enrollment = Enrollment {
+            device_id: "testdeviceid".to_string(),
+            user_id: "testuserid".to_string(),
+            user_secret: "testsecret".to_string(),
+            device_name: "testdevicename".to_string(),
+        };
+        DeviceAuth::new(
+            config,
+            enrollment,
+            Box::new(MemoryCredentials(
+                Mutex::new(Some(credential)),
+                Mutex::new(Some(SIGNING_SECRET.to_string())),
+            )),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use mockito::Matcher;
+
+    use crate::{
+        api::models::auth::DeviceCredential,
+        models::HandlerError,
+        test_commons::{before_each, setup_server},
+    };
+
+    use super::{test_auth::MemoryCredentials, DeviceAuth, Enrollment};
+
+    fn credential(token: &str, expires_at: Option<u64>) -> DeviceCredential {
+        DeviceCredential {
+            token: token.to_string(),
+            expires_at,
+        }
+    }
+
+    fn get_auth(
+        config: crate::api::requests::ApiConfig,
+        stored: Option<DeviceCredential>,
+    ) -> DeviceAuth {
+        let store = MemoryCredentials(Mutex::new(stored), Mutex::new(None));
+        let enrollment = Enrollment {
+            device_id: "testdeviceid".to_string(),
+            user_id: "testuserid".to_string(),
+            user_secret: "testsecret".to_string(),
+            device_name: "testdevicename".to_string(),
+        };
+        DeviceAuth::new(config, enrollment, Box::new(store))
+    }
+
+    fn reenroll_body(token: &str) -> String {
+        format!(
+            r#"{{"device_id": "testdeviceid", "credential": {{"token": "{}"}}}}"#,
+            token
+        )
+    }
+
+    #[tokio::test]
+    async fn test_valid_token_is_used_as_is() {
+        before_each();
+
+        let (_server, config) = setup_server();
+        let auth = get_auth(config, Some(credential("current", None)));
+
+        assert_eq!(auth.token().await.unwrap().as_deref(), Some("current"));
+    }
+
+    #[tokio::test]
+    async fn test_expiring_token_is_refreshed() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+        let mock = server
+            .mock("POST", "/devices/token/refresh")
+            .match_header("authorization", "Bearer old")
+            .with_status(200)
+            .with_body(r#"{"token": "fresh", "expires_at": null}"#)
+            .create();
+
+        let auth = get_auth(config, Some(credential("old", Some(1))));
+
+        assert_eq!(auth.token().await.unwrap().as_deref(), Some("fresh"));
+        assert_eq!(auth.store.load().unwrap().token, "fresh");
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_401_reenrolls_and_retries_once() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+        let reenroll = server
+            .mock("POST", "/devices/reenroll")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"device_id": "testdeviceid", "user_secret": "testsecret"}"#.to_string(),
+            ))
+            .with_status(200)
+            .with_body(reenroll_body("fresh"))
+            .create();
+
+        let auth = get_auth(config, Some(credential("revoked", None)));
+        let seen = Mutex::new(vec![]);
+        let result = auth
+            .authorized(|config| {
+                let token = config.token.clone().unwrap();
+                seen.lock().unwrap().push(token.clone());
+                async move {
+                    if token == "fresh" {
+                        Ok(())
+                    } else {
+                        Err(HandlerError::Unauthorized)
+                    }
+                }
+            })
+            .await;
+
+        assert!(result.is_ok());
+        assert_eq!(*seen.lock().unwrap(), vec!["revoked", "fresh"]);
+        assert_eq!(auth.store.load().unwrap().token, "fresh");
+        reenroll.assert();
+    }
+
+    #[tokio::test]
+    async fn test_reenroll_keeps_the_issued_signing_secret() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+        let reenroll = server
+            .mock("POST", "/devices/reenroll")
+            .with_status(200)
+            .with_body(
+                r#"{"device_id": "testdeviceid", "credential": {"token": "fresh"}, "signing_secret": "issued"}"#,
+            )
+            .create();
+
+        let auth = get_auth(config, Some(credential("revoked", None)));
+        let seen = Mutex::new(vec![]);
+        let _ = auth
+            .authorized(|config| {
+                seen.lock().unwrap().push(config.signing_secret.clone());
+                async move {
+                    match config.token.as_deref() {
+                        Some("fresh") => Ok(()),
+                        _ => Err(HandlerError::Unauthorized),
+                    }
+                }
+            })
+            .await;
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![None, Some("issued".to_string())]
+        );
+        assert_eq!(auth.store.load_signing_secret().as_deref(), Some("issued"));
+        assert_eq!(auth.signing_secret().as_deref(), Some("issued"));
+        reenroll.assert();
+    }
+
+    #[tokio::test]
+    async fn test_failed_reenroll_is_unauthorized() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+        let reenroll = server
+            .mock("POST", "/devices/reenroll")
+            .with_status(401)
+            .with_body("bad secret")
+            .create();
+
+        let auth = get_auth(config, None);
+        let result: Result<(), _> = auth
+            .authorized(|_| async { Err(HandlerError::Unauthorized) })
+            .await;
+
+        assert!(matches!(result, Err(HandlerError::Unauthorized)));
+        reenroll.assert();
+    }
+}
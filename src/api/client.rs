@@ -0,0 +1,588 @@
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use log::{info, warn};
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkey::PKey;
+use openssl::x509::X509;
+use reqwest::{Certificate, Identity, NoProxy, Proxy};
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{RootCertStore, ServerName};
+
+use crate::config::{get_config, ApiSettings, HttpSettings};
+use crate::models::HandlerError;
+
+static SHARED: RwLock<Option<Arc<Shared>>> = RwLock::new(None);
+
+/// client certificate presented on every api call, see `api::identity`
+#[derive(Clone)]
+pub struct ClientIdentity {
+    /// leaf first, then any intermediates
+    pub certificate_pem: String,
+    pub key_pkcs8_pem: String,
+}
+
+struct Shared {
+    settings: HttpSettings,
+    api: ApiSettings,
+    client: reqwest::Client,
+    websocket_tls: Option<Arc<rustls::ClientConfig>>,
+}
+
+impl Shared {
+    fn new(
+        settings: &HttpSettings,
+        api: &ApiSettings,
+        identity: Option<&ClientIdentity>,
+    ) -> Result<Self, HandlerError> {
+        Ok(Shared {
+            client: build_client(settings, api, identity)?,
+            websocket_tls: websocket_tls(settings, api, identity)?.map(Arc::new),
+            settings: settings.clone(),
+            api: api.clone(),
+        })
+    }
+}
+
+/// builds the shared client from the config; call once at startup, before any api call
+pub fn init(settings: &HttpSettings, api: &ApiSettings) -> Result<(), HandlerError> {
+    let shared = Shared::new(settings, api, None)?;
+    *SHARED.write().unwrap() = Some(Arc::new(shared));
+    Ok(())
+}
+
+/// rebuilds the shared client to present `identity` from now on
+pub fn set_identity(identity: ClientIdentity) -> Result<(), HandlerError> {
+    let current = get_shared();
+    let shared = Shared::new(&current.settings, &current.api, Some(&identity))?;
+    *SHARED.write().unwrap() = Some(Arc::new(shared));
+    Ok(())
+}
+
+fn get_shared() -> Arc<Shared> {
+    if let Some(shared) = SHARED.read().unwrap().as_ref() {
+        return shared.clone();
+    }
+    let mut shared = SHARED.write().unwrap();
+    shared
+        .get_or_insert_with(|| {
+            let config = get_config();
+            let built = Shared::new(&config.http, &config.api, None);
+            Arc::new(built.expect("invalid http settings"))
+        })
+        .clone()
+}
+
+/// the shared client, built from the global config if `init` was never called
+pub fn shared() -> reqwest::Client {
+    get_shared().client.clone()
+}
+
+/**
+ * tls for the command websocket, which does not go through reqwest: `None` (the system
+ * defaults) unless `http.ca_bundle`, `http.pinned_keys` or a client certificate are set, so
+ * those hold for the socket as well. proxies are not supported for it.
+ */
+pub fn websocket_connector() -> Option<tokio_tungstenite::Connector> {
+    get_shared()
+        .websocket_tls
+        .clone()
+        .map(tokio_tungstenite::Connector::Rustls)
+}
+
+fn websocket_tls(
+    settings: &HttpSettings,
+    api: &ApiSettings,
+    identity: Option<&ClientIdentity>,
+) -> Result<Option<rustls::ClientConfig>, HandlerError> {
+    if settings.ca_bundle.is_none() && settings.pinned_keys.is_empty() && identity.is_none() {
+        return Ok(None);
+    }
+    let config = rustls_config(settings, api, identity, vec![b"http/1.1".to_vec()])?;
+    Ok(Some(config))
+}
+
+/**
+ * one client for every api call, so connections are pooled and kept alive between polls:
+ * - connect and whole-request timeouts, idle pool and tcp keep-alive from `http`
+ * - http/2 negotiated over tls unless `http.http2` is off, or spoken straight away with
+ *   `http.http2_prior_knowledge`
+ * - `http.proxy` (http, https or socks5), and the usual proxy env vars unless
+ *   `http.env_proxy` is off
+ * - `http.ca_bundle` trusted on top of the system roots
+ * - with `http.pinned_keys`, the handshake with the control plane fails unless its
+ *   certificate's public key is one of the pins. this needs a hook into certificate
+ *   verification that native-tls does not have, so pinned clients use rustls instead.
+ * - `identity`, the device's client certificate, for mutual tls
+ */
+pub fn build_client(
+    settings: &HttpSettings,
+    api: &ApiSettings,
+    identity: Option<&ClientIdentity>,
+) -> Result<reqwest::Client, HandlerError> {
+    let mut builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(settings.connect_timeout_secs))
+        .pool_idle_timeout(Duration::from_secs(settings.pool_idle_secs))
+        .tcp_keepalive(settings.tcp_keepalive_secs.map(Duration::from_secs));
+    if let Some(secs) = settings.request_timeout_secs {
+        builder = builder.timeout(Duration::from_secs(secs));
+    }
+    // every test runs in its own runtime, so connections pooled by one cannot serve the next
+    if cfg!(test) {
+        builder = builder.pool_max_idle_per_host(0);
+    }
+
+    if !settings.http2 {
+        builder = builder.http1_only();
+    } else if settings.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+
+    if !settings.env_proxy {
+        builder = builder.no_proxy();
+    }
+    if let Some(url) = &settings.proxy {
+        let proxy = Proxy::all(url)
+            .map_err(|e| HandlerError::ConfigError(format!("invalid http.proxy: {}", e)))?;
+        let proxy = if settings.env_proxy {
+            proxy.no_proxy(NoProxy::from_env())
+        } else {
+            proxy
+        };
+        builder = builder.proxy(proxy);
+    }
+
+    if settings.pinned_keys.is_empty() {
+        if let Some(path) = &settings.ca_bundle {
+            for der in read_ca_bundle(path)? {
+                builder = builder.add_root_certificate(Certificate::from_der(&der)?);
+            }
+        }
+        if let Some(identity) = identity {
+            builder = builder.identity(Identity::from_pkcs8_pem(
+                identity.certificate_pem.as_bytes(),
+                identity.key_pkcs8_pem.as_bytes(),
+            )?);
+        }
+    } else {
+        let alpn = if settings.http2 {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        };
+        builder = builder.use_preconfigured_tls(rustls_config(settings, api, identity, alpn)?);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// der of every certificate in a pem file
+fn read_ca_bundle(path: &str) -> Result<Vec<Vec<u8>>, HandlerError> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        HandlerError::ConfigError(format!("cannot read http.ca_bundle {}: {}", path, e))
+    };
+    let pem = std::fs::read(path).map_err(|e| invalid(&e))?;
+    let certs = X509::stack_from_pem(&pem).map_err(|e| invalid(&e))?;
+    if certs.is_empty() {
+        return Err(invalid(&"no certificates found"));
+    }
+    certs
+        .iter()
+        .map(|cert| cert.to_der().map_err(|e| invalid(&e)))
+        .collect()
+}
+
+/// a pin is the base64 sha256 of a der encoded subject public key info
+pub fn parse_pin(pin: &str) -> Result<[u8; 32], HandlerError> {
+    let invalid = || {
+        HandlerError::ConfigError(format!(
+            "http.pinned_keys entry {:?} is not a base64 sha256",
+            pin
+        ))
+    };
+    let bytes = STANDARD.decode(pin.trim()).map_err(|_| invalid())?;
+    bytes.try_into().map_err(|_| invalid())
+}
+
+/// the pin of a der encoded certificate
+pub fn certificate_pin(der: &[u8]) -> Result<[u8; 32], HandlerError> {
+    let spki = X509::from_der(der)?.public_key()?.public_key_to_der()?;
+    let digest = hash(MessageDigest::sha256(), &spki)?;
+    let mut pin = [0; 32];
+    pin.copy_from_slice(&digest);
+    Ok(pin)
+}
+
+/// system roots plus `http.ca_bundle`, checked against `http.pinned_keys` if there are any
+fn rustls_config(
+    settings: &HttpSettings,
+    api: &ApiSettings,
+    identity: Option<&ClientIdentity>,
+    alpn: Vec<Vec<u8>>,
+) -> Result<rustls::ClientConfig, HandlerError> {
+    let mut roots = RootCertStore::empty();
+    let native: Vec<Vec<u8>> = rustls_native_certs::load_native_certs()?
+        .into_iter()
+        .map(|cert| cert.0)
+        .collect();
+    // a system store usually holds a few certificates rustls cannot parse
+    roots.add_parsable_certificates(&native);
+    if let Some(path) = &settings.ca_bundle {
+        for der in read_ca_bundle(path)? {
+            roots
+                .add(&rustls::Certificate(der))
+                .map_err(|e| HandlerError::ConfigError(format!("invalid http.ca_bundle: {}", e)))?;
+        }
+    }
+
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+    let builder = if settings.pinned_keys.is_empty() {
+        // the same checks `with_root_certificates` makes, typed like the pinned branch
+        builder.with_custom_certificate_verifier(Arc::new(WebPkiVerifier::new(roots, None)))
+    } else {
+        let pins = settings
+            .pinned_keys
+            .iter()
+            .map(|pin| parse_pin(pin))
+            .collect::<Result<Vec<_>, _>>()?;
+        info!(
+            "pinning {} key(s) for the control plane at {}",
+            pins.len(),
+            &api.host
+        );
+        let verifier = PinnedVerifier {
+            inner: WebPkiVerifier::new(roots, None),
+            host: api.host.clone(),
+            pins,
+        };
+        builder.with_custom_certificate_verifier(Arc::new(verifier))
+    };
+    let mut config = match identity {
+        Some(identity) => {
+            let chain = X509::stack_from_pem(identity.certificate_pem.as_bytes())?
+                .iter()
+                .map(|cert| cert.to_der().map(rustls::Certificate))
+                .collect::<Result<Vec<_>, _>>()?;
+            let key = PKey::private_key_from_pem(identity.key_pkcs8_pem.as_bytes())?
+                .private_key_to_pkcs8()?;
+            builder
+                .with_client_auth_cert(chain, rustls::PrivateKey(key))
+                .map_err(|e| {
+                    HandlerError::ConfigError(format!("invalid client certificate: {}", e))
+                })?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = alpn;
+    Ok(config)
+}
+
+/// the usual chain and hostname checks, plus the pins when talking to `host`
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    host: String,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let is_control_plane = match server_name {
+            ServerName::DnsName(name) => name.as_ref().eq_ignore_ascii_case(&self.host),
+            ServerName::IpAddress(ip) => ip.to_string() == self.host,
+            _ => false,
+        };
+        if !is_control_plane {
+            return Ok(verified);
+        }
+
+        let pin = certificate_pin(&end_entity.0)
+            .map_err(|e| rustls::Error::General(format!("cannot read certificate: {}", e)))?;
+        if self.pins.contains(&pin) {
+            Ok(verified)
+        } else {
+            warn!(
+                "control plane presented unpinned key {}",
+                STANDARD.encode(pin)
+            );
+            Err(rustls::Error::General(
+                "server key does not match http.pinned_keys".to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read as _, Write as _};
+    use std::net::TcpListener;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine as _;
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        hash::MessageDigest,
+        pkey::{PKey, Private},
+        rsa::Rsa,
+        ssl::{SslAcceptor, SslMethod, SslVerifyMode},
+        x509::{extension::SubjectAlternativeName, X509Builder, X509NameBuilder, X509},
+    };
+    use tempdir::TempDir;
+
+    use crate::{
+        config::{ApiSettings, HttpSettings},
+        models::HandlerError,
+        test_commons::{before_each, setup_server},
+    };
+
+    use super::{build_client, certificate_pin, parse_pin, websocket_tls, ClientIdentity};
+
+    fn self_signed() -> (Vec<u8>, Vec<u8>) {
+        let (cert, key) = self_signed_for("itx test");
+        (cert.to_pem().unwrap(), key.public_key_to_der().unwrap())
+    }
+
+    fn self_signed_for(host: &str) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", host).unwrap();
+        let name = name.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        builder.set_serial_number(&serial).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns(host)
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    /**
+     * https server for `localhost` answering every connection with a 200, on a thread. with
+     * `client_auth`, connections without a client certificate are refused (any certificate
+     * will do).
+     */
+    fn serve_tls(cert: X509, key: PKey<Private>, client_auth: bool) -> u16 {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        if client_auth {
+            let mode = SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+            acceptor.set_verify_callback(mode, |_, _| true);
+        }
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = acceptor.accept(stream.unwrap()) else {
+                    continue;
+                };
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                );
+                let _ = stream.shutdown();
+            }
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn test_pinned_key_is_enforced() {
+        let dir = TempDir::new("test-client").unwrap();
+        let path = dir.path().join("ca.pem");
+        let (cert, key) = self_signed_for("localhost");
+        std::fs::write(&path, cert.to_pem().unwrap()).unwrap();
+        let pin = STANDARD.encode(certificate_pin(&cert.to_der().unwrap()).unwrap());
+        let port = serve_tls(cert, key, false);
+        let url = format!("https://localhost:{}/status", port);
+        let api = ApiSettings {
+            scheme: "https".to_string(),
+            host: "localhost".to_string(),
+            port: Some(port),
+        };
+        let settings = |pin: String| HttpSettings {
+            ca_bundle: Some(path.display().to_string()),
+            pinned_keys: vec![pin],
+            env_proxy: false,
+            ..Default::default()
+        };
+
+        let client = build_client(&settings(pin), &api, None).unwrap();
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), 200);
+
+        let client = build_client(&settings(STANDARD.encode([7u8; 32])), &api, None).unwrap();
+        assert!(client.get(&url).send().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_is_presented() {
+        let dir = TempDir::new("test-client").unwrap();
+        let path = dir.path().join("ca.pem");
+        let (cert, key) = self_signed_for("localhost");
+        std::fs::write(&path, cert.to_pem().unwrap()).unwrap();
+        let pin = STANDARD.encode(certificate_pin(&cert.to_der().unwrap()).unwrap());
+        let port = serve_tls(cert, key, true);
+        let url = format!("https://localhost:{}/status", port);
+        let api = ApiSettings {
+            scheme: "https".to_string(),
+            host: "localhost".to_string(),
+            port: Some(port),
+        };
+        let (device_cert, device_key) = self_signed_for("device");
+        let identity = ClientIdentity {
+            certificate_pem: String::from_utf8(device_cert.to_pem().unwrap()).unwrap(),
+            key_pkcs8_pem: String::from_utf8(device_key.private_key_to_pem_pkcs8().unwrap())
+                .unwrap(),
+        };
+        let native = HttpSettings {
+            ca_bundle: Some(path.display().to_string()),
+            env_proxy: false,
+            ..Default::default()
+        };
+        // pins switch the client to rustls, which needs the identity in its own format
+        let rustls = HttpSettings {
+            pinned_keys: vec![pin],
+            ..native.clone()
+        };
+
+        for settings in [native, rustls] {
+            let client = build_client(&settings, &api, None).unwrap();
+            assert!(client.get(&url).send().await.is_err());
+
+            let client = build_client(&settings, &api, Some(&identity)).unwrap();
+            let response = client.get(&url).send().await.unwrap();
+            assert_eq!(response.status(), 200);
+        }
+    }
+
+    #[test]
+    fn test_certificate_pin_is_sha256_of_public_key() {
+        let (pem, spki) = self_signed();
+        let der = openssl::x509::X509::from_pem(&pem)
+            .unwrap()
+            .to_der()
+            .unwrap();
+
+        let pin = certificate_pin(&der).unwrap();
+
+        let expected = openssl::hash::hash(MessageDigest::sha256(), &spki).unwrap();
+        assert_eq!(pin.as_slice(), &*expected);
+        assert_eq!(parse_pin(&STANDARD.encode(pin)).unwrap(), pin);
+    }
+
+    #[test]
+    fn test_invalid_pin_is_rejected() {
+        assert!(matches!(
+            parse_pin("not base64!"),
+            Err(HandlerError::ConfigError(_))
+        ));
+        assert!(matches!(
+            parse_pin(&STANDARD.encode([0u8; 16])),
+            Err(HandlerError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_ca_bundle_and_pins_build_a_client() {
+        let dir = TempDir::new("test-client").unwrap();
+        let path = dir.path().join("ca.pem");
+        let (pem, _) = self_signed();
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(&pem)
+            .unwrap();
+        let settings = HttpSettings {
+            ca_bundle: Some(path.display().to_string()),
+            ..Default::default()
+        };
+        assert!(build_client(&settings, &ApiSettings::default(), None).is_ok());
+        assert!(websocket_tls(&settings, &ApiSettings::default(), None)
+            .unwrap()
+            .is_some());
+        assert!(
+            websocket_tls(&HttpSettings::default(), &ApiSettings::default(), None)
+                .unwrap()
+                .is_none()
+        );
+
+        let pinned = HttpSettings {
+            pinned_keys: vec![STANDARD.encode([7u8; 32])],
+            ..settings
+        };
+        assert!(build_client(&pinned, &ApiSettings::default(), None).is_ok());
+    }
+
+    #[test]
+    fn test_missing_ca_bundle_fails() {
+        let settings = HttpSettings {
+            ca_bundle: Some("/nonexistent/ca.pem".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            build_client(&settings, &ApiSettings::default(), None),
+            Err(HandlerError::ConfigError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_proxy_is_used() {
+        before_each();
+
+        // the mock server stands in for the proxy: the absolute url shows up as the path
+        let (mut server, config) = setup_server();
+        let mock = server
+            .mock("GET", "/status")
+            .match_header("host", "example.invalid")
+            .with_status(200)
+            .create();
+        let settings = HttpSettings {
+            proxy: Some(config.with_path("")),
+            env_proxy: false,
+            ..Default::default()
+        };
+        let client = build_client(&settings, &ApiSettings::default(), None).unwrap();
+
+        let response = client
+            .get("http://example.invalid/status")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), 200);
+        mock.assert();
+    }
+}
@@ -0,0 +1,573 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+
+use crate::api::auth::DeviceAuth;
+use crate::api::models::capabilities::DeviceCapabilities;
+use crate::api::models::heartbeat::HeartbeatRequest;
+use crate::api::models::register_device::RegisterDeviceResponse;
+use crate::api::models::upload_command_result::UploadCommandResultRequest;
+use crate::api::requests::{self, ApiConfig, ApiResult};
+use crate::api::retry::CircuitBreaker;
+use crate::api::websocket::{WebSocketControlPlane, WebSocketTransport};
+use crate::config::DaemonConfig;
+use crate::models::db::commands::{Command, CommandStatus};
+use crate::models::db::common::Id;
+use crate::models::db::output::OutputChunk;
+use crate::shutdown::Shutdown;
+
+/**
+ * everything the daemon needs from the server. the event loops only talk to this trait, so
+ * transports (http, websocket, in-memory for tests) can be swapped without touching them.
+ */
+pub trait ControlPlane: Send + Sync {
+    /// `csr` asks for a client certificate along with the registration
+    fn register_device<'a>(
+        &'a self,
+        user_id: &'a Id,
+        user_secret: &'a str,
+        device_name: String,
+        csr: Option<String>,
+        capabilities: Option<DeviceCapabilities>,
+    ) -> BoxFuture<'a, ApiResult<RegisterDeviceResponse>>;
+
+    /// the daemon's version and what it can run, see `capabilities`
+    fn advertise_capabilities<'a>(
+        &'a self,
+        device_id: &'a Id,
+        capabilities: &'a DeviceCapabilities,
+    ) -> BoxFuture<'a, ApiResult<()>>;
+
+    /// the device's health, see `heartbeat`
+    fn send_heartbeat<'a>(&'a self, request: &'a HeartbeatRequest) -> BoxFuture<'a, ApiResult<()>>;
+
+    /// a new pem client certificate for `csr`
+    fn renew_certificate<'a>(
+        &'a self,
+        device_id: &'a Id,
+        csr: String,
+    ) -> BoxFuture<'a, ApiResult<String>>;
+
+    /// the daemon binary of release `version`, for a self-update
+    fn download_update<'a>(&'a self, version: &'a str) -> BoxFuture<'a, ApiResult<Vec<u8>>>;
+
+    /// most recent command for the device, if any
+    fn fetch_command<'a>(&'a self, device_id: &'a Id) -> BoxFuture<'a, ApiResult<Option<Command>>>;
+
+    /// ids of commands the server asked to cancel since the last call
+    fn fetch_cancellations<'a>(&'a self, device_id: &'a Id) -> BoxFuture<'a, ApiResult<Vec<Id>>>;
+
+    fn update_command_status<'a>(
+        &'a self,
+        command: &'a Command,
+        new_status: CommandStatus,
+    ) -> BoxFuture<'a, ApiResult<()>>;
+
+    /// final result of a command, already cut down to the upload limits
+    fn upload_command_result<'a>(
+        &'a self,
+        request: &'a UploadCommandResultRequest,
+    ) -> BoxFuture<'a, ApiResult<()>>;
+
+    /// output of a still running command, in `seq` order
+    fn upload_output<'a>(
+        &'a self,
+        command: &'a Command,
+        chunks: &'a [OutputChunk],
+    ) -> BoxFuture<'a, ApiResult<()>>;
+
+    /// true when `fetch_command` itself waits for commands to be pushed, so the caller should
+    /// not sleep between fetches
+    fn is_push(&self) -> bool {
+        false
+    }
+}
+
+/// the plain request/response api in `api::requests`
+pub struct HttpControlPlane {
+    config: ApiConfig,
+    auth: Option<Arc<DeviceAuth>>,
+    gzip_min_bytes: Option<usize>,
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+impl HttpControlPlane {
+    pub fn new(config: ApiConfig) -> Self {
+        HttpControlPlane {
+            config,
+            auth: None,
+            gzip_min_bytes: None,
+            breaker: None,
+        }
+    }
+
+    /// sends the device's bearer token with every request except registration
+    pub fn authenticated(auth: Arc<DeviceAuth>) -> Self {
+        HttpControlPlane {
+            config: auth.config().clone(),
+            auth: Some(auth),
+            gzip_min_bytes: None,
+            breaker: None,
+        }
+    }
+
+    /// gzip result uploads whose body is at least `min_bytes`
+    pub fn with_result_gzip(self, min_bytes: Option<usize>) -> Self {
+        HttpControlPlane {
+            gzip_min_bytes: min_bytes,
+            ..self
+        }
+    }
+
+    /// refuse calls while `breaker` is open, see `CircuitBreaker`
+    pub fn with_circuit_breaker(self, breaker: Option<Arc<CircuitBreaker>>) -> Self {
+        HttpControlPlane { breaker, ..self }
+    }
+
+    async fn call<T, F, Fut>(&self, call: F) -> ApiResult<T>
+    where
+        F: Fn(ApiConfig) -> Fut,
+        Fut: Future<Output = ApiResult<T>>,
+    {
+        self.guarded(async {
+            match &self.auth {
+                Some(auth) => auth.authorized(call).await,
+                None => call(self.config.clone()).await,
+            }
+        })
+        .await
+    }
+
+    async fn guarded<T>(&self, call: impl Future<Output = ApiResult<T>>) -> ApiResult<T> {
+        let Some(breaker) = &self.breaker else {
+            return call.await;
+        };
+        breaker.allow()?;
+        let result = call.await;
+        breaker.record(&result);
+        result
+    }
+}
+
+impl Default for HttpControlPlane {
+    fn default() -> Self {
+        HttpControlPlane::new(ApiConfig::default())
+    }
+}
+
+impl ControlPlane for HttpControlPlane {
+    fn register_device<'a>(
+        &'a self,
+        user_id: &'a Id,
+        user_secret: &'a str,
+        device_name: String,
+        csr: Option<String>,
+        capabilities: Option<DeviceCapabilities>,
+    ) -> BoxFuture<'a, ApiResult<RegisterDeviceResponse>> {
+        Box::pin(self.guarded(requests::register_device::register_device(
+            user_id,
+            user_secret,
+            device_name,
+            csr,
+            capabilities,
+            &self.config,
+        )))
+    }
+
+    fn advertise_capabilities<'a>(
+        &'a self,
+        device_id: &'a Id,
+        capabilities: &'a DeviceCapabilities,
+    ) -> BoxFuture<'a, ApiResult<()>> {
+        Box::pin(self.call(move |config| async move {
+            requests::advertise_capabilities::advertise_capabilities(
+                device_id,
+                capabilities,
+                &config,
+            )
+            .await
+        }))
+    }
+
+    fn send_heartbeat<'a>(&'a self, request: &'a HeartbeatRequest) -> BoxFuture<'a, ApiResult<()>> {
+        Box::pin(self.call(move |config| async move {
+            requests::send_heartbeat::send_heartbeat(request, &config).await
+        }))
+    }
+
+    fn renew_certificate<'a>(
+        &'a self,
+        device_id: &'a Id,
+        csr: String,
+    ) -> BoxFuture<'a, ApiResult<String>> {
+        Box::pin(async move {
+            let response = self
+                .call(|config| {
+                    let csr = csr.clone();
+                    async move {
+                        requests::renew_device_certificate::renew_device_certificate(
+                            device_id, csr, &config,
+                        )
+                        .await
+                    }
+                })
+                .await?;
+            Ok(response.certificate)
+        })
+    }
+
+    fn download_update<'a>(&'a self, version: &'a str) -> BoxFuture<'a, ApiResult<Vec<u8>>> {
+        Box::pin(self.call(move |config| async move {
+            requests::download_update::download_update(version, &config).await
+        }))
+    }
+
+    fn fetch_command<'a>(&'a self, device_id: &'a Id) -> BoxFuture<'a, ApiResult<Option<Command>>> {
+        Box::pin(async move {
+            let response = self
+                .call(|config| async move {
+                    requests::fetch_commands::fetch_commands(device_id.clone(), &config).await
+                })
+                .await?;
+            Ok(response.map(|response| response.command))
+        })
+    }
+
+    fn fetch_cancellations<'a>(&'a self, device_id: &'a Id) -> BoxFuture<'a, ApiResult<Vec<Id>>> {
+        Box::pin(async move {
+            let response = self
+                .call(|config| async move {
+                    requests::fetch_cancellations::fetch_cancellations(device_id.clone(), &config)
+                        .await
+                })
+                .await?;
+            Ok(response.command_ids)
+        })
+    }
+
+    fn update_command_status<'a>(
+        &'a self,
+        command: &'a Command,
+        new_status: CommandStatus,
+    ) -> BoxFuture<'a, ApiResult<()>> {
+        Box::pin(self.call(move |config| {
+            let new_status = new_status.clone();
+            async move {
+                requests::update_command_status::update_command_status(command, new_status, &config)
+                    .await
+            }
+        }))
+    }
+
+    fn upload_command_result<'a>(
+        &'a self,
+        request: &'a UploadCommandResultRequest,
+    ) -> BoxFuture<'a, ApiResult<()>> {
+        Box::pin(self.call(move |config| async move {
+            requests::upload_command_result::upload_command_result(
+                request,
+                self.gzip_min_bytes,
+                &config,
+            )
+            .await
+        }))
+    }
+
+    fn upload_output<'a>(
+        &'a self,
+        command: &'a Command,
+        chunks: &'a [OutputChunk],
+    ) -> BoxFuture<'a, ApiResult<()>> {
+        Box::pin(self.call(move |config| async move {
+            requests::upload_command_output::upload_command_output(command, chunks, &config).await
+        }))
+    }
+}
+
+/// http only, or websocket with http fallback when `transport.websocket` is set
+pub fn connect(
+    config: &DaemonConfig,
+    device_id: &Id,
+    auth: Arc<DeviceAuth>,
+    shutdown: &Shutdown,
+) -> Arc<dyn ControlPlane> {
+    let http = HttpControlPlane::authenticated(auth.clone())
+        .with_result_gzip(config.result.gzip_min_bytes)
+        .with_circuit_breaker(CircuitBreaker::from_settings(&config.retry).map(Arc::new));
+    if !config.transport.websocket {
+        return Arc::new(http);
+    }
+
+    let path = format!(
+        "{}?device_id={}",
+        config.transport.websocket_path, device_id
+    );
+    let url = ApiConfig::from(&config.api).with_ws_path(&path);
+    let reconnect = Duration::from_secs(config.transport.reconnect_secs);
+    let transport = WebSocketTransport::spawn(url, reconnect, Some(auth), shutdown.clone());
+    let wait = Duration::from_secs(config.poll.medium_secs);
+    Arc::new(WebSocketControlPlane::new(transport, http, wait))
+}
+
+#[cfg(test)]
+pub use in_memory::InMemoryControlPlane;
+
+#[cfg(test)]
+mod in_memory {
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use futures::future::BoxFuture;
+
+    use super::ControlPlane;
+    use crate::api::models::capabilities::DeviceCapabilities;
+    use crate::api::models::heartbeat::HeartbeatRequest;
+    use crate::api::models::register_device::RegisterDeviceResponse;
+    use crate::api::models::upload_command_result::UploadCommandResultRequest;
+    use crate::api::requests::ApiResult;
+    use crate::models::db::commands::{Command, CommandStatus};
+    use crate::models::db::common::{HasId, Id};
+    use crate::models::db::output::OutputChunk;
+    use crate::models::db::results::ExecutionResult;
+    use crate::models::HandlerError;
+
+    /// serves queued commands and records everything the daemon sends back
+    #[derive(Default)]
+    pub struct InMemoryControlPlane {
+        pub device_id: Id,
+        commands: Mutex<VecDeque<Command>>,
+        cancellations: Mutex<Vec<Id>>,
+        registrations: Mutex<Vec<String>>,
+        advertisements: Mutex<Vec<DeviceCapabilities>>,
+        heartbeats: Mutex<Vec<HeartbeatRequest>>,
+        csrs: Mutex<Vec<String>>,
+        certificate: Mutex<Option<String>>,
+        releases: Mutex<HashMap<String, Vec<u8>>>,
+        statuses: Mutex<Vec<(Id, CommandStatus)>>,
+        results: Mutex<Vec<(Id, ExecutionResult)>>,
+        output: Mutex<Vec<(Id, Vec<OutputChunk>)>>,
+        failures: AtomicUsize,
+    }
+
+    impl InMemoryControlPlane {
+        pub fn new(device_id: &str) -> Self {
+            InMemoryControlPlane {
+                device_id: device_id.to_string(),
+                ..Default::default()
+            }
+        }
+
+        pub fn push_command(&self, command: Command) {
+            self.commands.lock().unwrap().push_back(command);
+        }
+
+        /// the next `count` status updates and result uploads fail with a server error
+        pub fn fail_next(&self, count: usize) {
+            self.failures.store(count, Ordering::SeqCst);
+        }
+
+        fn should_fail(&self) -> bool {
+            self.failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+        }
+
+        pub fn cancel_command(&self, command_id: &str) {
+            self.cancellations
+                .lock()
+                .unwrap()
+                .push(command_id.to_string());
+        }
+
+        pub fn registrations(&self) -> Vec<String> {
+            self.registrations.lock().unwrap().clone()
+        }
+
+        /// capabilities received, at registration or advertised afterwards
+        pub fn advertisements(&self) -> Vec<DeviceCapabilities> {
+            self.advertisements.lock().unwrap().clone()
+        }
+
+        pub fn heartbeats(&self) -> Vec<HeartbeatRequest> {
+            self.heartbeats.lock().unwrap().clone()
+        }
+
+        /// issued for every csr from now on, at registration or renewal
+        pub fn issue_certificate(&self, pem: &str) {
+            *self.certificate.lock().unwrap() = Some(pem.to_string());
+        }
+
+        /// served by `download_update` for `version`
+        pub fn publish_release(&self, version: &str, binary: &[u8]) {
+            self.releases
+                .lock()
+                .unwrap()
+                .insert(version.to_string(), binary.to_vec());
+        }
+
+        /// csrs received, at registration or renewal
+        pub fn csrs(&self) -> Vec<String> {
+            self.csrs.lock().unwrap().clone()
+        }
+
+        pub fn statuses(&self) -> Vec<(Id, CommandStatus)> {
+            self.statuses.lock().unwrap().clone()
+        }
+
+        pub fn results(&self) -> Vec<(Id, ExecutionResult)> {
+            self.results.lock().unwrap().clone()
+        }
+
+        /// uploaded output batches
+        pub fn output(&self) -> Vec<(Id, Vec<OutputChunk>)> {
+            self.output.lock().unwrap().clone()
+        }
+    }
+
+    impl ControlPlane for InMemoryControlPlane {
+        fn register_device<'a>(
+            &'a self,
+            _user_id: &'a Id,
+            _user_secret: &'a str,
+            device_name: String,
+            csr: Option<String>,
+            capabilities: Option<DeviceCapabilities>,
+        ) -> BoxFuture<'a, ApiResult<RegisterDeviceResponse>> {
+            self.registrations.lock().unwrap().push(device_name);
+            self.advertisements.lock().unwrap().extend(capabilities);
+            let mut response = RegisterDeviceResponse::new(self.device_id.clone());
+            if let Some(csr) = csr {
+                self.csrs.lock().unwrap().push(csr);
+                response.certificate = self.certificate.lock().unwrap().clone();
+            }
+            Box::pin(async move { Ok(response) })
+        }
+
+        fn advertise_capabilities<'a>(
+            &'a self,
+            _device_id: &'a Id,
+            capabilities: &'a DeviceCapabilities,
+        ) -> BoxFuture<'a, ApiResult<()>> {
+            if self.should_fail() {
+                return Box::pin(async { Err(HandlerError::ServerError) });
+            }
+            self.advertisements
+                .lock()
+                .unwrap()
+                .push(capabilities.clone());
+            Box::pin(async { Ok(()) })
+        }
+
+        fn send_heartbeat<'a>(
+            &'a self,
+            request: &'a HeartbeatRequest,
+        ) -> BoxFuture<'a, ApiResult<()>> {
+            if self.should_fail() {
+                return Box::pin(async { Err(HandlerError::ServerError) });
+            }
+            self.heartbeats.lock().unwrap().push(request.clone());
+            Box::pin(async { Ok(()) })
+        }
+
+        fn renew_certificate<'a>(
+            &'a self,
+            _device_id: &'a Id,
+            csr: String,
+        ) -> BoxFuture<'a, ApiResult<String>> {
+            self.csrs.lock().unwrap().push(csr);
+            let certificate = self.certificate.lock().unwrap().clone();
+            Box::pin(async move { certificate.ok_or(HandlerError::NotFound) })
+        }
+
+        fn download_update<'a>(&'a self, version: &'a str) -> BoxFuture<'a, ApiResult<Vec<u8>>> {
+            let binary = self.releases.lock().unwrap().get(version).cloned();
+            Box::pin(async move { binary.ok_or(HandlerError::NotFound) })
+        }
+
+        fn fetch_command<'a>(
+            &'a self,
+            _device_id: &'a Id,
+        ) -> BoxFuture<'a, ApiResult<Option<Command>>> {
+            let command = self.commands.lock().unwrap().pop_front();
+            Box::pin(async move { Ok(command) })
+        }
+
+        fn fetch_cancellations<'a>(
+            &'a self,
+            _device_id: &'a Id,
+        ) -> BoxFuture<'a, ApiResult<Vec<Id>>> {
+            let command_ids = std::mem::take(&mut *self.cancellations.lock().unwrap());
+            Box::pin(async move { Ok(command_ids) })
+        }
+
+        fn update_command_status<'a>(
+            &'a self,
+            command: &'a Command,
+            new_status: CommandStatus,
+        ) -> BoxFuture<'a, ApiResult<()>> {
+            if self.should_fail() {
+                return Box::pin(async move { Err(HandlerError::ServerError) });
+            }
+            self.statuses
+                .lock()
+                .unwrap()
+                .push((command.get_id().clone(), new_status));
+            Box::pin(async move { Ok(()) })
+        }
+
+        fn upload_command_result<'a>(
+            &'a self,
+            request: &'a UploadCommandResultRequest,
+        ) -> BoxFuture<'a, ApiResult<()>> {
+            if self.should_fail() {
+                return Box::pin(async move { Err(HandlerError::ServerError) });
+            }
+            self.results
+                .lock()
+                .unwrap()
+                .push((request.command_id.clone(), request.result.clone()));
+            Box::pin(async move { Ok(()) })
+        }
+
+        fn upload_output<'a>(
+            &'a self,
+            command: &'a Command,
+            chunks: &'a [OutputChunk],
+        ) -> BoxFuture<'a, ApiResult<()>> {
+            self.output
+                .lock()
+                .unwrap()
+                .push((command.get_id().clone(), chunks.to_vec()));
+            Box::pin(async move { Ok(()) })
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        api::control_plane::{ControlPlane, HttpControlPlane},
+        api::models::fetch_commands::FetchRecentCommandResponse,
+        models::db::{commands::Command, common::HasId},
+        test_commons::{before_each, setup_signed_server},
+    };
+
+    #[tokio::test]
+    async fn test_http_fetch_command_unwraps_response() {
+        before_each();
+
+        let command = Command::default();
+        let json =
+            serde_json::to_string(&FetchRecentCommandResponse::new(command.clone())).unwrap();
+        let config = setup_signed_server("testsecret", json);
+
+        let control_plane = HttpControlPlane::new(config);
+        let result = control_plane.fetch_command(&command.device_id).await;
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().unwrap().get_id(), command.get_id());
+    }
+}
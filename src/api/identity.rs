@@ -0,0 +1,259 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+use openssl::asn1::Asn1Time;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::x509::{X509NameBuilder, X509ReqBuilder, X509};
+
+use crate::api::client::{self, ClientIdentity};
+use crate::api::control_plane::ControlPlane;
+use crate::api::retry::{Backoff, RetryPolicy};
+use crate::localstore::{query_data, write_private, write_single};
+use crate::models::db::common::Id;
+use crate::models::HandlerError;
+use crate::shutdown::Shutdown;
+
+/// localstore key of the pem certificate chain issued for the device key
+pub const CERTIFICATE_KEY: &str = "device_certificate";
+/// how often `keep_renewed` looks at the certificate's expiry
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/**
+ * the device's key pair for mutual tls.
+ *
+ * the key is generated on first run and never leaves the device: the control plane gets a
+ * csr for it (at registration, and again on every renewal) and answers with a certificate,
+ * which is kept in the localstore and presented on every api call through `client`.
+ */
+pub struct DeviceIdentity {
+    key: PKey<Private>,
+}
+
+impl DeviceIdentity {
+    /// reads the p-256 key at `path`, creating it (readable by the owner only) if missing
+    pub fn load_or_create(path: &Path) -> Result<Self, HandlerError> {
+        if path.exists() {
+            let key = PKey::private_key_from_pem(&std::fs::read(path)?)?;
+            return Ok(DeviceIdentity { key });
+        }
+        info!("generating device key at {}", path.display());
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        write_private(path, &key.private_key_to_pem_pkcs8()?)?;
+        Ok(DeviceIdentity { key })
+    }
+
+    /// pem csr for the device key, with `common_name` as the subject
+    pub fn csr(&self, common_name: &str) -> Result<String, HandlerError> {
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+        let mut builder = X509ReqBuilder::new()?;
+        builder.set_subject_name(&name.build())?;
+        builder.set_pubkey(&self.key)?;
+        builder.sign(&self.key, MessageDigest::sha256())?;
+        Ok(String::from_utf8(builder.build().to_pem()?)?)
+    }
+
+    /// the leaf of `pem`, if it certifies the device key
+    pub fn parse_certificate(&self, pem: &str) -> Result<X509, HandlerError> {
+        let leaf = X509::stack_from_pem(pem.as_bytes())?
+            .into_iter()
+            .next()
+            .ok_or_else(|| HandlerError::InvalidCertificate("no certificate".to_string()))?;
+        if !leaf.public_key()?.public_eq(&self.key) {
+            return Err(HandlerError::InvalidCertificate(
+                "issued for another key".to_string(),
+            ));
+        }
+        Ok(leaf)
+    }
+
+    /// the stored certificate; one left over from a previous key is ignored
+    pub fn certificate(&self) -> Option<(String, X509)> {
+        let pem = query_data(CERTIFICATE_KEY).ok().flatten()?;
+        match self.parse_certificate(&pem) {
+            Ok(leaf) => Some((pem, leaf)),
+            Err(e) => {
+                warn!("ignoring the stored device certificate: {}", e);
+                None
+            }
+        }
+    }
+
+    /// keeps a certificate the control plane issued and presents it from now on
+    pub fn store_certificate(&self, pem: &str) -> Result<(), HandlerError> {
+        self.parse_certificate(pem)?;
+        write_single(&pem.to_string(), CERTIFICATE_KEY)?;
+        self.present(pem)
+    }
+
+    /// presents the stored certificate, if there is one; false if there is none
+    pub fn install(&self) -> Result<bool, HandlerError> {
+        match self.certificate() {
+            Some((pem, _)) => self.present(&pem).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    fn present(&self, pem: &str) -> Result<(), HandlerError> {
+        client::set_identity(ClientIdentity {
+            certificate_pem: pem.to_string(),
+            key_pkcs8_pem: String::from_utf8(self.key.private_key_to_pem_pkcs8()?)?,
+        })
+    }
+}
+
+/// whether `certificate` is missing or expires within `margin`
+pub fn needs_renewal(certificate: Option<&X509>, margin: Duration) -> Result<bool, HandlerError> {
+    let Some(certificate) = certificate else {
+        return Ok(true);
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let deadline = Asn1Time::from_unix((now + margin).as_secs() as i64)?;
+    Ok(certificate.not_after() <= deadline)
+}
+
+/**
+ * renews the device certificate `renew_before` its expiry, and requests one if the device
+ * has none yet (registered before client certificates were enabled, or its key was
+ * replaced). a failed renewal is retried with backoff; the old certificate stays in use
+ * until then.
+ */
+pub async fn keep_renewed(
+    identity: Arc<DeviceIdentity>,
+    control_plane: Arc<dyn ControlPlane>,
+    device_id: Id,
+    renew_before: Duration,
+    retry: RetryPolicy,
+    shutdown: Shutdown,
+) {
+    let mut backoff = Backoff::new(retry);
+    loop {
+        let current = identity.certificate().map(|(_, leaf)| leaf);
+        let delay = match needs_renewal(current.as_ref(), renew_before) {
+            Ok(false) => CHECK_INTERVAL,
+            Ok(true) => match renew(&identity, control_plane.as_ref(), &device_id).await {
+                Ok(()) => {
+                    backoff.reset();
+                    CHECK_INTERVAL
+                }
+                Err(e) => {
+                    error!("cannot renew the device certificate: {}", e);
+                    backoff.next_delay(&e)
+                }
+            },
+            Err(e) => {
+                error!("cannot read the device certificate expiry: {}", e);
+                CHECK_INTERVAL
+            }
+        };
+        if !shutdown.sleep(delay).await {
+            return;
+        }
+    }
+}
+
+async fn renew(
+    identity: &DeviceIdentity,
+    control_plane: &dyn ControlPlane,
+    device_id: &Id,
+) -> Result<(), HandlerError> {
+    info!("requesting a new device certificate");
+    let csr = identity.csr(device_id)?;
+    let certificate = control_plane.renew_certificate(device_id, csr).await?;
+    identity.store_certificate(&certificate)?;
+    info!("device certificate renewed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt as _;
+    use std::time::Duration;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::{PKey, Private};
+    use openssl::x509::{X509Builder, X509Req, X509};
+    use tempdir::TempDir;
+
+    use crate::models::HandlerError;
+
+    use super::{needs_renewal, DeviceIdentity};
+
+    /// what the control plane would issue for `key`, valid for `days`
+    fn issue(key: &PKey<Private>, days: u32) -> X509 {
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(days).unwrap())
+            .unwrap();
+        builder.sign(key, MessageDigest::sha256()).unwrap();
+        builder.build()
+    }
+
+    #[test]
+    fn test_key_is_created_once_and_private() {
+        let dir = TempDir::new("test-identity").unwrap();
+        let path = dir.path().join("device_key.pem");
+
+        let identity = DeviceIdentity::load_or_create(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let reloaded = DeviceIdentity::load_or_create(&path).unwrap();
+        assert!(identity.key.public_eq(&reloaded.key));
+    }
+
+    #[test]
+    fn test_csr_is_signed_by_device_key() {
+        let dir = TempDir::new("test-identity").unwrap();
+        let identity = DeviceIdentity::load_or_create(&dir.path().join("key.pem")).unwrap();
+
+        let csr = X509Req::from_pem(identity.csr("device-1").unwrap().as_bytes()).unwrap();
+
+        assert!(csr.verify(&identity.key).unwrap());
+        let subject = csr.subject_name().entries().next().unwrap();
+        assert_eq!(subject.data().as_utf8().unwrap().to_string(), "device-1");
+    }
+
+    #[test]
+    fn test_certificate_for_another_key_is_rejected() {
+        let dir = TempDir::new("test-identity").unwrap();
+        let identity = DeviceIdentity::load_or_create(&dir.path().join("a.pem")).unwrap();
+        let other = DeviceIdentity::load_or_create(&dir.path().join("b.pem")).unwrap();
+
+        let own = issue(&identity.key, 30).to_pem().unwrap();
+        let foreign = issue(&other.key, 30).to_pem().unwrap();
+
+        assert!(identity
+            .parse_certificate(&String::from_utf8(own).unwrap())
+            .is_ok());
+        assert!(matches!(
+            identity.parse_certificate(&String::from_utf8(foreign).unwrap()),
+            Err(HandlerError::InvalidCertificate(_))
+        ));
+        assert!(identity.parse_certificate("").is_err());
+    }
+
+    #[test]
+    fn test_renewal_is_due_before_expiry() {
+        let dir = TempDir::new("test-identity").unwrap();
+        let identity = DeviceIdentity::load_or_create(&dir.path().join("key.pem")).unwrap();
+        let fortnight = Duration::from_secs(14 * 24 * 3600);
+
+        assert!(needs_renewal(None, fortnight).unwrap());
+        assert!(needs_renewal(Some(&issue(&identity.key, 7)), fortnight).unwrap());
+        assert!(!needs_renewal(Some(&issue(&identity.key, 90)), fortnight).unwrap());
+    }
+}
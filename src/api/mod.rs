@@ -1,2 +1,12 @@
+pub mod auth;
+pub mod client;
+pub mod control_plane;
+pub mod identity;
 pub mod models;
 pub mod requests;
+pub mod retry;
+pub mod signing;
+pub mod websocket;
+
+/// version of the device api the daemon speaks, advertised with its capabilities
+pub const PROTOCOL_VERSION: u32 = 1;
@@ -18,6 +18,92 @@ pub mod update_command_status {
     }
 }
 
+pub mod upload_command_result {
+    use crate::models::db::{commands::CommandStatus, common::Id, results::ExecutionResult};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct UploadCommandResultRequest {
+        pub command_id: Id,
+        pub status: CommandStatus,
+        pub result: ExecutionResult,
+        /// original size of stdout when it was cut down to the upload limit
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub stdout_bytes: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub stderr_bytes: Option<usize>,
+    }
+
+    impl UploadCommandResultRequest {
+        /// keeps at most `max_output_bytes` of stdout and of stderr, marking where they were cut;
+        /// bytes the executor already dropped while capturing count towards the original size
+        pub fn new(command_id: Id, mut result: ExecutionResult, max_output_bytes: usize) -> Self {
+            let stdout_bytes = truncate(
+                &mut result.stdout,
+                result.stdout_dropped_bytes.unwrap_or(0),
+                max_output_bytes,
+            );
+            let stderr_bytes = truncate(
+                &mut result.stderr,
+                result.stderr_dropped_bytes.unwrap_or(0),
+                max_output_bytes,
+            );
+            Self {
+                command_id,
+                status: result.status(),
+                result,
+                stdout_bytes,
+                stderr_bytes,
+            }
+        }
+
+        pub fn is_truncated(&self) -> bool {
+            self.stdout_bytes.is_some() || self.stderr_bytes.is_some()
+        }
+    }
+
+    /// returns the original length when `output` had to be cut or `dropped` bytes of it were
+    /// never captured
+    fn truncate(output: &mut Option<String>, dropped: usize, max_bytes: usize) -> Option<usize> {
+        let output = output
+            .as_mut()
+            .filter(|output| dropped > 0 || output.len() > max_bytes)?;
+        let original = output.len() + dropped;
+        let mut end = max_bytes.min(output.len());
+        while !output.is_char_boundary(end) {
+            end -= 1;
+        }
+        output.truncate(end);
+        output.push_str(&format!(
+            "\n[truncated {} of {} bytes]",
+            original - end,
+            original
+        ));
+        Some(original)
+    }
+}
+
+pub mod upload_command_output {
+    use crate::models::db::{common::Id, output::OutputChunk};
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct UploadCommandOutputRequest {
+        pub command_id: Id,
+        pub chunks: Vec<OutputChunk>,
+    }
+}
+
+pub mod fetch_cancellations {
+    use crate::models::db::common::Id;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, Default)]
+    pub struct FetchCancellationsResponse {
+        pub command_ids: Vec<Id>,
+    }
+}
+
 pub mod fetch_commands {
     use crate::models::db::commands::Command;
     use serde::{Deserialize, Serialize};
@@ -34,7 +120,115 @@ pub mod fetch_commands {
     }
 }
 
+pub mod auth {
+    use serde::{Deserialize, Serialize};
+
+    /// bearer token issued to the device at registration
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct DeviceCredential {
+        pub token: String,
+        /// unix seconds; `None` never expires
+        #[serde(default)]
+        pub expires_at: Option<u64>,
+    }
+
+    impl DeviceCredential {
+        pub fn expires_within(&self, now_secs: u64, margin_secs: u64) -> bool {
+            self.expires_at
+                .is_some_and(|expires_at| now_secs + margin_secs >= expires_at)
+        }
+    }
+}
+
+pub mod capabilities {
+    use crate::handlers::HandlerInfo;
+    use crate::models::db::common::Id;
+    use serde::{Deserialize, Serialize};
+
+    /// what the daemon can do, so the server only sends commands it can run
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct DeviceCapabilities {
+        pub version: String,
+        /// `api::PROTOCOL_VERSION`
+        pub protocol_version: u32,
+        /// the command names the daemon runs, with their `args` schema
+        pub commands: Vec<HandlerInfo>,
+        /// optional behaviour that is switched on, e.g. `sandbox` or `output_streaming`
+        pub features: Vec<String>,
+        pub platform: PlatformInfo,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct PlatformInfo {
+        /// `linux`, `macos`, ...
+        pub os: String,
+        /// `x86_64`, `aarch64`, ...
+        pub arch: String,
+        /// kernel release
+        #[serde(default)]
+        pub os_release: Option<String>,
+        /// distribution name and version, from `/etc/os-release`
+        #[serde(default)]
+        pub distribution: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct AdvertiseCapabilitiesRequest {
+        pub device_id: Id,
+        pub capabilities: DeviceCapabilities,
+    }
+}
+
+pub mod heartbeat {
+    use crate::models::db::common::Id;
+    use serde::{Deserialize, Serialize};
+
+    /// the device's health, posted every `heartbeat.interval_secs`
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct HeartbeatRequest {
+        pub device_id: Id,
+        pub version: String,
+        pub sent_at_ms: u64,
+        /// since the daemon started
+        pub uptime_secs: u64,
+        /// since the machine booted
+        #[serde(default)]
+        pub system_uptime_secs: Option<u64>,
+        #[serde(default)]
+        pub load_average: Option<LoadAverage>,
+        #[serde(default)]
+        pub memory: Option<MemoryUsage>,
+        /// all mounted disks together
+        #[serde(default)]
+        pub disk: Option<DiskUsage>,
+        /// when the daemon last started running a command, if it has since it started
+        #[serde(default)]
+        pub last_command_at_ms: Option<u64>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct LoadAverage {
+        pub one: f64,
+        pub five: f64,
+        pub fifteen: f64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct MemoryUsage {
+        pub total_kb: u64,
+        pub available_kb: u64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct DiskUsage {
+        pub total_kb: u64,
+        pub free_kb: u64,
+    }
+}
+
 pub mod register_device {
+    use super::auth::DeviceCredential;
+    use super::capabilities::DeviceCapabilities;
     use crate::models::db::common::Id;
     use serde::{Deserialize, Serialize};
 
@@ -44,6 +238,11 @@ pub mod register_device {
         pub user_secret: String,
         pub issuer_id: Id,
         pub user_id: Id,
+        /// pem certificate signing request for the device's client certificate
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub csr: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub capabilities: Option<DeviceCapabilities>,
     }
 
     impl Default for RegisterDeviceRequest {
@@ -53,6 +252,8 @@ pub mod register_device {
                 issuer_id: "testissuerid".to_string(),
                 user_secret: "testusersecret".to_string(),
                 user_id: "testuserid".to_string(),
+                csr: None,
+                capabilities: None,
             }
         }
     }
@@ -60,11 +261,132 @@ pub mod register_device {
     #[derive(Serialize, Deserialize, Debug)]
     pub struct RegisterDeviceResponse {
         pub device_id: Id,
+        #[serde(default)]
+        pub credential: Option<DeviceCredential>,
+        /// pem client certificate (chain) issued for the request's `csr`
+        #[serde(default)]
+        pub certificate: Option<String>,
+        /// key material for `api::signing`, only ever sent in this response
+        #[serde(default)]
+        pub signing_secret: Option<String>,
     }
 
     impl RegisterDeviceResponse {
         pub fn new(device_id: Id) -> Self {
-            RegisterDeviceResponse { device_id }
+            RegisterDeviceResponse {
+                device_id,
+                credential: None,
+                certificate: None,
+                signing_secret: None,
+            }
+        }
+    }
+}
+
+pub mod reenroll_device {
+    use crate::models::db::common::Id;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct ReenrollDeviceRequest {
+        pub device_id: Id,
+        pub device_name: String,
+        pub user_secret: String,
+        pub user_id: Id,
+    }
+}
+
+pub mod refresh_device_token {
+    use crate::models::db::common::Id;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct RefreshDeviceTokenRequest {
+        pub device_id: Id,
+    }
+}
+
+pub mod renew_device_certificate {
+    use crate::models::db::common::Id;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct RenewDeviceCertificateRequest {
+        pub device_id: Id,
+        pub csr: String,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct RenewDeviceCertificateResponse {
+        /// pem client certificate, optionally followed by its intermediates
+        pub certificate: String,
+    }
+}
+
+pub mod websocket {
+    use super::{
+        update_command_status::UpdateCommandStatusRequest,
+        upload_command_output::UploadCommandOutputRequest,
+        upload_command_result::UploadCommandResultRequest,
+    };
+    use crate::models::db::common::Id;
+    use serde::{Deserialize, Serialize};
+
+    /// pushed by the server over the command socket, each signed as described in
+    /// `api::signing` with its `type` as the kind
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum ServerMessage {
+        /// `payload` is the command's json
+        Command {
+            payload: String,
+            timestamp: u64,
+            signature: String,
+        },
+        /// `payload` is the id of the command to cancel
+        Cancel {
+            payload: Id,
+            timestamp: u64,
+            signature: String,
+        },
+        /// `payload` is a `DeliveryAck`'s json; sent once a `DeviceFrame` is stored
+        Ack {
+            payload: String,
+            timestamp: u64,
+            signature: String,
+        },
+    }
+
+    /// the `DeviceFrame` the server took over, and the command it was about
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct DeliveryAck {
+        pub id: u64,
+        pub command_id: Id,
+    }
+
+    /// sent by the daemon over the command socket; `id` is echoed in the server's ack
+    #[derive(Serialize, Deserialize, Debug)]
+    pub struct DeviceFrame {
+        pub id: u64,
+        #[serde(flatten)]
+        pub message: DeviceMessage,
+    }
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    pub enum DeviceMessage {
+        UpdateStatus(UpdateCommandStatusRequest),
+        UploadOutput(UploadCommandOutputRequest),
+        UploadResult(Box<UploadCommandResultRequest>),
+    }
+
+    impl DeviceMessage {
+        pub fn command_id(&self) -> &Id {
+            match self {
+                DeviceMessage::UpdateStatus(request) => &request.command_id,
+                DeviceMessage::UploadOutput(request) => &request.command_id,
+                DeviceMessage::UploadResult(request) => &request.command_id,
+            }
         }
     }
 }
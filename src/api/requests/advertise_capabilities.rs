@@ -0,0 +1,122 @@
+use futures::future::BoxFuture;
+use reqwest::Method;
+
+use crate::api::models::capabilities::{AdvertiseCapabilitiesRequest, DeviceCapabilities};
+use crate::api::requests::{api_request, handle_response, send, ApiResult};
+use crate::models::db::common::Id;
+
+use super::ApiConfig;
+
+/// replaces what the server knows of the device's version and capabilities
+pub async fn advertise_capabilities(
+    device_id: &Id,
+    capabilities: &DeviceCapabilities,
+    config: &ApiConfig,
+) -> ApiResult<()> {
+    let request_body = AdvertiseCapabilitiesRequest {
+        device_id: device_id.clone(),
+        capabilities: capabilities.clone(),
+    };
+
+    let url = config.with_path("/devices/capabilities");
+
+    let builder = api_request(config, Method::PUT, url).json(&request_body);
+    let response = send(config, builder).await?;
+
+    let bind = |_: reqwest::Response| -> BoxFuture<'static, ApiResult<()>> {
+        Box::pin(async move { Ok(()) })
+    };
+    handle_response(response, bind).await
+}
+
+#[cfg(test)]
+mod test {
+    use mockito::Matcher;
+
+    use crate::{
+        api::models::capabilities::DeviceCapabilities,
+        capabilities,
+        config::DaemonConfig,
+        handlers::HandlerRegistry,
+        models::HandlerError,
+        test_commons::{before_each, get_404_json_string, get_500_json_string, setup_server},
+    };
+
+    fn get_capabilities() -> DeviceCapabilities {
+        capabilities::collect(&DaemonConfig::default(), &HandlerRegistry::builtin())
+    }
+
+    #[tokio::test]
+    async fn test_advertise_capabilities() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("PUT", "/devices/capabilities")
+            .match_body(Matcher::PartialJsonString(format!(
+                r#"{{"device_id": "testdeviceid", "capabilities": {{"version": "{}", "protocol_version": 1}}}}"#,
+                crate::updater::VERSION
+            )))
+            .with_status(200)
+            .create();
+
+        let result = super::advertise_capabilities(
+            &"testdeviceid".to_string(),
+            &get_capabilities(),
+            &config,
+        )
+        .await;
+
+        assert!(result.is_ok());
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_advertise_capabilities_404_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("PUT", "/devices/capabilities")
+            .with_status(404)
+            .with_body(get_404_json_string())
+            .create();
+
+        let result = super::advertise_capabilities(
+            &"testdeviceid".to_string(),
+            &get_capabilities(),
+            &config,
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::NotFound));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_advertise_capabilities_500_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("PUT", "/devices/capabilities")
+            .with_status(500)
+            .with_body(get_500_json_string())
+            .create();
+
+        let result = super::advertise_capabilities(
+            &"testdeviceid".to_string(),
+            &get_capabilities(),
+            &config,
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::ServerError));
+        mock.assert();
+    }
+}
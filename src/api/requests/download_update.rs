@@ -0,0 +1,84 @@
+use futures::future::BoxFuture;
+use reqwest::Method;
+
+use crate::api::requests::{api_request, handle_response, send, ApiResult};
+
+use super::ApiConfig;
+
+/// the daemon binary of release `version`; the caller checks it against the update manifest
+pub async fn download_update(version: &str, config: &ApiConfig) -> ApiResult<Vec<u8>> {
+    let url = config.with_path(&format!("/updates/{}", version));
+
+    let builder = api_request(config, Method::GET, url);
+    let response = send(config, builder).await?;
+
+    let bind = |response: reqwest::Response| -> BoxFuture<'static, ApiResult<Vec<u8>>> {
+        Box::pin(async move { Ok(response.bytes().await?.to_vec()) })
+    };
+    handle_response(response, bind).await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        models::HandlerError,
+        test_commons::{before_each, get_404_json_string, get_500_json_string, setup_server},
+    };
+
+    #[tokio::test]
+    async fn test_download_update() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("GET", "/updates/1.2.0")
+            .with_status(200)
+            .with_header("content-type", "application/octet-stream")
+            .with_body([0x7f, b'E', b'L', b'F'])
+            .create();
+
+        let result = super::download_update("1.2.0", &config).await;
+
+        assert_eq!(result.unwrap(), vec![0x7f, b'E', b'L', b'F']);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_download_update_404_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("GET", "/updates/1.2.0")
+            .with_status(404)
+            .with_body(get_404_json_string())
+            .create();
+
+        let result = super::download_update("1.2.0", &config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::NotFound));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_download_update_500_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("GET", "/updates/1.2.0")
+            .with_status(500)
+            .with_body(get_500_json_string())
+            .create();
+
+        let result = super::download_update("1.2.0", &config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::ServerError));
+        mock.assert();
+    }
+}
@@ -0,0 +1,107 @@
+use futures::future::BoxFuture;
+use reqwest::Method;
+
+use crate::api::models::fetch_cancellations::FetchCancellationsResponse;
+use crate::api::requests::{api_request, handle_response, send_verified, ApiResult};
+use crate::models::db::common::Id;
+
+use super::ApiConfig;
+
+/// ids of the device's commands the server wants stopped; the response must be signed, so a
+/// forged one cannot stop commands
+pub async fn fetch_cancellations(
+    device_id: Id,
+    config: &ApiConfig,
+) -> ApiResult<FetchCancellationsResponse> {
+    let url = config.with_path("/commands/cancellations");
+
+    let builder = api_request(config, Method::GET, url).query(&[("device_id", device_id)]);
+    let response = send_verified(config, builder).await?;
+
+    let bind =
+        |response: reqwest::Response| -> BoxFuture<'static, ApiResult<FetchCancellationsResponse>> {
+            Box::pin(async move { Ok(response.json().await?) })
+        };
+    handle_response(response, bind).await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        models::HandlerError,
+        test_commons::{
+            before_each, get_404_json_string, get_500_json_string, setup_server,
+            setup_signed_server,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_fetch_cancellations() {
+        before_each();
+
+        let body = r#"{"command_ids": ["one", "two"]}"#.to_string();
+        let config = setup_signed_server("testsecret", body);
+
+        let result = super::fetch_cancellations("testdeviceid".to_string(), &config).await;
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().command_ids, vec!["one", "two"]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_cancellations_rejects_unsigned_response() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("GET", "/commands/cancellations?device_id=testdeviceid")
+            .with_status(200)
+            .with_body(r#"{"command_ids": ["one", "two"]}"#)
+            .create();
+
+        let config = config.with_signing_secret("testsecret".to_string());
+        let result = super::fetch_cancellations("testdeviceid".to_string(), &config).await;
+
+        assert!(matches!(result, Err(HandlerError::InvalidSignature(_))));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_cancellations_404_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("GET", "/commands/cancellations?device_id=testdeviceid")
+            .with_status(404)
+            .with_body(get_404_json_string())
+            .create();
+
+        let result = super::fetch_cancellations("testdeviceid".to_string(), &config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::NotFound));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_cancellations_500_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("GET", "/commands/cancellations?device_id=testdeviceid")
+            .with_status(500)
+            .with_body(get_500_json_string())
+            .create();
+
+        let result = super::fetch_cancellations("testdeviceid".to_string(), &config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::ServerError));
+        mock.assert();
+    }
+}
@@ -1,8 +1,9 @@
 use futures::future::BoxFuture;
 use log::info;
+use reqwest::Method;
 
 use crate::api::models::fetch_commands::FetchRecentCommandResponse;
-use crate::api::requests::{get_client, handle_response, ApiResult};
+use crate::api::requests::{api_request, handle_response, send_verified, ApiResult};
 use crate::models::db::common::Id;
 
 use super::ApiConfig;
@@ -13,11 +14,8 @@ pub async fn fetch_commands(
 ) -> ApiResult<Option<FetchRecentCommandResponse>> {
     let url = config.with_path("/commands/recent");
 
-    let response = get_client()
-        .get(url)
-        .query(&[("device_id", device_id)])
-        .send()
-        .await?;
+    let builder = api_request(config, Method::GET, url).query(&[("device_id", device_id)]);
+    let response = send_verified(config, builder).await?;
 
     let status = response.status();
     info!("Response status for fetch commands: {}", status);
@@ -30,10 +28,22 @@ pub async fn fetch_commands(
 
 #[cfg(test)]
 mod test {
+    use mockito::Matcher;
+
     use crate::{
         api::models::fetch_commands::FetchRecentCommandResponse,
-        models::{db::commands::Command, HandlerError},
-        test_commons::{before_each, get_404_json_string, get_500_json_string, setup_server},
+        api::signing::{NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+        models::{
+            db::{
+                commands::{Command, CommandNames, CommandStatus},
+                common::HasId,
+            },
+            HandlerError,
+        },
+        test_commons::{
+            before_each, get_404_json_string, get_500_json_string, setup_server,
+            setup_signed_server,
+        },
     };
 
     fn get_json_payload() -> (FetchRecentCommandResponse, String) {
@@ -47,6 +57,22 @@ mod test {
     async fn test_fetch_commands() {
         before_each();
 
+        let (data, json) = get_json_payload();
+        let device_id = data.command.device_id;
+        let config = setup_signed_server("testsecret", json);
+
+        let result = super::fetch_commands(device_id.to_string(), &config).await;
+
+        assert!(result.is_ok());
+        let response = result.unwrap();
+        assert!(response.is_some());
+        assert_eq!(response.unwrap().command.device_id, device_id);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_commands_without_token_is_rejected() {
+        before_each();
+
         let (data, json) = get_json_payload();
         let device_id = data.command.device_id;
         let (mut server, config) = setup_server();
@@ -54,7 +80,7 @@ mod test {
         let mock = server
             .mock(
                 "GET",
-                format!("/commands/recent?device_id={}", &device_id).as_str(),
+                format!("/commands/recent?device_id={}", device_id).as_str(),
             )
             .with_status(200)
             .with_body(json)
@@ -62,13 +88,38 @@ mod test {
 
         let result = super::fetch_commands(device_id.to_string(), &config).await;
 
-        assert!(result.is_ok());
-        let response = result.unwrap();
-        assert!(response.is_some());
-        assert_eq!(response.unwrap().command.device_id, device_id);
+        assert!(matches!(result, Err(HandlerError::InvalidSignature(_))));
         mock.assert();
     }
 
+    #[tokio::test]
+    async fn test_fetch_commands_tolerates_unknown_names_and_fields() {
+        before_each();
+
+        let json = r#"{"command": {
+            "_id": "cmd1",
+            "name": "Reboot",
+            "status": "Scheduled",
+            "priority": 3
+        }}"#;
+        let config = setup_signed_server("testsecret", json.to_string());
+
+        let result = super::fetch_commands("dev1".to_string(), &config).await;
+
+        let command = result.unwrap().unwrap().command;
+        assert_eq!(command.get_id(), "cmd1");
+        assert_eq!(command.name, CommandNames::Unknown("Reboot".to_string()));
+        assert_eq!(
+            command.status,
+            CommandStatus::Unknown("Scheduled".to_string())
+        );
+        assert_eq!(command.args, None);
+        assert_eq!(
+            serde_json::to_value(&command).unwrap()["name"],
+            serde_json::json!("Reboot")
+        );
+    }
+
     #[tokio::test]
     async fn test_fetch_commands_404_fail() {
         before_each();
@@ -116,4 +167,59 @@ mod test {
         assert!(matches!(result.err().unwrap(), HandlerError::ServerError));
         mock.assert();
     }
+
+    #[tokio::test]
+    async fn test_fetch_commands_429_retry_after() {
+        before_each();
+
+        let (data, _) = get_json_payload();
+        let device_id = data.command.device_id;
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock(
+                "GET",
+                format!("/commands/recent?device_id={}", device_id).as_str(),
+            )
+            .with_status(429)
+            .with_header("retry-after", "7")
+            .create();
+
+        let result = super::fetch_commands(device_id.to_string(), &config).await;
+
+        assert!(matches!(
+            result.err().unwrap(),
+            HandlerError::Throttled(Some(after)) if after == std::time::Duration::from_secs(7)
+        ));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_commands_rejects_unsigned_response() {
+        before_each();
+
+        let (data, json) = get_json_payload();
+        let device_id = data.command.device_id;
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock(
+                "GET",
+                format!("/commands/recent?device_id={}", device_id).as_str(),
+            )
+            .match_header(TIMESTAMP_HEADER, Matcher::Any)
+            .match_header(NONCE_HEADER, Matcher::Any)
+            .match_header(SIGNATURE_HEADER, Matcher::Any)
+            .with_status(200)
+            .with_body(json)
+            .create();
+
+        let config = config
+            .with_token("testtoken".to_string())
+            .with_signing_secret("testsecret".to_string());
+        let result = super::fetch_commands(device_id.to_string(), &config).await;
+
+        assert!(matches!(result, Err(HandlerError::InvalidSignature(_))));
+        mock.assert();
+    }
 }
@@ -1,25 +1,161 @@
+pub mod advertise_capabilities;
+pub mod download_update;
+pub mod fetch_cancellations;
 pub mod fetch_commands;
+pub mod reenroll_device;
+pub mod refresh_device_token;
 pub mod register_device;
+pub mod renew_device_certificate;
+pub mod send_heartbeat;
 pub mod update_command_status;
+pub mod upload_command_output;
+pub mod upload_command_result;
 
+use crate::api::client;
+use crate::api::signing::{
+    new_nonce, RequestSigner, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER,
+};
+use crate::config::{get_config, ApiSettings};
+use crate::executor::now_ms;
 use crate::models::HandlerError;
 use futures::future::BoxFuture;
 use log::{error, warn};
-use reqwest::StatusCode;
+use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use std::time::{Duration, SystemTime};
 pub type ApiResult<T> = Result<T, HandlerError>;
 
 fn get_client() -> reqwest::Client {
-    reqwest::Client::new()
+    client::shared()
 }
 
+/// request builder carrying the device bearer token, if the config has one
+fn api_request(config: &ApiConfig, method: Method, url: String) -> RequestBuilder {
+    let builder = get_client().request(method, url);
+    match &config.token {
+        Some(token) => builder.bearer_auth(token),
+        None => builder,
+    }
+}
+
+/// signs the request when the config carries a signing secret, then sends it
+async fn send(config: &ApiConfig, builder: RequestBuilder) -> ApiResult<reqwest::Response> {
+    Ok(send_signed(config, builder).await?.0)
+}
+
+/**
+ * like `send`, but a successful response must carry a valid server signature bound to the
+ * request nonce, otherwise it is rejected before its body is read by the caller. without a
+ * signing secret nothing can be verified, so a successful response is rejected as well.
+ */
+async fn send_verified(
+    config: &ApiConfig,
+    builder: RequestBuilder,
+) -> ApiResult<reqwest::Response> {
+    let (response, signed) = send_signed(config, builder).await?;
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+    let Some((signer, nonce)) = signed else {
+        error!("rejecting server response: no signing secret to verify it with");
+        return Err(HandlerError::InvalidSignature(
+            "no signing secret to verify the response".to_string(),
+        ));
+    };
+
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = response.bytes().await?;
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Err(e) = signer.verify_response(
+        status.as_u16(),
+        &body,
+        header(TIMESTAMP_HEADER),
+        header(SIGNATURE_HEADER),
+        &nonce,
+        now_ms() / 1000,
+    ) {
+        error!("rejecting unverified server response: {}", e);
+        return Err(e);
+    }
+
+    let mut verified = http::Response::new(body);
+    *verified.status_mut() = status;
+    *verified.headers_mut() = headers;
+    Ok(reqwest::Response::from(verified))
+}
+
+async fn send_signed(
+    config: &ApiConfig,
+    builder: RequestBuilder,
+) -> ApiResult<(reqwest::Response, Option<(RequestSigner, String)>)> {
+    let mut request = builder.build()?;
+    let signed = match &config.signing_secret {
+        Some(secret) => {
+            let signer = RequestSigner::from_secret(secret)?;
+            let nonce = new_nonce()?;
+            let timestamp = now_ms() / 1000;
+            let body = request
+                .body()
+                .and_then(|body| body.as_bytes())
+                .unwrap_or_default();
+            let signature = signer.sign_request(
+                request.method().as_str(),
+                request.url().path(),
+                request.url().query().unwrap_or_default(),
+                body,
+                timestamp,
+                &nonce,
+            )?;
+
+            let headers = request.headers_mut();
+            headers.insert(TIMESTAMP_HEADER, HeaderValue::from(timestamp));
+            headers.insert(NONCE_HEADER, header_value(&nonce)?);
+            headers.insert(SIGNATURE_HEADER, header_value(&signature)?);
+            Some((signer, nonce))
+        }
+        None => None,
+    };
+
+    let response = get_client().execute(request).await?;
+    Ok((response, signed))
+}
+
+fn header_value(value: &str) -> ApiResult<HeaderValue> {
+    HeaderValue::from_str(value).map_err(|_| HandlerError::InvalidSignature(value.to_string()))
+}
+
+#[derive(Clone)]
 pub struct ApiConfig {
     pub host: String,
     pub port: Option<u16>,
+    pub token: Option<String>,
+    /// signs requests and verifies responses, see `api::signing`; never sent itself
+    pub signing_secret: Option<String>,
 }
 
 impl ApiConfig {
     pub fn new(host: String, port: Option<u16>) -> Self {
-        ApiConfig { host, port }
+        ApiConfig {
+            host,
+            port,
+            token: None,
+            signing_secret: None,
+        }
+    }
+
+    pub fn with_token(&self, token: String) -> Self {
+        ApiConfig {
+            token: Some(token),
+            ..self.clone()
+        }
+    }
+
+    pub fn with_signing_secret(&self, secret: String) -> Self {
+        ApiConfig {
+            signing_secret: Some(secret),
+            ..self.clone()
+        }
     }
 
     fn get_port_string_if_any(&self) -> String {
@@ -33,22 +169,41 @@ impl ApiConfig {
         let port_string = self.get_port_string_if_any();
         format!("{}{}{}", self.host, port_string, path)
     }
+
+    /// same as `with_path` but with the http(s) scheme swapped for ws(s)
+    pub fn with_ws_path(&self, path: &str) -> String {
+        let url = self.with_path(path);
+        match url.split_once("://") {
+            Some(("https", rest)) => format!("wss://{}", rest),
+            Some(("http", rest)) => format!("ws://{}", rest),
+            _ => url,
+        }
+    }
+}
+
+impl From<&ApiSettings> for ApiConfig {
+    fn from(settings: &ApiSettings) -> Self {
+        ApiConfig::new(
+            format!("{}://{}", settings.scheme, settings.host),
+            settings.port,
+        )
+    }
 }
 
 impl Default for ApiConfig {
     fn default() -> Self {
-        ApiConfig {
-            host: "http://127.0.0.1".to_string(),
-            port: Some(5001),
-            // host: "https://api.itx-app.com".to_string(),
-            // port: None,
-        }
+        ApiConfig::from(&get_config().api)
     }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::api::requests::ApiConfig;
+    use std::time::{Duration, SystemTime};
+
+    use reqwest::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+
+    use super::parse_retry_after;
+    use crate::{api::requests::ApiConfig, config::ApiSettings};
 
     #[test]
     fn test_api_config_with_path() {
@@ -76,6 +231,54 @@ mod test {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_api_config_with_ws_path() {
+        let config = ApiConfig::new("https://testhost".to_string(), Some(5001));
+        assert_eq!(config.with_ws_path("/ws"), "wss://testhost:5001/ws");
+
+        let config = ApiConfig::new("http://testhost".to_string(), None);
+        assert_eq!(config.with_ws_path("/ws"), "ws://testhost/ws");
+    }
+
+    #[test]
+    fn test_api_config_from_settings() {
+        let settings = ApiSettings {
+            scheme: "https".to_string(),
+            host: "api.itx-app.com".to_string(),
+            port: None,
+        };
+        let config = ApiConfig::from(&settings);
+
+        let result = config.with_path("/testpath");
+
+        let expected = "https://api.itx-app.com/testpath";
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let header = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+
+        assert_eq!(
+            parse_retry_after(&header("120"), now),
+            Some(Duration::from_secs(120))
+        );
+        let date = httpdate::fmt_http_date(now + Duration::from_secs(30));
+        assert_eq!(
+            parse_retry_after(&header(&date), now),
+            Some(Duration::from_secs(30))
+        );
+        let past = httpdate::fmt_http_date(now - Duration::from_secs(30));
+        assert_eq!(parse_retry_after(&header(&past), now), Some(Duration::ZERO));
+        assert_eq!(parse_retry_after(&header("soon"), now), None);
+        assert_eq!(parse_retry_after(&HeaderMap::new(), now), None);
+    }
+
     #[test]
     fn test_default_api_config_with_path() {
         let config = ApiConfig::default();
@@ -93,8 +296,13 @@ async fn handle_response<T>(
     if let StatusCode::OK | StatusCode::CREATED | StatusCode::NO_CONTENT = status {
         Ok(on_ok(response).await?)
     } else {
+        let retry_after = parse_retry_after(response.headers(), SystemTime::now());
         let text = response.text().await?;
         match status {
+            StatusCode::UNAUTHORIZED => {
+                warn!("device credential rejected: {}", &text);
+                Err(HandlerError::Unauthorized)
+            }
             StatusCode::NOT_FOUND => {
                 warn!("No commands found: {}", &text);
                 Err(HandlerError::NotFound)
@@ -103,6 +311,13 @@ async fn handle_response<T>(
                 error!("server error on fetch: {}", &text);
                 Err(HandlerError::ServerError)
             }
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE => {
+                warn!(
+                    "server busy ({}), retry after {:?}: {}",
+                    status, retry_after, &text
+                );
+                Err(HandlerError::Throttled(retry_after))
+            }
             StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
                 warn!("error in data passed in: {}", &text);
                 Err(HandlerError::InputError)
@@ -114,3 +329,15 @@ async fn handle_response<T>(
         }
     }
 }
+
+/// `Retry-After` as either delay-seconds or an http date, relative to `now`
+fn parse_retry_after(headers: &HeaderMap, now: SystemTime) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            let at = httpdate::parse_http_date(value).ok()?;
+            Some(at.duration_since(now).unwrap_or_default())
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use futures::future::BoxFuture;
+use reqwest::Method;
+
+use crate::api::models::reenroll_device::ReenrollDeviceRequest;
+use crate::api::models::register_device::RegisterDeviceResponse;
+use crate::api::requests::{api_request, handle_response, send, ApiResult};
+use crate::models::db::common::Id;
+
+use super::ApiConfig;
+
+/// re-issues a credential for an already registered device, keeping its id
+pub async fn reenroll_device(
+    device_id: &Id,
+    user_id: &Id,
+    user_secret: &str,
+    device_name: String,
+    config: &ApiConfig,
+) -> ApiResult<RegisterDeviceResponse> {
+    let request_body = ReenrollDeviceRequest {
+        device_id: device_id.clone(),
+        user_id: user_id.clone(),
+        device_name,
+        user_secret: user_secret.to_string(),
+    };
+
+    let url = config.with_path("/devices/reenroll");
+
+    let builder = api_request(config, Method::POST, url).json(&request_body);
+    let response = send(config, builder).await?;
+
+    let bind =
+        |response: reqwest::Response| -> BoxFuture<'static, ApiResult<RegisterDeviceResponse>> {
+            Box::pin(async move { Ok(response.json().await?) })
+        };
+    handle_response(response, bind).await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        api::models::{auth::DeviceCredential, register_device::RegisterDeviceResponse},
+        models::HandlerError,
+        test_commons::{before_each, get_500_json_string, setup_server},
+    };
+
+    fn get_json_payload() -> (RegisterDeviceResponse, String) {
+        let mut data = RegisterDeviceResponse::new("testdeviceid".to_string());
+        data.credential = Some(DeviceCredential {
+            token: "newtoken".to_string(),
+            expires_at: Some(1_000),
+        });
+        let data_string = serde_json::to_string(&data).unwrap();
+
+        (data, data_string)
+    }
+
+    async fn call(
+        config: &crate::api::requests::ApiConfig,
+    ) -> Result<RegisterDeviceResponse, HandlerError> {
+        super::reenroll_device(
+            &"testdeviceid".to_string(),
+            &"testuserid".to_string(),
+            "testsecret",
+            "testdevicename".to_string(),
+            config,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_reenroll_device() {
+        before_each();
+
+        let (data, json) = get_json_payload();
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/devices/reenroll")
+            .with_status(200)
+            .with_body(json)
+            .create();
+
+        let result = call(&config).await;
+
+        assert!(result.is_ok());
+        let response = result.unwrap();
+        assert_eq!(response.device_id, data.device_id);
+        assert_eq!(response.credential, data.credential);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_reenroll_device_401_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/devices/reenroll")
+            .with_status(401)
+            .with_body("bad secret")
+            .create();
+
+        let result = call(&config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::Unauthorized));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_reenroll_device_500_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/devices/reenroll")
+            .with_status(500)
+            .with_body(get_500_json_string())
+            .create();
+
+        let result = call(&config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::ServerError));
+        mock.assert();
+    }
+}
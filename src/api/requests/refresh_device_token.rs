@@ -0,0 +1,114 @@
+use futures::future::BoxFuture;
+use reqwest::Method;
+
+use crate::api::models::auth::DeviceCredential;
+use crate::api::models::refresh_device_token::RefreshDeviceTokenRequest;
+use crate::api::requests::{api_request, handle_response, send, ApiResult};
+use crate::models::db::common::Id;
+
+use super::ApiConfig;
+
+/// exchanges the (still valid) bearer token in `config` for a fresh one
+pub async fn refresh_device_token(
+    device_id: &Id,
+    config: &ApiConfig,
+) -> ApiResult<DeviceCredential> {
+    let request_body = RefreshDeviceTokenRequest {
+        device_id: device_id.clone(),
+    };
+
+    let url = config.with_path("/devices/token/refresh");
+
+    let builder = api_request(config, Method::POST, url).json(&request_body);
+    let response = send(config, builder).await?;
+
+    let bind = |response: reqwest::Response| -> BoxFuture<'static, ApiResult<DeviceCredential>> {
+        Box::pin(async move { Ok(response.json().await?) })
+    };
+    handle_response(response, bind).await
+}
+
+#[cfg(test)]
+mod test {
+    use mockito::Matcher;
+
+    use crate::{
+        api::models::auth::DeviceCredential,
+        models::HandlerError,
+        test_commons::{before_each, get_500_json_string, setup_server},
+    };
+
+    fn get_json_payload() -> (DeviceCredential, String) {
+        let data = DeviceCredential {
+            token: "newtoken".to_string(),
+            expires_at: Some(1_000),
+        };
+        let data_string = serde_json::to_string(&data).unwrap();
+
+        (data, data_string)
+    }
+
+    #[tokio::test]
+    async fn test_refresh_device_token() {
+        before_each();
+
+        let (data, json) = get_json_payload();
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/devices/token/refresh")
+            .match_header("authorization", "Bearer oldtoken")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"device_id": "testdeviceid"}"#.to_string(),
+            ))
+            .with_status(200)
+            .with_body(json)
+            .create();
+
+        let config = config.with_token("oldtoken".to_string());
+        let result = super::refresh_device_token(&"testdeviceid".to_string(), &config).await;
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), data);
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_refresh_device_token_401_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/devices/token/refresh")
+            .with_status(401)
+            .with_body("expired")
+            .create();
+
+        let config = config.with_token("oldtoken".to_string());
+        let result = super::refresh_device_token(&"testdeviceid".to_string(), &config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::Unauthorized));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_refresh_device_token_500_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/devices/token/refresh")
+            .with_status(500)
+            .with_body(get_500_json_string())
+            .create();
+
+        let result = super::refresh_device_token(&"testdeviceid".to_string(), &config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::ServerError));
+        mock.assert();
+    }
+}
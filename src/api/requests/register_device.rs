@@ -1,7 +1,9 @@
 use futures::future::BoxFuture;
+use reqwest::Method;
 
+use crate::api::models::capabilities::DeviceCapabilities;
 use crate::api::models::register_device::{RegisterDeviceRequest, RegisterDeviceResponse};
-use crate::api::requests::{get_client, handle_response, ApiResult};
+use crate::api::requests::{api_request, handle_response, send, ApiResult};
 use crate::models::db::common::Id;
 
 use super::ApiConfig;
@@ -10,6 +12,8 @@ pub async fn register_device(
     user_id: &Id,
     user_secret: &str,
     device_name: String,
+    csr: Option<String>,
+    capabilities: Option<DeviceCapabilities>,
     config: &ApiConfig,
 ) -> ApiResult<RegisterDeviceResponse> {
     let request = RegisterDeviceRequest {
@@ -17,11 +21,14 @@ pub async fn register_device(
         issuer_id: user_id.clone(),
         device_name,
         user_secret: user_secret.to_string(),
+        csr,
+        capabilities,
     };
 
     let url = config.with_path("/devices/register");
 
-    let response = get_client().post(url).json(&request).send().await?;
+    let builder = api_request(config, Method::POST, url).json(&request);
+    let response = send(config, builder).await?;
 
     let bind =
         |response: reqwest::Response| -> BoxFuture<'static, ApiResult<RegisterDeviceResponse>> {
@@ -32,8 +39,13 @@ pub async fn register_device(
 
 #[cfg(test)]
 mod test {
+    use mockito::Matcher;
+
     use crate::{
         api::models::register_device::{RegisterDeviceRequest, RegisterDeviceResponse},
+        capabilities,
+        config::DaemonConfig,
+        handlers::HandlerRegistry,
         models::{db::common::Id, HandlerError},
         test_commons::{before_each, get_404_json_string, get_500_json_string, setup_server},
     };
@@ -55,15 +67,26 @@ mod test {
 
         let mock = server
             .mock("POST", "/devices/register")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"capabilities": {"protocol_version": 1}}"#.to_string(),
+            ))
             .with_status(200)
             .with_body(json)
             .create();
 
-        let input = RegisterDeviceRequest::default();
+        let input = RegisterDeviceRequest {
+            capabilities: Some(capabilities::collect(
+                &DaemonConfig::default(),
+                &HandlerRegistry::builtin(),
+            )),
+            ..Default::default()
+        };
         let result = super::register_device(
             &input.user_id,
             &input.user_secret,
             input.device_name,
+            input.csr,
+            input.capabilities,
             &config,
         )
         .await;
@@ -91,6 +114,8 @@ mod test {
             &input.user_id,
             &input.user_secret,
             input.device_name,
+            input.csr,
+            input.capabilities,
             &config,
         )
         .await;
@@ -117,6 +142,8 @@ mod test {
             &input.user_id,
             &input.user_secret,
             input.device_name,
+            input.csr,
+            input.capabilities,
             &config,
         )
         .await;
@@ -0,0 +1,150 @@
+use futures::future::BoxFuture;
+use reqwest::Method;
+
+use crate::api::models::renew_device_certificate::{
+    RenewDeviceCertificateRequest, RenewDeviceCertificateResponse,
+};
+use crate::api::requests::{api_request, handle_response, send_verified, ApiResult};
+use crate::models::db::common::Id;
+
+use super::ApiConfig;
+
+/// asks for a new client certificate for `csr`, before the current one expires
+pub async fn renew_device_certificate(
+    device_id: &Id,
+    csr: String,
+    config: &ApiConfig,
+) -> ApiResult<RenewDeviceCertificateResponse> {
+    let request_body = RenewDeviceCertificateRequest {
+        device_id: device_id.clone(),
+        csr,
+    };
+
+    let url = config.with_path("/devices/certificate");
+
+    let builder = api_request(config, Method::POST, url).json(&request_body);
+    let response = send_verified(config, builder).await?;
+
+    let bind = |response: reqwest::Response| -> BoxFuture<
+        'static,
+        ApiResult<RenewDeviceCertificateResponse>,
+    > { Box::pin(async move { Ok(response.json().await?) }) };
+    handle_response(response, bind).await
+}
+
+#[cfg(test)]
+mod test {
+    use mockito::Matcher;
+
+    use crate::{
+        api::models::renew_device_certificate::RenewDeviceCertificateResponse,
+        models::HandlerError,
+        test_commons::{
+            before_each, get_404_json_string, get_500_json_string, setup_server,
+            setup_signed_server,
+        },
+    };
+
+    fn get_json_payload() -> (RenewDeviceCertificateResponse, String) {
+        let data = RenewDeviceCertificateResponse {
+            certificate: "-----BEGIN CERTIFICATE-----".to_string(),
+        };
+        let data_string = serde_json::to_string(&data).unwrap();
+
+        (data, data_string)
+    }
+
+    #[tokio::test]
+    async fn test_renew_device_certificate() {
+        before_each();
+
+        let (data, json) = get_json_payload();
+        let config = setup_signed_server("testsecret", json);
+
+        let result = super::renew_device_certificate(
+            &"testdeviceid".to_string(),
+            "testcsr".to_string(),
+            &config,
+        )
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().certificate, data.certificate);
+    }
+
+    #[tokio::test]
+    async fn test_renew_device_certificate_sends_csr() {
+        before_each();
+
+        let (_, json) = get_json_payload();
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/devices/certificate")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"device_id": "testdeviceid", "csr": "testcsr"}"#.to_string(),
+            ))
+            .with_status(200)
+            .with_body(json)
+            .create();
+
+        let result = super::renew_device_certificate(
+            &"testdeviceid".to_string(),
+            "testcsr".to_string(),
+            &config,
+        )
+        .await;
+
+        // unsigned, since the config carries no signing secret
+        assert!(matches!(result, Err(HandlerError::InvalidSignature(_))));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_renew_device_certificate_404_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/devices/certificate")
+            .with_status(404)
+            .with_body(get_404_json_string())
+            .create();
+
+        let result = super::renew_device_certificate(
+            &"testdeviceid".to_string(),
+            "testcsr".to_string(),
+            &config,
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::NotFound));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_renew_device_certificate_500_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/devices/certificate")
+            .with_status(500)
+            .with_body(get_500_json_string())
+            .create();
+
+        let result = super::renew_device_certificate(
+            &"testdeviceid".to_string(),
+            "testcsr".to_string(),
+            &config,
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::ServerError));
+        mock.assert();
+    }
+}
@@ -0,0 +1,103 @@
+use futures::future::BoxFuture;
+use reqwest::Method;
+
+use crate::api::models::heartbeat::HeartbeatRequest;
+use crate::api::requests::{api_request, handle_response, send, ApiResult};
+
+use super::ApiConfig;
+
+pub async fn send_heartbeat(request: &HeartbeatRequest, config: &ApiConfig) -> ApiResult<()> {
+    let url = config.with_path("/devices/heartbeat");
+
+    let builder = api_request(config, Method::POST, url).json(request);
+    let response = send(config, builder).await?;
+
+    let bind = |_: reqwest::Response| -> BoxFuture<'static, ApiResult<()>> {
+        Box::pin(async move { Ok(()) })
+    };
+    handle_response(response, bind).await
+}
+
+#[cfg(test)]
+mod test {
+    use mockito::Matcher;
+
+    use crate::{
+        api::models::heartbeat::HeartbeatRequest,
+        models::HandlerError,
+        test_commons::{before_each, get_404_json_string, get_500_json_string, setup_server},
+    };
+
+    fn get_request() -> HeartbeatRequest {
+        HeartbeatRequest {
+            device_id: "testdeviceid".to_string(),
+            version: "1.2.3".to_string(),
+            sent_at_ms: 1000,
+            uptime_secs: 42,
+            system_uptime_secs: None,
+            load_average: None,
+            memory: None,
+            disk: None,
+            last_command_at_ms: Some(500),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_heartbeat() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/devices/heartbeat")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"device_id": "testdeviceid", "uptime_secs": 42, "last_command_at_ms": 500}"#
+                    .to_string(),
+            ))
+            .with_status(200)
+            .create();
+
+        let result = super::send_heartbeat(&get_request(), &config).await;
+
+        assert!(result.is_ok());
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_send_heartbeat_404_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/devices/heartbeat")
+            .with_status(404)
+            .with_body(get_404_json_string())
+            .create();
+
+        let result = super::send_heartbeat(&get_request(), &config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::NotFound));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_send_heartbeat_500_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/devices/heartbeat")
+            .with_status(500)
+            .with_body(get_500_json_string())
+            .create();
+
+        let result = super::send_heartbeat(&get_request(), &config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::ServerError));
+        mock.assert();
+    }
+}
@@ -1,11 +1,12 @@
 use futures::future::BoxFuture;
+use reqwest::Method;
 
 use crate::api::models::update_command_status::UpdateCommandStatusRequest;
-use crate::api::requests::{get_client, ApiResult};
+use crate::api::requests::ApiResult;
 use crate::models::db::commands::{Command, CommandStatus};
 use crate::models::db::common::HasId;
 
-use super::{handle_response, ApiConfig};
+use super::{api_request, handle_response, send, ApiConfig};
 
 pub async fn update_command_status(
     command: &Command,
@@ -19,7 +20,8 @@ pub async fn update_command_status(
 
     let url = config.with_path("/commands/update/status");
 
-    let response = get_client().patch(url).json(&request).send().await?;
+    let builder = api_request(config, Method::PATCH, url).json(&request);
+    let response = send(config, builder).await?;
 
     let bind = |_: reqwest::Response| -> BoxFuture<'static, ApiResult<()>> {
         Box::pin(async move { Ok(()) })
@@ -0,0 +1,127 @@
+use futures::future::BoxFuture;
+use reqwest::Method;
+
+use crate::api::models::upload_command_output::UploadCommandOutputRequest;
+use crate::api::requests::ApiResult;
+use crate::models::db::commands::Command;
+use crate::models::db::common::HasId;
+use crate::models::db::output::OutputChunk;
+
+use super::{api_request, handle_response, send, ApiConfig};
+
+pub async fn upload_command_output(
+    command: &Command,
+    chunks: &[OutputChunk],
+    config: &ApiConfig,
+) -> ApiResult<()> {
+    let request = UploadCommandOutputRequest {
+        command_id: command.get_id().clone(),
+        chunks: chunks.to_vec(),
+    };
+
+    let url = config.with_path("/commands/output");
+
+    let builder = api_request(config, Method::POST, url).json(&request);
+    let response = send(config, builder).await?;
+
+    let bind = |_: reqwest::Response| -> BoxFuture<'static, ApiResult<()>> {
+        Box::pin(async move { Ok(()) })
+    };
+
+    handle_response(response, bind).await
+}
+
+#[cfg(test)]
+mod test {
+    use mockito::Matcher;
+
+    use crate::{
+        models::{
+            db::{
+                commands::Command,
+                output::{OutputChunk, OutputStream},
+            },
+            HandlerError,
+        },
+        test_commons::{before_each, get_404_json_string, get_500_json_string, setup_server},
+    };
+
+    fn get_chunks() -> Vec<OutputChunk> {
+        vec![
+            OutputChunk {
+                stream: OutputStream::Stdout,
+                seq: 0,
+                data: "hello\n".to_string(),
+                at_ms: 1,
+            },
+            OutputChunk {
+                stream: OutputStream::Stderr,
+                seq: 1,
+                data: "oops\n".to_string(),
+                at_ms: 2,
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_upload_command_output() {
+        before_each();
+
+        let command = Command::default();
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/commands/output")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"command_id": "default", "chunks": [{"stream": "stdout", "seq": 0, "data": "hello\n"}, {"stream": "stderr", "seq": 1}]}"#
+                    .to_string(),
+            ))
+            .with_status(200)
+            .create();
+
+        let result = super::upload_command_output(&command, &get_chunks(), &config).await;
+
+        assert!(result.is_ok());
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_upload_command_output_404_fail() {
+        before_each();
+
+        let command = Command::default();
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/commands/output")
+            .with_status(404)
+            .with_body(get_404_json_string())
+            .create();
+
+        let result = super::upload_command_output(&command, &get_chunks(), &config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::NotFound));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_upload_command_output_500_fail() {
+        before_each();
+
+        let command = Command::default();
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/commands/output")
+            .with_status(500)
+            .with_body(get_500_json_string())
+            .create();
+
+        let result = super::upload_command_output(&command, &get_chunks(), &config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::ServerError));
+        mock.assert();
+    }
+}
@@ -0,0 +1,219 @@
+use std::io::Write as _;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future::BoxFuture;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::Method;
+
+use crate::api::models::upload_command_result::UploadCommandResultRequest;
+use crate::api::requests::ApiResult;
+
+use super::{api_request, handle_response, send, ApiConfig};
+
+/**
+ * uploads a command's execution result. output limits are applied when the request is built
+ * (see `UploadCommandResultRequest::new`); bodies of at least `gzip_min_bytes` are sent gzip
+ * encoded, and the request signature covers the encoded bytes.
+ */
+pub async fn upload_command_result(
+    request: &UploadCommandResultRequest,
+    gzip_min_bytes: Option<usize>,
+    config: &ApiConfig,
+) -> ApiResult<()> {
+    let url = config.with_path("/commands/result");
+
+    let (body, gzipped) = encode_body(request, gzip_min_bytes)?;
+    let mut builder =
+        api_request(config, Method::POST, url).header(CONTENT_TYPE, "application/json");
+    if gzipped {
+        builder = builder.header(CONTENT_ENCODING, "gzip");
+    }
+    let response = send(config, builder.body(body)).await?;
+
+    let bind = |_: reqwest::Response| -> BoxFuture<'static, ApiResult<()>> {
+        Box::pin(async move { Ok(()) })
+    };
+
+    handle_response(response, bind).await
+}
+
+/// json body, gzipped (and flagged so) once it reaches `gzip_min_bytes`
+fn encode_body(
+    request: &UploadCommandResultRequest,
+    gzip_min_bytes: Option<usize>,
+) -> ApiResult<(Vec<u8>, bool)> {
+    let json = serde_json::to_vec(request)?;
+    match gzip_min_bytes {
+        Some(min_bytes) if json.len() >= min_bytes => {
+            let mut encoder = GzEncoder::new(vec![], Compression::default());
+            encoder.write_all(&json)?;
+            Ok((encoder.finish()?, true))
+        }
+        _ => Ok((json, false)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read as _;
+
+    use flate2::read::GzDecoder;
+    use mockito::Matcher;
+
+    use crate::{
+        api::models::upload_command_result::UploadCommandResultRequest,
+        models::{db::results::ExecutionResult, HandlerError},
+        test_commons::{before_each, get_404_json_string, get_500_json_string, setup_server},
+    };
+
+    fn get_failed_result() -> ExecutionResult {
+        ExecutionResult {
+            stdout: Some("".to_string()),
+            stderr: Some("boom".to_string()),
+            exit_code: Some(1),
+            ..Default::default()
+        }
+    }
+
+    fn get_request(result: ExecutionResult) -> UploadCommandResultRequest {
+        UploadCommandResultRequest::new("default".to_string(), result, 1024)
+    }
+
+    #[tokio::test]
+    async fn test_upload_command_result() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/commands/result")
+            .match_body(Matcher::PartialJsonString(
+                r#"{"status": "Failed", "result": {"stderr": "boom", "exit_code": 1}}"#.to_string(),
+            ))
+            .with_status(200)
+            .create();
+
+        let request = get_request(get_failed_result());
+        let result = super::upload_command_result(&request, None, &config).await;
+
+        assert!(result.is_ok());
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_upload_command_result_404_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/commands/result")
+            .with_status(404)
+            .with_body(get_404_json_string())
+            .create();
+
+        let request = get_request(get_failed_result());
+        let result = super::upload_command_result(&request, None, &config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::NotFound));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_upload_command_result_500_fail() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/commands/result")
+            .with_status(500)
+            .with_body(get_500_json_string())
+            .create();
+
+        let request = get_request(get_failed_result());
+        let result = super::upload_command_result(&request, None, &config).await;
+
+        assert!(result.is_err());
+        assert!(matches!(result.err().unwrap(), HandlerError::ServerError));
+        mock.assert();
+    }
+
+    #[tokio::test]
+    async fn test_upload_command_result_gzips_large_bodies() {
+        before_each();
+
+        let (mut server, config) = setup_server();
+
+        let mock = server
+            .mock("POST", "/commands/result")
+            .match_header("content-encoding", "gzip")
+            .with_status(200)
+            .create();
+
+        let result = ExecutionResult {
+            stdout: Some("x".repeat(512)),
+            ..Default::default()
+        };
+        let request = get_request(result);
+        let result = super::upload_command_result(&request, Some(256), &config).await;
+
+        assert!(result.is_ok());
+        mock.assert();
+    }
+
+    #[test]
+    fn test_encode_body_round_trips_gzip() {
+        let request = get_request(get_failed_result());
+
+        let (body, gzipped) = super::encode_body(&request, Some(0)).unwrap();
+        assert!(gzipped);
+        let mut json = String::new();
+        GzDecoder::new(body.as_slice())
+            .read_to_string(&mut json)
+            .unwrap();
+        assert_eq!(json, serde_json::to_string(&request).unwrap());
+
+        let (body, gzipped) = super::encode_body(&request, Some(usize::MAX)).unwrap();
+        assert!(!gzipped);
+        assert_eq!(body, serde_json::to_vec(&request).unwrap());
+    }
+
+    #[test]
+    fn test_output_over_limit_is_truncated_with_marker() {
+        let result = ExecutionResult {
+            stdout: Some("é".repeat(600)),
+            stderr: Some("short".to_string()),
+            ..Default::default()
+        };
+
+        let request = get_request(result);
+
+        let stdout = request.result.stdout.as_deref().unwrap();
+        // 1024 bytes is exactly 512 two-byte characters
+        assert!(stdout.starts_with(&"é".repeat(512)));
+        assert!(stdout.ends_with("\n[truncated 176 of 1200 bytes]"));
+        assert_eq!(request.stdout_bytes, Some(1200));
+        assert_eq!(request.result.stderr.as_deref(), Some("short"));
+        assert_eq!(request.stderr_bytes, None);
+        assert!(request.is_truncated());
+    }
+
+    #[test]
+    fn test_output_dropped_while_capturing_counts_towards_marker() {
+        let result = ExecutionResult {
+            stdout: Some("x".repeat(1024)),
+            stdout_dropped_bytes: Some(976),
+            ..Default::default()
+        };
+
+        let request = get_request(result);
+
+        let stdout = request.result.stdout.as_deref().unwrap();
+        assert!(stdout.starts_with(&"x".repeat(1024)));
+        assert!(stdout.ends_with("\n[truncated 976 of 2000 bytes]"));
+        assert_eq!(request.stdout_bytes, Some(2000));
+    }
+}
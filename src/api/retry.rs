@@ -0,0 +1,327 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::api::requests::ApiResult;
+use crate::config::RetrySettings;
+use crate::models::HandlerError;
+
+/**
+ * exponential backoff with full jitter: the ceiling starts at `base` and doubles with every
+ * attempt up to `max`, and the delay is picked uniformly below it, so a fleet of devices that
+ * lost the server at the same moment does not come back in lockstep.
+ *
+ * a `Retry-After` from the server is a lower bound on the delay, capped at `max`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl RetryPolicy {
+    pub fn ceiling(&self, attempt: u32) -> Duration {
+        self.base
+            .checked_mul(2u32.saturating_pow(attempt))
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        let delay = random_below(self.ceiling(attempt));
+        match retry_after {
+            Some(after) => delay.max(after.min(self.max)),
+            None => delay,
+        }
+    }
+}
+
+impl From<&RetrySettings> for RetryPolicy {
+    fn from(settings: &RetrySettings) -> Self {
+        RetryPolicy {
+            base: Duration::from_millis(settings.base_millis),
+            max: Duration::from_secs(settings.max_secs),
+        }
+    }
+}
+
+/// uniform in `0..=ceiling`; the ceiling itself if the system has no randomness to give
+fn random_below(ceiling: Duration) -> Duration {
+    let mut bytes = [0; 8];
+    if openssl::rand::rand_bytes(&mut bytes).is_err() {
+        return ceiling;
+    }
+    let nanos = ceiling.as_nanos().min(u64::MAX as u128) as u64;
+    Duration::from_nanos(u64::from_le_bytes(bytes) % nanos.saturating_add(1))
+}
+
+/// a retry loop's position in its `RetryPolicy`, reset once a call goes through
+pub struct Backoff {
+    policy: RetryPolicy,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Backoff { policy, attempt: 0 }
+    }
+
+    /// how long to wait after `error` before trying again
+    pub fn next_delay(&mut self, error: &HandlerError) -> Duration {
+        let delay = self.policy.delay(self.attempt, error.retry_after());
+        self.attempt = self.attempt.saturating_add(1);
+        info!(
+            "retrying in {:?} (attempt {}) after: {}",
+            delay, self.attempt, error
+        );
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// the open period is over and one call is on its way to probe the server
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+/// a probe that has not come back by then was probably dropped, so another one may go
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// counters for the logs, since the daemon started
+#[derive(Debug, Clone, PartialEq)]
+pub struct BreakerStats {
+    pub state: BreakerState,
+    pub opened: u64,
+    pub rejected: u64,
+}
+
+/**
+ * stops api calls after `failure_threshold` consecutive transient failures (see
+ * `HandlerError::is_transient`), refusing them with `HandlerError::CircuitOpen` for
+ * `open_for`. after that a single call is let through: it closes the breaker again if it
+ * succeeds and reopens it if not.
+ *
+ * other errors (404, 401, bad input) mean the server is up, so they count as successes.
+ */
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    state: Mutex<BreakerState>,
+    opened: AtomicU64,
+    rejected: AtomicU64,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        CircuitBreaker {
+            failure_threshold,
+            open_for,
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+            opened: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// the breaker configured in `retry`, if `retry.breaker_failures` is set
+    pub fn from_settings(settings: &RetrySettings) -> Option<Self> {
+        settings.breaker_failures.map(|failures| {
+            CircuitBreaker::new(failures, Duration::from_secs(settings.breaker_open_secs))
+        })
+    }
+
+    /// whether a call may be made now
+    pub fn allow(&self) -> ApiResult<()> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BreakerState::Closed { .. } => Ok(()),
+            BreakerState::Open { until } if Instant::now() >= until => {
+                info!("circuit half open, probing the server");
+                *state = BreakerState::HalfOpen {
+                    since: Instant::now(),
+                };
+                Ok(())
+            }
+            BreakerState::HalfOpen { since } if since.elapsed() >= PROBE_TIMEOUT => {
+                *state = BreakerState::HalfOpen {
+                    since: Instant::now(),
+                };
+                Ok(())
+            }
+            BreakerState::Open { until } => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(HandlerError::CircuitOpen(
+                    until.saturating_duration_since(Instant::now()),
+                ))
+            }
+            BreakerState::HalfOpen { .. } => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(HandlerError::CircuitOpen(Duration::ZERO))
+            }
+        }
+    }
+
+    /// counts the outcome of a call `allow` let through
+    pub fn record<T>(&self, result: &ApiResult<T>) {
+        let failed = matches!(result, Err(e) if e.is_transient());
+        let mut state = self.state.lock().unwrap();
+        *state = match (*state, failed) {
+            (BreakerState::Closed { .. }, false) => BreakerState::Closed { failures: 0 },
+            (BreakerState::HalfOpen { .. }, false) => {
+                info!("server answered again, circuit closed");
+                BreakerState::Closed { failures: 0 }
+            }
+            (BreakerState::Closed { failures }, true) if failures + 1 < self.failure_threshold => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (BreakerState::Closed { .. } | BreakerState::HalfOpen { .. }, true) => {
+                let opened = self.opened.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "circuit open, no api calls for {:?} (opened {} times, {} calls refused)",
+                    self.open_for,
+                    opened,
+                    self.rejected.load(Ordering::Relaxed)
+                );
+                BreakerState::Open {
+                    until: Instant::now() + self.open_for,
+                }
+            }
+            // a call that was already on its way when the breaker opened
+            (open @ BreakerState::Open { .. }, _) => open,
+        };
+    }
+
+    pub fn stats(&self) -> BreakerStats {
+        BreakerStats {
+            state: *self.state.lock().unwrap(),
+            opened: self.opened.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::models::HandlerError;
+
+    use super::{Backoff, BreakerState, CircuitBreaker, RetryPolicy};
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            base: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn test_ceiling_doubles_up_to_max() {
+        let policy = policy();
+        assert_eq!(policy.ceiling(0), Duration::from_millis(10));
+        assert_eq!(policy.ceiling(3), Duration::from_millis(80));
+        assert_eq!(policy.ceiling(20), Duration::from_secs(1));
+        assert_eq!(policy.ceiling(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_delay_is_jittered_below_ceiling() {
+        let policy = policy();
+        let delays: Vec<Duration> = (0..50).map(|_| policy.delay(3, None)).collect();
+
+        assert!(delays
+            .iter()
+            .all(|delay| *delay <= Duration::from_millis(80)));
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[test]
+    fn test_delay_honours_retry_after_up_to_max() {
+        let policy = policy();
+        assert!(policy.delay(0, Some(Duration::from_millis(500))) >= Duration::from_millis(500));
+        assert_eq!(
+            policy.delay(0, Some(Duration::from_secs(3600))),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_backoff_grows_and_resets() {
+        let mut backoff = Backoff::new(RetryPolicy {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(1000),
+        });
+        let error = HandlerError::ServerError;
+        for _ in 0..8 {
+            backoff.next_delay(&error);
+        }
+        // ceiling 256s: the odds of 50 delays all under 1s are negligible
+        assert!((0..50).any(|_| backoff.next_delay(&error) > Duration::from_secs(1)));
+
+        backoff.reset();
+        assert!(backoff.next_delay(&error) <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_breaker_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        let failure: Result<(), HandlerError> = Err(HandlerError::ServerError);
+
+        breaker.record(&failure);
+        breaker.record(&failure);
+        breaker.record(&Ok(()));
+        breaker.record(&failure);
+        breaker.record(&failure);
+        assert!(breaker.allow().is_ok());
+
+        breaker.record(&failure);
+        assert!(matches!(
+            breaker.allow(),
+            Err(HandlerError::CircuitOpen(after)) if after > Duration::from_secs(50)
+        ));
+        let stats = breaker.stats();
+        assert_eq!((stats.opened, stats.rejected), (1, 1));
+    }
+
+    #[test]
+    fn test_breaker_ignores_client_errors() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(60));
+
+        breaker.record::<()>(&Err(HandlerError::NotFound));
+        breaker.record::<()>(&Err(HandlerError::Unauthorized));
+
+        assert!(breaker.allow().is_ok());
+    }
+
+    #[test]
+    fn test_breaker_half_open_probe() {
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        let failure: Result<(), HandlerError> = Err(HandlerError::Throttled(None));
+
+        breaker.record(&failure);
+        // open period over: one probe goes through, the others wait for it
+        assert!(breaker.allow().is_ok());
+        assert!(breaker.allow().is_err());
+        breaker.record(&failure);
+        assert!(matches!(breaker.stats().state, BreakerState::Open { .. }));
+
+        assert!(breaker.allow().is_ok());
+        breaker.record(&Ok(()));
+        assert_eq!(breaker.stats().state, BreakerState::Closed { failures: 0 });
+        assert!(breaker.allow().is_ok());
+    }
+}
@@ -0,0 +1,257 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sha::sha256;
+use openssl::sign::Signer;
+
+use crate::models::HandlerError;
+
+pub const TIMESTAMP_HEADER: &str = "x-itx-timestamp";
+pub const NONCE_HEADER: &str = "x-itx-nonce";
+pub const SIGNATURE_HEADER: &str = "x-itx-signature";
+/// signed timestamps further than this from the local clock are rejected
+pub const MAX_SKEW_SECS: u64 = 300;
+/// context string mixed into the key so the issued secret itself never signs anything
+const KEY_CONTEXT: &[u8] = b"itx-request-signing-v1";
+
+/**
+ * hmac-sha256 request signing with a key derived from the signing secret the server issues
+ * at registration. unlike the bearer token the secret never goes over the wire again, so
+ * whoever reads a request cannot forge the server's side.
+ *
+ * requests are signed over
+ *     METHOD\npath\nquery\ntimestamp\nnonce\nhex(sha256(body))
+ * and the server signs its responses over
+ *     status\ntimestamp\nrequest nonce\nhex(sha256(body))
+ * so a response only verifies for the request it answers and cannot be replayed.
+ *
+ * messages pushed over the websocket have no request to answer; the server signs them over
+ *     push\nkind\ntimestamp\nconnection nonce\nhex(sha256(payload))
+ * with the nonce the daemon sent when it opened the socket. `kind` is the message type (e.g.
+ * `command` or `cancel`), so a payload signed as one kind cannot be replayed as another.
+ */
+pub struct RequestSigner {
+    key: Vec<u8>,
+}
+
+impl RequestSigner {
+    pub fn from_secret(secret: &str) -> Result<Self, HandlerError> {
+        let key = hmac(secret.as_bytes(), KEY_CONTEXT)?;
+        Ok(RequestSigner { key })
+    }
+
+    pub fn sign_request(
+        &self,
+        method: &str,
+        path: &str,
+        query: &str,
+        body: &[u8],
+        timestamp: u64,
+        nonce: &str,
+    ) -> Result<String, HandlerError> {
+        let canonical = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method,
+            path,
+            query,
+            timestamp,
+            nonce,
+            hex::encode(sha256(body))
+        );
+        Ok(STANDARD.encode(hmac(&self.key, canonical.as_bytes())?))
+    }
+
+    pub fn sign_response(
+        &self,
+        status: u16,
+        body: &[u8],
+        timestamp: u64,
+        request_nonce: &str,
+    ) -> Result<String, HandlerError> {
+        let canonical = format!(
+            "{}\n{}\n{}\n{}",
+            status,
+            timestamp,
+            request_nonce,
+            hex::encode(sha256(body))
+        );
+        Ok(STANDARD.encode(hmac(&self.key, canonical.as_bytes())?))
+    }
+
+    pub fn verify_response(
+        &self,
+        status: u16,
+        body: &[u8],
+        timestamp: Option<&str>,
+        signature: Option<&str>,
+        request_nonce: &str,
+        now_secs: u64,
+    ) -> Result<(), HandlerError> {
+        let (timestamp, signature) = match (timestamp, signature) {
+            (Some(timestamp), Some(signature)) => (timestamp, signature),
+            _ => return Err(invalid("response is not signed")),
+        };
+        let timestamp: u64 = timestamp
+            .parse()
+            .map_err(|_| invalid("unreadable response timestamp"))?;
+        if timestamp.abs_diff(now_secs) > MAX_SKEW_SECS {
+            return Err(invalid("response timestamp outside the allowed skew"));
+        }
+
+        let expected = self.sign_response(status, body, timestamp, request_nonce)?;
+        check(&expected, signature, "response signature mismatch")
+    }
+
+    pub fn sign_push(
+        &self,
+        kind: &str,
+        payload: &[u8],
+        timestamp: u64,
+        connection_nonce: &str,
+    ) -> Result<String, HandlerError> {
+        let canonical = format!(
+            "push\n{}\n{}\n{}\n{}",
+            kind,
+            timestamp,
+            connection_nonce,
+            hex::encode(sha256(payload))
+        );
+        Ok(STANDARD.encode(hmac(&self.key, canonical.as_bytes())?))
+    }
+
+    pub fn verify_push(
+        &self,
+        kind: &str,
+        payload: &[u8],
+        timestamp: u64,
+        signature: &str,
+        connection_nonce: &str,
+        now_secs: u64,
+    ) -> Result<(), HandlerError> {
+        if timestamp.abs_diff(now_secs) > MAX_SKEW_SECS {
+            return Err(invalid("pushed message timestamp outside the allowed skew"));
+        }
+        let expected = self.sign_push(kind, payload, timestamp, connection_nonce)?;
+        check(&expected, signature, "pushed message signature mismatch")
+    }
+}
+
+fn check(expected: &str, signature: &str, reason: &str) -> Result<(), HandlerError> {
+    if expected.len() != signature.len() || !memcmp::eq(expected.as_bytes(), signature.as_bytes()) {
+        return Err(invalid(reason));
+    }
+    Ok(())
+}
+
+/// random hex nonce, unique per request
+pub fn new_nonce() -> Result<String, HandlerError> {
+    let mut bytes = [0; 16];
+    openssl::rand::rand_bytes(&mut bytes)?;
+    Ok(hex::encode(bytes))
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>, HandlerError> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+fn invalid(reason: &str) -> HandlerError {
+    HandlerError::InvalidSignature(reason.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{new_nonce, RequestSigner};
+
+    #[test]
+    fn test_request_signature_covers_every_field() {
+        let signer = RequestSigner::from_secret("secret").unwrap();
+        let sign = |method, path, query, body: &[u8], timestamp, nonce| {
+            signer
+                .sign_request(method, path, query, body, timestamp, nonce)
+                .unwrap()
+        };
+
+        let base = sign("GET", "/commands/recent", "device_id=a", b"", 100, "n");
+        assert_eq!(
+            base,
+            sign("GET", "/commands/recent", "device_id=a", b"", 100, "n")
+        );
+        assert_ne!(
+            base,
+            sign("POST", "/commands/recent", "device_id=a", b"", 100, "n")
+        );
+        assert_ne!(base, sign("GET", "/commands", "device_id=a", b"", 100, "n"));
+        assert_ne!(
+            base,
+            sign("GET", "/commands/recent", "device_id=b", b"", 100, "n")
+        );
+        assert_ne!(
+            base,
+            sign("GET", "/commands/recent", "device_id=a", b"x", 100, "n")
+        );
+        assert_ne!(
+            base,
+            sign("GET", "/commands/recent", "device_id=a", b"", 101, "n")
+        );
+        assert_ne!(
+            base,
+            sign("GET", "/commands/recent", "device_id=a", b"", 100, "m")
+        );
+
+        let other = RequestSigner::from_secret("other").unwrap();
+        assert_ne!(
+            base,
+            other
+                .sign_request("GET", "/commands/recent", "device_id=a", b"", 100, "n")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_verify_response() {
+        let signer = RequestSigner::from_secret("secret").unwrap();
+        let body = br#"{"command": {}}"#;
+        let signature = signer.sign_response(200, body, 1000, "nonce").unwrap();
+        let verify = |body: &[u8], timestamp, signature, nonce, now| {
+            signer.verify_response(200, body, timestamp, signature, nonce, now)
+        };
+
+        assert!(verify(body, Some("1000"), Some(&signature), "nonce", 1000).is_ok());
+        assert!(verify(body, Some("1000"), Some(&signature), "nonce", 1200).is_ok());
+        // tampered body, replayed for another request, stale, unsigned
+        assert!(verify(b"{}", Some("1000"), Some(&signature), "nonce", 1000).is_err());
+        assert!(verify(body, Some("1000"), Some(&signature), "other", 1000).is_err());
+        assert!(verify(body, Some("1000"), Some(&signature), "nonce", 2000).is_err());
+        assert!(verify(body, Some("1000"), None, "nonce", 1000).is_err());
+        assert!(verify(body, None, Some(&signature), "nonce", 1000).is_err());
+    }
+
+    #[test]
+    fn test_verify_push() {
+        let signer = RequestSigner::from_secret("secret").unwrap();
+        let payload = br#"{"_id": "a"}"#;
+        let signature = signer.sign_push("command", payload, 1000, "nonce").unwrap();
+        let verify = |kind, payload: &[u8], signature: &str, nonce, now| {
+            signer.verify_push(kind, payload, 1000, signature, nonce, now)
+        };
+
+        assert!(verify("command", payload, &signature, "nonce", 1000).is_ok());
+        // tampered, another kind, replayed on another connection, stale, a response signature
+        assert!(verify("command", br#"{"_id": "b"}"#, &signature, "nonce", 1000).is_err());
+        assert!(verify("cancel", payload, &signature, "nonce", 1000).is_err());
+        assert!(verify("command", payload, &signature, "other", 1000).is_err());
+        assert!(verify("command", payload, &signature, "nonce", 2000).is_err());
+        let response = signer.sign_response(200, payload, 1000, "nonce").unwrap();
+        assert!(verify("command", payload, &response, "nonce", 1000).is_err());
+    }
+
+    #[test]
+    fn test_nonces_are_unique() {
+        assert_ne!(new_nonce().unwrap(), new_nonce().unwrap());
+    }
+}
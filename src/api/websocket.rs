@@ -0,0 +1,915 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::{SinkExt as _, StreamExt as _};
+use log::{debug, error, info, warn};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest as _;
+use tokio_tungstenite::tungstenite::http::{header, StatusCode};
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::api::auth::DeviceAuth;
+use crate::api::client;
+use crate::api::control_plane::{ControlPlane, HttpControlPlane};
+use crate::api::models::capabilities::DeviceCapabilities;
+use crate::api::models::heartbeat::HeartbeatRequest;
+use crate::api::models::register_device::RegisterDeviceResponse;
+use crate::api::models::update_command_status::UpdateCommandStatusRequest;
+use crate::api::models::upload_command_output::UploadCommandOutputRequest;
+use crate::api::models::upload_command_result::UploadCommandResultRequest;
+use crate::api::models::websocket::{DeliveryAck, DeviceFrame, DeviceMessage, ServerMessage};
+use crate::api::requests::ApiResult;
+use crate::api::signing::{new_nonce, RequestSigner, NONCE_HEADER};
+use crate::executor::now_ms;
+use crate::models::db::commands::{Command, CommandStatus};
+use crate::models::db::common::{HasId, Id};
+use crate::models::db::output::OutputChunk;
+use crate::models::HandlerError;
+use crate::shutdown::Shutdown;
+
+/// how long a message may wait for the server's ack before falling back to http
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// pushed commands buffered; more are left on the server for polling
+const COMMAND_BUFFER: usize = 16;
+
+type Outgoing = (DeviceMessage, oneshot::Sender<ApiResult<()>>);
+
+/// where the connection task hands over what the server pushes
+struct Pushed {
+    commands: mpsc::Sender<Command>,
+    cancellations: mpsc::UnboundedSender<Id>,
+    /// set when a command did not fit in the buffer
+    missed: Arc<AtomicBool>,
+}
+
+/**
+ * persistent command socket: the server pushes `Command`s (and cancellations) and the daemon
+ * sends status updates back over the same connection. pushed messages must be signed with the
+ * device's signing secret for the connection's nonce (see `api::signing`), or they are dropped.
+ * a message the daemon sends only counts as delivered once the server acks its frame the same
+ * way; one the socket loses before that fails, so the caller can send it over http instead.
+ * a background task keeps (re)connecting every `reconnect` until shutdown; callers check
+ * `is_connected` and use http polling otherwise.
+ */
+#[derive(Clone)]
+pub struct WebSocketTransport {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    connected: watch::Receiver<bool>,
+    commands: Mutex<mpsc::Receiver<Command>>,
+    cancellations: Mutex<mpsc::UnboundedReceiver<Id>>,
+    outgoing: mpsc::UnboundedSender<Outgoing>,
+    missed: Arc<AtomicBool>,
+}
+
+impl WebSocketTransport {
+    pub fn spawn(
+        url: String,
+        reconnect: Duration,
+        auth: Option<Arc<DeviceAuth>>,
+        shutdown: Shutdown,
+    ) -> Self {
+        let (connected_tx, connected) = watch::channel(false);
+        let (commands_tx, commands) = mpsc::channel(COMMAND_BUFFER);
+        let (cancellations_tx, cancellations) = mpsc::unbounded_channel();
+        let (outgoing, outgoing_rx) = mpsc::unbounded_channel();
+        let missed = Arc::new(AtomicBool::new(false));
+
+        tokio::spawn(run_connection(
+            url,
+            reconnect,
+            auth,
+            connected_tx,
+            Pushed {
+                commands: commands_tx,
+                cancellations: cancellations_tx,
+                missed: missed.clone(),
+            },
+            outgoing_rx,
+            shutdown,
+        ));
+
+        WebSocketTransport {
+            inner: Arc::new(Inner {
+                connected,
+                commands: Mutex::new(commands),
+                cancellations: Mutex::new(cancellations),
+                outgoing,
+                missed,
+            }),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        *self.inner.connected.borrow()
+    }
+
+    /// waits up to `wait` for the server to push a command
+    pub async fn next_command(&self, wait: Duration) -> Option<Command> {
+        let mut commands = self.inner.commands.lock().await;
+        tokio::time::timeout(wait, commands.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+
+    /// true (once) if a pushed command was dropped because the buffer was full; it is still
+    /// pending on the server, so the caller should poll for it
+    pub fn take_missed(&self) -> bool {
+        self.inner.missed.swap(false, Ordering::SeqCst)
+    }
+
+    /// makes the next `take_missed` true again, e.g. while polling still finds commands
+    pub fn mark_missed(&self) {
+        self.inner.missed.store(true, Ordering::SeqCst);
+    }
+
+    /// cancellations pushed since the last call
+    pub async fn take_cancellations(&self) -> Vec<Id> {
+        let mut cancellations = self.inner.cancellations.lock().await;
+        let mut command_ids = vec![];
+        while let Ok(command_id) = cancellations.try_recv() {
+            command_ids.push(command_id);
+        }
+        command_ids
+    }
+
+    pub async fn update_command_status(
+        &self,
+        command: &Command,
+        new_status: CommandStatus,
+    ) -> ApiResult<()> {
+        let request = UpdateCommandStatusRequest {
+            command_id: command.get_id().clone(),
+            status: new_status,
+        };
+        self.send(DeviceMessage::UpdateStatus(request)).await
+    }
+
+    pub async fn upload_command_result(
+        &self,
+        request: &UploadCommandResultRequest,
+    ) -> ApiResult<()> {
+        self.send(DeviceMessage::UploadResult(Box::new(request.clone())))
+            .await
+    }
+
+    pub async fn upload_output(&self, command: &Command, chunks: &[OutputChunk]) -> ApiResult<()> {
+        let request = UploadCommandOutputRequest {
+            command_id: command.get_id().clone(),
+            chunks: chunks.to_vec(),
+        };
+        self.send(DeviceMessage::UploadOutput(request)).await
+    }
+
+    async fn send(&self, message: DeviceMessage) -> ApiResult<()> {
+        if !self.is_connected() {
+            return Err(HandlerError::NotConnected);
+        }
+        let (ack_tx, ack_rx) = oneshot::channel();
+        self.inner
+            .outgoing
+            .send((message, ack_tx))
+            .map_err(|_| HandlerError::NotConnected)?;
+        match tokio::time::timeout(SEND_TIMEOUT, ack_rx).await {
+            Ok(Ok(result)) => result,
+            _ => Err(HandlerError::NotConnected),
+        }
+    }
+}
+
+/**
+ * control plane that takes pushed commands from the websocket while it is connected and
+ * otherwise (and for registration) goes through the http api.
+ */
+pub struct WebSocketControlPlane {
+    transport: WebSocketTransport,
+    fallback: HttpControlPlane,
+    /// how long a fetch waits for a pushed command
+    wait: Duration,
+}
+
+impl WebSocketControlPlane {
+    pub fn new(transport: WebSocketTransport, fallback: HttpControlPlane, wait: Duration) -> Self {
+        WebSocketControlPlane {
+            transport,
+            fallback,
+            wait,
+        }
+    }
+}
+
+impl ControlPlane for WebSocketControlPlane {
+    fn register_device<'a>(
+        &'a self,
+        user_id: &'a Id,
+        user_secret: &'a str,
+        device_name: String,
+        csr: Option<String>,
+        capabilities: Option<DeviceCapabilities>,
+    ) -> BoxFuture<'a, ApiResult<RegisterDeviceResponse>> {
+        self.fallback
+            .register_device(user_id, user_secret, device_name, csr, capabilities)
+    }
+
+    fn advertise_capabilities<'a>(
+        &'a self,
+        device_id: &'a Id,
+        capabilities: &'a DeviceCapabilities,
+    ) -> BoxFuture<'a, ApiResult<()>> {
+        self.fallback
+            .advertise_capabilities(device_id, capabilities)
+    }
+
+    fn send_heartbeat<'a>(&'a self, request: &'a HeartbeatRequest) -> BoxFuture<'a, ApiResult<()>> {
+        self.fallback.send_heartbeat(request)
+    }
+
+    fn renew_certificate<'a>(
+        &'a self,
+        device_id: &'a Id,
+        csr: String,
+    ) -> BoxFuture<'a, ApiResult<String>> {
+        self.fallback.renew_certificate(device_id, csr)
+    }
+
+    /// binaries are too large for the socket, so always over http
+    fn download_update<'a>(&'a self, version: &'a str) -> BoxFuture<'a, ApiResult<Vec<u8>>> {
+        self.fallback.download_update(version)
+    }
+
+    /// pushed commands first; commands the buffer had no room for are polled until none is left
+    fn fetch_command<'a>(&'a self, device_id: &'a Id) -> BoxFuture<'a, ApiResult<Option<Command>>> {
+        if !self.transport.is_connected() {
+            return self.fallback.fetch_command(device_id);
+        }
+        Box::pin(async move {
+            if !self.transport.take_missed() {
+                return Ok(self.transport.next_command(self.wait).await);
+            }
+            if let Some(command) = self.transport.next_command(Duration::ZERO).await {
+                self.transport.mark_missed();
+                return Ok(Some(command));
+            }
+            let polled = self.fallback.fetch_command(device_id).await;
+            if !matches!(polled, Ok(None)) {
+                self.transport.mark_missed();
+            }
+            polled
+        })
+    }
+
+    fn fetch_cancellations<'a>(&'a self, device_id: &'a Id) -> BoxFuture<'a, ApiResult<Vec<Id>>> {
+        if self.transport.is_connected() {
+            Box::pin(async move { Ok(self.transport.take_cancellations().await) })
+        } else {
+            self.fallback.fetch_cancellations(device_id)
+        }
+    }
+
+    fn update_command_status<'a>(
+        &'a self,
+        command: &'a Command,
+        new_status: CommandStatus,
+    ) -> BoxFuture<'a, ApiResult<()>> {
+        Box::pin(async move {
+            if self.transport.is_connected() {
+                match self
+                    .transport
+                    .update_command_status(command, new_status.clone())
+                    .await
+                {
+                    Ok(_) => return Ok(()),
+                    Err(e) => warn!("status update over websocket failed, using http: {}", e),
+                }
+            }
+            self.fallback
+                .update_command_status(command, new_status)
+                .await
+        })
+    }
+
+    fn upload_command_result<'a>(
+        &'a self,
+        request: &'a UploadCommandResultRequest,
+    ) -> BoxFuture<'a, ApiResult<()>> {
+        Box::pin(async move {
+            if self.transport.is_connected() {
+                match self.transport.upload_command_result(request).await {
+                    Ok(_) => return Ok(()),
+                    Err(e) => warn!("result upload over websocket failed, using http: {}", e),
+                }
+            }
+            self.fallback.upload_command_result(request).await
+        })
+    }
+
+    fn upload_output<'a>(
+        &'a self,
+        command: &'a Command,
+        chunks: &'a [OutputChunk],
+    ) -> BoxFuture<'a, ApiResult<()>> {
+        Box::pin(async move {
+            if self.transport.is_connected() {
+                match self.transport.upload_output(command, chunks).await {
+                    Ok(_) => return Ok(()),
+                    Err(e) => warn!("output upload over websocket failed, using http: {}", e),
+                }
+            }
+            self.fallback.upload_output(command, chunks).await
+        })
+    }
+
+    fn is_push(&self) -> bool {
+        self.transport.is_connected()
+    }
+}
+
+async fn run_connection(
+    url: String,
+    reconnect: Duration,
+    auth: Option<Arc<DeviceAuth>>,
+    connected: watch::Sender<bool>,
+    pushed: Pushed,
+    mut outgoing: mpsc::UnboundedReceiver<Outgoing>,
+    shutdown: Shutdown,
+) {
+    while !shutdown.is_triggered() {
+        let token = match &auth {
+            Some(auth) => match auth.token().await {
+                Ok(token) => token,
+                Err(e) => {
+                    warn!("no device token for the command websocket: {}", e);
+                    shutdown.sleep(reconnect).await;
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let secret = auth.as_ref().and_then(|auth| auth.signing_secret());
+        let signer = match secret
+            .as_deref()
+            .map(RequestSigner::from_secret)
+            .transpose()
+        {
+            Ok(signer) => signer,
+            Err(e) => {
+                warn!("cannot derive the websocket signing key: {}", e);
+                return;
+            }
+        };
+        let request = new_nonce()
+            .and_then(|nonce| Ok((connect_request(&url, token.as_deref(), &nonce)?, nonce)));
+        let (request, nonce) = match request {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("invalid command websocket url {}: {}", &url, e);
+                return;
+            }
+        };
+        let connector = client::websocket_connector();
+        match tokio_tungstenite::connect_async_tls_with_config(request, None, false, connector)
+            .await
+        {
+            Ok((socket, _)) => {
+                info!("command websocket connected to {}", &url);
+                let _ = connected.send(true);
+                let (mut sink, mut stream) = socket.split();
+                // sent frames waiting for the server's ack, dropped (failing them) with the socket
+                let mut unacked: HashMap<u64, (Id, oneshot::Sender<ApiResult<()>>)> =
+                    HashMap::new();
+                let mut next_id = 0;
+                loop {
+                    tokio::select! {
+                        incoming = stream.next() => match incoming {
+                            Some(Ok(Message::Text(text))) => {
+                                match serde_json::from_str::<ServerMessage>(&text) {
+                                    Ok(ServerMessage::Command { payload, timestamp, signature }) => {
+                                        let command = verify_pushed(
+                                            signer.as_ref(),
+                                            "command",
+                                            &nonce,
+                                            &payload,
+                                            timestamp,
+                                            &signature,
+                                        )
+                                        .and_then(|_| Ok(serde_json::from_str::<Command>(&payload)?));
+                                        match command {
+                                            Ok(command) => {
+                                                debug!("command pushed over websocket: {:?}", &command);
+                                                match pushed.commands.try_send(command) {
+                                                    Ok(_) => {}
+                                                    Err(TrySendError::Full(command)) => {
+                                                        warn!(
+                                                            "too many pushed commands, polling for {} instead",
+                                                            command.get_id()
+                                                        );
+                                                        pushed.missed.store(true, Ordering::SeqCst);
+                                                    }
+                                                    Err(TrySendError::Closed(_)) => return,
+                                                }
+                                            }
+                                            Err(e) => error!("rejecting pushed command: {}", e),
+                                        }
+                                    }
+                                    Ok(ServerMessage::Cancel { payload, timestamp, signature }) => {
+                                        let verified = verify_pushed(
+                                            signer.as_ref(),
+                                            "cancel",
+                                            &nonce,
+                                            &payload,
+                                            timestamp,
+                                            &signature,
+                                        );
+                                        match verified {
+                                            Ok(_) => {
+                                                debug!("cancellation pushed for command {}", &payload);
+                                                let _ = pushed.cancellations.send(payload);
+                                            }
+                                            Err(e) => error!("rejecting pushed cancellation: {}", e),
+                                        }
+                                    }
+                                    Ok(ServerMessage::Ack { payload, timestamp, signature }) => {
+                                        let delivered = verify_pushed(
+                                            signer.as_ref(),
+                                            "ack",
+                                            &nonce,
+                                            &payload,
+                                            timestamp,
+                                            &signature,
+                                        )
+                                        .and_then(|_| Ok(serde_json::from_str::<DeliveryAck>(&payload)?));
+                                        match delivered {
+                                            Ok(delivered) => match unacked.remove(&delivered.id) {
+                                                Some((command_id, ack)) if command_id == delivered.command_id => {
+                                                    let _ = ack.send(Ok(()));
+                                                }
+                                                _ => warn!(
+                                                    "ack for unknown frame {} of command {}",
+                                                    delivered.id, &delivered.command_id
+                                                ),
+                                            },
+                                            Err(e) => error!("rejecting ack: {}", e),
+                                        }
+                                    }
+                                    Err(e) => warn!("unreadable websocket message: {}", e),
+                                }
+                            }
+                            Some(Ok(Message::Close(_))) | None => {
+                                warn!("command websocket closed by server");
+                                break;
+                            }
+                            Some(Err(e)) => {
+                                warn!("command websocket error: {}", e);
+                                break;
+                            }
+                            // pings are answered by tungstenite on the next read/write
+                            Some(Ok(_)) => {}
+                        },
+                        message = outgoing.recv() => match message {
+                            Some((message, ack)) => {
+                                next_id += 1;
+                                let frame = DeviceFrame { id: next_id, message };
+                                if let Err(e) = send_frame(&mut sink, &frame).await {
+                                    let _ = ack.send(Err(e));
+                                    break;
+                                }
+                                // senders that gave up waiting are of no use anymore
+                                unacked.retain(|_, (_, ack)| !ack.is_closed());
+                                unacked.insert(frame.id, (frame.message.command_id().clone(), ack));
+                            }
+                            None => return,
+                        },
+                        _ = shutdown.wait() => {
+                            let _ = sink.close().await;
+                            break;
+                        }
+                    }
+                }
+                let _ = connected.send(false);
+            }
+            Err(tungstenite::Error::Http(response))
+                if response.status() == StatusCode::UNAUTHORIZED =>
+            {
+                warn!("command websocket rejected the device token");
+                if let Some(auth) = &auth {
+                    if let Err(e) = auth.reenroll(token.as_deref()).await {
+                        warn!("re-enrolling for the command websocket failed: {}", e);
+                    }
+                }
+            }
+            Err(e) => warn!(
+                "command websocket unavailable, polling over http instead: {}",
+                e
+            ),
+        }
+        shutdown.sleep(reconnect).await;
+    }
+}
+
+/// checks the server signed `payload` as a `kind` message for this connection
+fn verify_pushed(
+    signer: Option<&RequestSigner>,
+    kind: &str,
+    nonce: &str,
+    payload: &str,
+    timestamp: u64,
+    signature: &str,
+) -> Result<(), HandlerError> {
+    let signer = signer.ok_or_else(|| {
+        HandlerError::InvalidSignature("no signing secret to verify pushed messages".to_string())
+    })?;
+    signer.verify_push(
+        kind,
+        payload.as_bytes(),
+        timestamp,
+        signature,
+        nonce,
+        now_ms() / 1000,
+    )
+}
+
+fn connect_request(
+    url: &str,
+    token: Option<&str>,
+    nonce: &str,
+) -> Result<tungstenite::handshake::client::Request, HandlerError> {
+    let mut request = url.into_client_request()?;
+    let value = nonce
+        .parse()
+        .map_err(|_| HandlerError::InvalidSignature(nonce.to_string()))?;
+    request.headers_mut().insert(NONCE_HEADER, value);
+    if let Some(token) = token {
+        let value = format!("Bearer {}", token)
+            .parse()
+            .map_err(|_| HandlerError::Unauthorized)?;
+        request.headers_mut().insert(header::AUTHORIZATION, value);
+    }
+    Ok(request)
+}
+
+async fn send_frame<S>(sink: &mut S, frame: &DeviceFrame) -> ApiResult<()>
+where
+    S: futures::Sink<Message, Error = tokio_tungstenite::tungstenite::Error> + Unpin,
+{
+    let text = serde_json::to_string(frame)?;
+    sink.send(Message::Text(text)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use futures::{SinkExt as _, StreamExt as _};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::handshake::server::Request;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
+
+    use crate::{
+        api::auth::{test_auth, test_auth::SIGNING_SECRET, DeviceAuth},
+        api::control_plane::{ControlPlane, HttpControlPlane},
+        api::models::fetch_commands::FetchRecentCommandResponse,
+        api::models::websocket::{DeliveryAck, DeviceFrame, DeviceMessage, ServerMessage},
+        api::requests::ApiConfig,
+        api::signing::{RequestSigner, NONCE_HEADER},
+        executor::now_ms,
+        models::db::commands::{Command, CommandStatus},
+        shutdown,
+        test_commons::{before_each, setup_signed_server},
+    };
+
+    use super::{WebSocketControlPlane, WebSocketTransport, COMMAND_BUFFER};
+
+    fn get_auth() -> Arc<DeviceAuth> {
+        Arc::new(test_auth::with_token(ApiConfig::default(), "testtoken"))
+    }
+
+    /// accepts the daemon's connection, returning it with the nonce it sent
+    #[allow(clippy::result_large_err)]
+    async fn accept(listener: &TcpListener) -> (WebSocketStream<TcpStream>, String) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut nonce = String::new();
+        let socket = tokio_tungstenite::accept_hdr_async(stream, |request: &Request, response| {
+            nonce = request.headers()[NONCE_HEADER]
+                .to_str()
+                .unwrap()
+                .to_string();
+            Ok(response)
+        })
+        .await
+        .unwrap();
+        (socket, nonce)
+    }
+
+    /// timestamp and signature of `payload` as a `kind` message for the connection `nonce`
+    fn sign(kind: &str, payload: &str, secret: &str, nonce: &str) -> (u64, String) {
+        let timestamp = now_ms() / 1000;
+        let signature = RequestSigner::from_secret(secret)
+            .unwrap()
+            .sign_push(kind, payload.as_bytes(), timestamp, nonce)
+            .unwrap();
+        (timestamp, signature)
+    }
+
+    /// `command` as the server pushes it, signed with `secret` for the connection `nonce`
+    fn signed_push(command: &Command, secret: &str, nonce: &str) -> Message {
+        let payload = serde_json::to_string(command).unwrap();
+        let (timestamp, signature) = sign("command", &payload, secret, nonce);
+        let push = ServerMessage::Command {
+            payload,
+            timestamp,
+            signature,
+        };
+        Message::Text(serde_json::to_string(&push).unwrap())
+    }
+
+    /// a cancellation of `command_id`, signed with `secret` for the connection `nonce`
+    fn signed_cancel(command_id: &str, secret: &str, nonce: &str) -> Message {
+        let (timestamp, signature) = sign("cancel", command_id, secret, nonce);
+        let push = ServerMessage::Cancel {
+            payload: command_id.to_string(),
+            timestamp,
+            signature,
+        };
+        Message::Text(serde_json::to_string(&push).unwrap())
+    }
+
+    /// the server's ack of frame `id`, signed with `secret` for the connection `nonce`
+    fn signed_ack(id: u64, command_id: &str, secret: &str, nonce: &str) -> Message {
+        let delivered = DeliveryAck {
+            id,
+            command_id: command_id.to_string(),
+        };
+        let payload = serde_json::to_string(&delivered).unwrap();
+        let (timestamp, signature) = sign("ack", &payload, secret, nonce);
+        let push = ServerMessage::Ack {
+            payload,
+            timestamp,
+            signature,
+        };
+        Message::Text(serde_json::to_string(&push).unwrap())
+    }
+
+    fn command(args: &str) -> Command {
+        let mut command = Command::default();
+        command.args = Some(args.to_string());
+        command
+    }
+
+    async fn wait_until_connected(transport: &WebSocketTransport, connected: bool) {
+        for _ in 0..200 {
+            if transport.is_connected() == connected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("websocket never reached connected = {}", connected);
+    }
+
+    #[tokio::test]
+    async fn test_pushed_command_and_status_update() {
+        before_each();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/commands/ws", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, nonce) = accept(&listener).await;
+            let push = signed_push(&Command::default(), SIGNING_SECRET, &nonce);
+            socket.send(push).await.unwrap();
+            let reply = socket.next().await.unwrap().unwrap();
+            let frame = serde_json::from_str::<DeviceFrame>(reply.to_text().unwrap()).unwrap();
+            let ack = signed_ack(frame.id, frame.message.command_id(), SIGNING_SECRET, &nonce);
+            socket.send(ack).await.unwrap();
+            frame.message
+        });
+
+        let (trigger, shutdown) = shutdown::channel();
+        let transport =
+            WebSocketTransport::spawn(url, Duration::from_millis(50), Some(get_auth()), shutdown);
+        wait_until_connected(&transport, true).await;
+
+        let command = transport.next_command(Duration::from_secs(5)).await;
+        assert!(command.is_some());
+        let command = command.unwrap();
+
+        let result = transport
+            .update_command_status(&command, CommandStatus::Received)
+            .await;
+        assert!(result.is_ok());
+
+        let reply = server.await.unwrap();
+        assert!(matches!(
+            reply,
+            DeviceMessage::UpdateStatus(request) if matches!(request.status, CommandStatus::Received)
+        ));
+        trigger.trigger();
+    }
+
+    #[tokio::test]
+    async fn test_commands_past_the_buffer_are_polled() {
+        before_each();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/commands/ws", listener.local_addr().unwrap());
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let (mut socket, nonce) = accept(&listener).await;
+            for i in 0..=COMMAND_BUFFER {
+                let push = signed_push(&command(&format!("pushed {}", i)), SIGNING_SECRET, &nonce);
+                socket.send(push).await.unwrap();
+            }
+            let frame = socket.next().await.unwrap().unwrap();
+            let frame = serde_json::from_str::<DeviceFrame>(frame.to_text().unwrap()).unwrap();
+            let ack = signed_ack(frame.id, frame.message.command_id(), SIGNING_SECRET, &nonce);
+            socket.send(ack).await.unwrap();
+            let _ = done_rx.await;
+        });
+        let polled = serde_json::to_string(&FetchRecentCommandResponse::new(command("polled")));
+        let fallback = HttpControlPlane::new(setup_signed_server("testsecret", polled.unwrap()));
+
+        let (trigger, shutdown) = shutdown::channel();
+        let transport =
+            WebSocketTransport::spawn(url, Duration::from_millis(50), Some(get_auth()), shutdown);
+        wait_until_connected(&transport, true).await;
+        let control_plane =
+            WebSocketControlPlane::new(transport.clone(), fallback, Duration::from_millis(100));
+
+        // the socket still reads (and takes acks) after the buffer filled up
+        let result = transport
+            .update_command_status(&Command::default(), CommandStatus::Received)
+            .await;
+        assert!(result.is_ok());
+
+        let device_id = "testdeviceid".to_string();
+        for i in 0..COMMAND_BUFFER {
+            let fetched = control_plane.fetch_command(&device_id).await.unwrap();
+            assert_eq!(fetched.unwrap().args, Some(format!("pushed {}", i)));
+        }
+        let fetched = control_plane.fetch_command(&device_id).await.unwrap();
+        assert_eq!(fetched.unwrap().args.as_deref(), Some("polled"));
+        let _ = done_tx.send(());
+        trigger.trigger();
+    }
+
+    #[tokio::test]
+    async fn test_message_lost_before_the_ack_fails() {
+        before_each();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/commands/ws", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = accept(&listener).await;
+            let frame = socket.next().await.unwrap().unwrap();
+            let frame = serde_json::from_str::<DeviceFrame>(frame.to_text().unwrap()).unwrap();
+            // an ack signed for another connection, then the socket goes away
+            let forged = signed_ack(
+                frame.id,
+                frame.message.command_id(),
+                SIGNING_SECRET,
+                "other",
+            );
+            socket.send(forged).await.unwrap();
+            socket.close(None).await.unwrap();
+        });
+
+        let (trigger, shutdown) = shutdown::channel();
+        let transport =
+            WebSocketTransport::spawn(url, Duration::from_secs(60), Some(get_auth()), shutdown);
+        wait_until_connected(&transport, true).await;
+
+        let result = transport
+            .update_command_status(&Command::default(), CommandStatus::Received)
+            .await;
+        assert!(result.is_err());
+        trigger.trigger();
+    }
+
+    #[tokio::test]
+    async fn test_pushed_command_must_be_signed_for_the_connection() {
+        before_each();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/commands/ws", listener.local_addr().unwrap());
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let (mut socket, nonce) = accept(&listener).await;
+            for push in [
+                signed_push(&command("other secret"), "othersecret", &nonce),
+                signed_push(&command("other connection"), SIGNING_SECRET, "othernonce"),
+                signed_push(&command("signed"), SIGNING_SECRET, &nonce),
+            ] {
+                socket.send(push).await.unwrap();
+            }
+            let _ = done_rx.await;
+        });
+
+        let (trigger, shutdown) = shutdown::channel();
+        let transport =
+            WebSocketTransport::spawn(url, Duration::from_millis(50), Some(get_auth()), shutdown);
+        wait_until_connected(&transport, true).await;
+
+        let command = transport.next_command(Duration::from_secs(5)).await;
+        assert_eq!(command.unwrap().args.as_deref(), Some("signed"));
+        assert!(transport
+            .next_command(Duration::from_millis(100))
+            .await
+            .is_none());
+        let _ = done_tx.send(());
+        trigger.trigger();
+    }
+
+    #[tokio::test]
+    async fn test_pushed_command_is_dropped_without_a_signing_secret() {
+        before_each();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/commands/ws", listener.local_addr().unwrap());
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let (mut socket, nonce) = accept(&listener).await;
+            let push = signed_push(&Command::default(), SIGNING_SECRET, &nonce);
+            socket.send(push).await.unwrap();
+            let _ = done_rx.await;
+        });
+
+        let (trigger, shutdown) = shutdown::channel();
+        let transport = WebSocketTransport::spawn(url, Duration::from_millis(50), None, shutdown);
+        wait_until_connected(&transport, true).await;
+
+        assert!(transport
+            .next_command(Duration::from_millis(200))
+            .await
+            .is_none());
+        let _ = done_tx.send(());
+        trigger.trigger();
+    }
+
+    #[tokio::test]
+    async fn test_pushed_cancellation() {
+        before_each();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/commands/ws", listener.local_addr().unwrap());
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            let (mut socket, nonce) = accept(&listener).await;
+            for push in [
+                signed_cancel("other secret", "othersecret", &nonce),
+                signed_cancel("other connection", SIGNING_SECRET, "othernonce"),
+                signed_cancel("cancelme", SIGNING_SECRET, &nonce),
+            ] {
+                socket.send(push).await.unwrap();
+            }
+            let _ = done_rx.await;
+        });
+
+        let (trigger, shutdown) = shutdown::channel();
+        let transport =
+            WebSocketTransport::spawn(url, Duration::from_millis(50), Some(get_auth()), shutdown);
+        wait_until_connected(&transport, true).await;
+
+        let mut cancellations = vec![];
+        for _ in 0..200 {
+            cancellations = transport.take_cancellations().await;
+            if !cancellations.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(cancellations, vec!["cancelme".to_string()]);
+        assert!(transport.take_cancellations().await.is_empty());
+        let _ = done_tx.send(());
+        trigger.trigger();
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_socket_is_not_connected() {
+        before_each();
+
+        // grab a free port and close it again so nothing is listening
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/commands/ws", listener.local_addr().unwrap());
+        drop(listener);
+
+        let (trigger, shutdown) = shutdown::channel();
+        let transport = WebSocketTransport::spawn(url, Duration::from_millis(50), None, shutdown);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(!transport.is_connected());
+        let result = transport
+            .update_command_status(&Command::default(), CommandStatus::Received)
+            .await;
+        assert!(result.is_err());
+        trigger.trigger();
+    }
+}
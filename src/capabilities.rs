@@ -0,0 +1,180 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{error, info};
+
+use crate::api::control_plane::ControlPlane;
+use crate::api::models::capabilities::{DeviceCapabilities, PlatformInfo};
+use crate::api::retry::{Backoff, RetryPolicy};
+use crate::api::PROTOCOL_VERSION;
+use crate::config::DaemonConfig;
+use crate::handlers::HandlerRegistry;
+use crate::models::db::common::Id;
+use crate::shutdown::Shutdown;
+use crate::updater::VERSION;
+
+/**
+ * what this daemon can do with `config`: its version, the commands in `handlers` and the
+ * optional features that are switched on, plus the platform it runs on.
+ *
+ * sent with the registration, and again by `keep_advertised` on every start and every
+ * `device.advertise_secs`, since an update or a config change may change any of it. the
+ * registration comes before the executor is set up and only knows the built-in handlers;
+ * the advertisement on start lists the ones the executor actually has.
+ */
+pub fn collect(config: &DaemonConfig, handlers: &HandlerRegistry) -> DeviceCapabilities {
+    let commands = handlers.describe();
+
+    let features = [
+        ("sandbox", config.sandbox.enabled),
+        ("output_streaming", config.output.stream),
+        ("websocket", config.transport.websocket),
+        ("result_gzip", config.result.gzip_min_bytes.is_some()),
+        (
+            "command_signatures",
+            config.security.command_public_key.is_some(),
+        ),
+        ("command_policy", config.policy.path.is_some()),
+        ("client_certificate", config.identity.client_certificate),
+        ("self_update", config.update.public_key.is_some()),
+    ]
+    .into_iter()
+    .filter(|(_, enabled)| *enabled)
+    .map(|(feature, _)| feature.to_string())
+    .collect();
+
+    DeviceCapabilities {
+        version: VERSION.to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        commands,
+        features,
+        platform: platform(),
+    }
+}
+
+fn platform() -> PlatformInfo {
+    PlatformInfo {
+        os: std::env::consts::OS.to_string(),
+        arch: std::env::consts::ARCH.to_string(),
+        os_release: sys_info::os_release().ok(),
+        distribution: sys_info::linux_os_release()
+            .ok()
+            .and_then(|release| release.pretty_name),
+    }
+}
+
+/// advertises `capabilities` now and then every `interval`, retrying failures with backoff
+pub async fn keep_advertised(
+    capabilities: DeviceCapabilities,
+    control_plane: Arc<dyn ControlPlane>,
+    device_id: Id,
+    interval: Duration,
+    retry: RetryPolicy,
+    shutdown: Shutdown,
+) {
+    let mut backoff = Backoff::new(retry);
+    loop {
+        let delay = match control_plane
+            .advertise_capabilities(&device_id, &capabilities)
+            .await
+        {
+            Ok(()) => {
+                info!("advertised version {} and capabilities", VERSION);
+                backoff.reset();
+                interval
+            }
+            Err(e) => {
+                error!("cannot advertise the device capabilities: {}", e);
+                backoff.next_delay(&e)
+            }
+        };
+        if !shutdown.sleep(delay).await {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::{
+        api::{
+            control_plane::{ControlPlane, InMemoryControlPlane},
+            retry::RetryPolicy,
+            PROTOCOL_VERSION,
+        },
+        config::DaemonConfig,
+        handlers::{shell::ShellHandler, HandlerRegistry},
+        shutdown,
+        updater::VERSION,
+    };
+
+    #[test]
+    fn test_collect_follows_config() {
+        let mut config = DaemonConfig::default();
+        let handlers = HandlerRegistry::builtin();
+
+        let capabilities = super::collect(&config, &handlers);
+        assert_eq!(capabilities.version, VERSION);
+        assert_eq!(capabilities.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(capabilities.platform.os, std::env::consts::OS);
+        assert!(!capabilities.features.contains(&"sandbox".to_string()));
+
+        config.sandbox.enabled = true;
+        config.update.public_key = Some("key".to_string());
+        let capabilities = super::collect(&config, &handlers);
+        assert!(capabilities.features.contains(&"sandbox".to_string()));
+        assert!(capabilities.features.contains(&"self_update".to_string()));
+    }
+
+    #[test]
+    fn test_collect_lists_registered_handlers() {
+        let config = DaemonConfig::default();
+        let names = |handlers: &HandlerRegistry| -> Vec<String> {
+            super::collect(&config, handlers)
+                .commands
+                .into_iter()
+                .map(|info| info.name)
+                .collect()
+        };
+
+        assert_eq!(names(&HandlerRegistry::builtin()), vec!["ShellCmd", "Test"]);
+        let mut handlers = HandlerRegistry::empty();
+        handlers.register(Arc::new(ShellHandler));
+        assert_eq!(names(&handlers), vec!["ShellCmd"]);
+    }
+
+    #[tokio::test]
+    async fn test_keep_advertised_retries_until_accepted() {
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.fail_next(1);
+        let (trigger, shutdown) = shutdown::channel();
+        let plane: Arc<dyn ControlPlane> = control_plane.clone();
+        let task = tokio::spawn(super::keep_advertised(
+            super::collect(&DaemonConfig::default(), &HandlerRegistry::builtin()),
+            plane,
+            "testdeviceid".to_string(),
+            Duration::from_secs(3600),
+            RetryPolicy {
+                base: Duration::from_millis(1),
+                max: Duration::from_millis(10),
+            },
+            shutdown,
+        ));
+
+        for _ in 0..200 {
+            if !control_plane.advertisements().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        trigger.trigger();
+        task.await.unwrap();
+
+        let advertisements = control_plane.advertisements();
+        assert_eq!(advertisements.len(), 1);
+        assert_eq!(advertisements[0].version, VERSION);
+    }
+}
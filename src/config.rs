@@ -0,0 +1,1125 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use log::{info, LevelFilter};
+use serde::Deserialize;
+
+use crate::api::client::parse_pin;
+use crate::models::HandlerError;
+use crate::verification::parse_public_key;
+
+/**
+ * daemon configuration, resolved in layers (later layers win):
+ * 1. built-in defaults (see the `Default` impls below)
+ * 2. toml config file: `--config <path>`, else `ITX_CONFIG`, else `./daemon.toml` if present
+ * 3. environment variables: `ITX_<SECTION>_<KEY>`, e.g. `ITX_API_HOST`, `ITX_POLL_SHORT_SECS`
+ * 4. cli flags: `--<section>-<key> <value>`, e.g. `--api-host`, `--poll-short-secs`
+ *
+ * the fully merged config is validated once at startup.
+ */
+pub const CONFIG_ENV_VAR: &str = "ITX_CONFIG";
+pub const CONFIG_FLAG: &str = "--config";
+pub const DEFAULT_CONFIG_PATH: &str = "daemon.toml";
+const ENV_PREFIX: &str = "ITX_";
+
+/// every overridable key, as `<section>.<key>`
+pub const CONFIG_KEYS: &[&str] = &[
+    "api.scheme",
+    "api.host",
+    "api.port",
+    "device.name",
+    "device.advertise_secs",
+    "poll.short_secs",
+    "poll.medium_secs",
+    "localstore.path",
+    "log.level",
+    "executor.default_timeout_secs",
+    "executor.kill_grace_secs",
+    "executor.max_concurrent",
+    "executor.per_command_limits",
+    "shutdown.drain_timeout_secs",
+    "transport.websocket",
+    "transport.websocket_path",
+    "transport.reconnect_secs",
+    "security.command_public_key",
+    "security.allow_unsigned_commands",
+    "security.signing_secret_path",
+    "policy.path",
+    "sandbox.enabled",
+    "sandbox.uid",
+    "sandbox.gid",
+    "sandbox.isolate_network",
+    "sandbox.tmp_size_mb",
+    "sandbox.cpu_secs",
+    "sandbox.address_space_mb",
+    "sandbox.open_files",
+    "sandbox.processes",
+    "sandbox.keep_env",
+    "output.stream",
+    "output.flush_millis",
+    "output.max_batch_bytes",
+    "result.max_output_bytes",
+    "result.gzip_min_bytes",
+    "outbox.path",
+    "outbox.retry_base_millis",
+    "outbox.retry_max_secs",
+    "outbox.flush_secs",
+    "journal.path",
+    "journal.retain_days",
+    "retry.base_millis",
+    "retry.max_secs",
+    "retry.breaker_failures",
+    "retry.breaker_open_secs",
+    "http.connect_timeout_secs",
+    "http.request_timeout_secs",
+    "http.pool_idle_secs",
+    "http.tcp_keepalive_secs",
+    "http.http2",
+    "http.http2_prior_knowledge",
+    "http.proxy",
+    "http.env_proxy",
+    "http.ca_bundle",
+    "http.pinned_keys",
+    "identity.client_certificate",
+    "identity.key_path",
+    "identity.renew_before_days",
+    "update.public_key",
+    "update.binary_path",
+    "update.health_deadline_secs",
+    "heartbeat.enabled",
+    "heartbeat.interval_secs",
+];
+
+static CONFIG: OnceLock<DaemonConfig> = OnceLock::new();
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct DaemonConfig {
+    pub api: ApiSettings,
+    pub device: DeviceSettings,
+    pub poll: PollSettings,
+    pub localstore: LocalstoreSettings,
+    pub log: LogSettings,
+    pub executor: ExecutorSettings,
+    pub shutdown: ShutdownSettings,
+    pub transport: TransportSettings,
+    pub security: SecuritySettings,
+    pub policy: PolicySettings,
+    pub sandbox: SandboxSettings,
+    pub output: OutputSettings,
+    pub result: ResultSettings,
+    pub outbox: OutboxSettings,
+    pub journal: JournalSettings,
+    pub retry: RetrySettings,
+    pub http: HttpSettings,
+    pub identity: IdentitySettings,
+    pub update: UpdateSettings,
+    pub heartbeat: HeartbeatSettings,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiSettings {
+    pub scheme: String,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+impl Default for ApiSettings {
+    fn default() -> Self {
+        ApiSettings {
+            scheme: "http".to_string(),
+            host: "127.0.0.1".to_string(),
+            port: Some(5001),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceSettings {
+    pub name: String,
+    /// how often the version and capabilities are sent again after startup
+    pub advertise_secs: u64,
+}
+
+impl Default for DeviceSettings {
+    fn default() -> Self {
+        let name = sys_info::hostname().unwrap_or_else(|_| "itx-device".to_string());
+        DeviceSettings {
+            name,
+            advertise_secs: 3600,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PollSettings {
+    pub short_secs: u64,
+    pub medium_secs: u64,
+}
+
+impl Default for PollSettings {
+    fn default() -> Self {
+        PollSettings {
+            short_secs: 1,
+            medium_secs: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalstoreSettings {
+    pub path: String,
+}
+
+impl Default for LocalstoreSettings {
+    fn default() -> Self {
+        LocalstoreSettings {
+            path: "localstore.json".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSettings {
+    pub level: String,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            level: "info".to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExecutorSettings {
+    /// applied to commands that carry no `timeout_secs` of their own; `None` waits forever
+    pub default_timeout_secs: Option<u64>,
+    /// time between SIGTERM and SIGKILL once a command times out
+    pub kill_grace_secs: u64,
+    /// commands executing at once across all command names
+    pub max_concurrent: usize,
+    /// extra limits keyed by command name, e.g. `{ Update = 1 }`
+    pub per_command_limits: HashMap<String, usize>,
+}
+
+impl Default for ExecutorSettings {
+    fn default() -> Self {
+        ExecutorSettings {
+            default_timeout_secs: Some(3600),
+            kill_grace_secs: 5,
+            max_concurrent: 4,
+            per_command_limits: HashMap::from([("Update".to_string(), 1)]),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownSettings {
+    /// how long in-flight commands may keep running after SIGINT/SIGTERM
+    pub drain_timeout_secs: u64,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        ShutdownSettings {
+            drain_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportSettings {
+    /// receive pushed commands over a websocket; http polling is used while it is down
+    pub websocket: bool,
+    pub websocket_path: String,
+    /// delay between websocket connection attempts
+    pub reconnect_secs: u64,
+}
+
+impl Default for TransportSettings {
+    fn default() -> Self {
+        TransportSettings {
+            websocket: false,
+            websocket_path: "/commands/ws".to_string(),
+            reconnect_secs: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecuritySettings {
+    /// base64 ed25519 key the server signs commands with; required unless
+    /// `allow_unsigned_commands` is set
+    pub command_public_key: Option<String>,
+    /// run commands unverified when no `command_public_key` is set
+    pub allow_unsigned_commands: bool,
+    /// the secret the server issues at registration to sign requests and its replies with;
+    /// unset keeps it in `signing_secret` next to the localstore
+    pub signing_secret_path: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicySettings {
+    /// toml file of allow/deny rules, see `policy::PolicyEngine`; unset allows everything
+    pub path: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SandboxSettings {
+    /// sandbox every shell command; otherwise only those sent with `sandbox: true`
+    pub enabled: bool,
+    /// should belong to the sandbox alone, since `processes` counts every process of the uid
+    pub uid: u32,
+    pub gid: u32,
+    /// run without any network access (a fresh network namespace)
+    pub isolate_network: bool,
+    /// size of the private /tmp
+    pub tmp_size_mb: u64,
+    pub cpu_secs: Option<u64>,
+    pub address_space_mb: Option<u64>,
+    pub open_files: Option<u64>,
+    /// RLIMIT_NPROC, which the kernel counts per uid across the whole host
+    pub processes: Option<u64>,
+    /// daemon environment variables passed through to sandboxed commands
+    pub keep_env: Vec<String>,
+}
+
+impl Default for SandboxSettings {
+    fn default() -> Self {
+        SandboxSettings {
+            enabled: false,
+            uid: 65534,
+            gid: 65534,
+            isolate_network: true,
+            tmp_size_mb: 64,
+            cpu_secs: None,
+            address_space_mb: None,
+            open_files: Some(256),
+            processes: Some(64),
+            keep_env: vec!["LANG".to_string()],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputSettings {
+    /// upload stdout/stderr while a command runs, not only in its final result
+    pub stream: bool,
+    /// how long output is buffered before a batch is uploaded
+    pub flush_millis: u64,
+    /// a batch is uploaded early once it holds this much output
+    pub max_batch_bytes: usize,
+}
+
+impl Default for OutputSettings {
+    fn default() -> Self {
+        OutputSettings {
+            stream: true,
+            flush_millis: 1000,
+            max_batch_bytes: 65536,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResultSettings {
+    /// stdout and stderr are each cut to this size (plus a truncation marker) for the upload
+    pub max_output_bytes: usize,
+    /// result uploads at least this large are gzipped; unset never compresses
+    pub gzip_min_bytes: Option<usize>,
+}
+
+impl Default for ResultSettings {
+    fn default() -> Self {
+        ResultSettings {
+            max_output_bytes: 1024 * 1024,
+            gzip_min_bytes: Some(4096),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboxSettings {
+    /// queued status updates and results; unset keeps them in `outbox.json` next to the
+    /// localstore
+    pub path: Option<String>,
+    /// first retry delay, doubled after every failed delivery
+    pub retry_base_millis: u64,
+    pub retry_max_secs: u64,
+    /// how long shutdown waits for the outbox to empty; the rest is sent after a restart
+    pub flush_secs: u64,
+}
+
+impl Default for OutboxSettings {
+    fn default() -> Self {
+        OutboxSettings {
+            path: None,
+            retry_base_millis: 1000,
+            retry_max_secs: 300,
+            flush_secs: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct JournalSettings {
+    /// commands taken on and their last state; unset keeps them in `journal.json` next to the
+    /// localstore
+    pub path: Option<String>,
+    /// how long finished commands are remembered, so a resent one is not run again
+    pub retain_days: u64,
+}
+
+impl Default for JournalSettings {
+    fn default() -> Self {
+        JournalSettings {
+            path: None,
+            retain_days: 7,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetrySettings {
+    /// ceiling of the first retry delay after a failed api call, doubled on every further
+    /// failure; the actual delay is random below the ceiling
+    pub base_millis: u64,
+    /// largest delay, also the most a server `Retry-After` is honoured for
+    pub max_secs: u64,
+    /// consecutive failures after which api calls stop being made; unset never stops
+    pub breaker_failures: Option<u32>,
+    /// how long calls are refused once the breaker opened, before one is let through again
+    pub breaker_open_secs: u64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        RetrySettings {
+            base_millis: 1000,
+            max_secs: 60,
+            breaker_failures: Some(5),
+            breaker_open_secs: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpSettings {
+    pub connect_timeout_secs: u64,
+    /// whole request, response body included; `None` waits forever
+    pub request_timeout_secs: Option<u64>,
+    /// how long an idle pooled connection is kept for reuse
+    pub pool_idle_secs: u64,
+    pub tcp_keepalive_secs: Option<u64>,
+    /// offer http/2 over tls (alpn); otherwise only http/1.1 is spoken
+    pub http2: bool,
+    /// speak http/2 straight away, for servers known to support it (also over plain http)
+    pub http2_prior_knowledge: bool,
+    /// http://, https://, socks5:// or socks5h:// proxy for every api call
+    pub proxy: Option<String>,
+    /// honour HTTP_PROXY, HTTPS_PROXY, ALL_PROXY and NO_PROXY (NO_PROXY also applies to `proxy`)
+    pub env_proxy: bool,
+    /// pem file of extra ca certificates to trust, on top of the system ones
+    pub ca_bundle: Option<String>,
+    /// base64 sha256 of the control-plane's public key (spki); when set, a server presenting
+    /// any other key is rejected
+    pub pinned_keys: Vec<String>,
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings {
+            connect_timeout_secs: 10,
+            request_timeout_secs: Some(60),
+            pool_idle_secs: 90,
+            tcp_keepalive_secs: Some(60),
+            http2: true,
+            http2_prior_knowledge: false,
+            proxy: None,
+            env_proxy: true,
+            ca_bundle: None,
+            pinned_keys: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentitySettings {
+    /// authenticate with a client certificate the control plane issues at registration
+    pub client_certificate: bool,
+    /// the device's private key, created on first run; unset keeps it in `device_key.pem`
+    /// next to the localstore
+    pub key_path: Option<String>,
+    /// how long before it expires the certificate is renewed
+    pub renew_before_days: u64,
+}
+
+impl Default for IdentitySettings {
+    fn default() -> Self {
+        IdentitySettings {
+            client_certificate: false,
+            key_path: None,
+            renew_before_days: 14,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpdateSettings {
+    /// base64 ed25519 key the releases are signed with; unset refuses `Update` commands
+    pub public_key: Option<String>,
+    /// the daemon binary to replace; unset replaces the running executable
+    pub binary_path: Option<String>,
+    /// how long a new version has to reach the server before the previous one is restored
+    pub health_deadline_secs: u64,
+}
+
+impl Default for UpdateSettings {
+    fn default() -> Self {
+        UpdateSettings {
+            public_key: None,
+            binary_path: None,
+            health_deadline_secs: 300,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatSettings {
+    /// post health metrics to the server, independent of the command loop
+    pub enabled: bool,
+    pub interval_secs: u64,
+}
+
+impl Default for HeartbeatSettings {
+    fn default() -> Self {
+        HeartbeatSettings {
+            enabled: true,
+            interval_secs: 60,
+        }
+    }
+}
+
+impl DaemonConfig {
+    pub fn from_toml_str(data: &str) -> Result<Self, HandlerError> {
+        toml::from_str(data).map_err(|e| HandlerError::ConfigError(e.to_string()))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, HandlerError> {
+        let data = std::fs::read_to_string(path).map_err(|e| {
+            HandlerError::ConfigError(format!("cannot read {}: {}", path.display(), e))
+        })?;
+        Self::from_toml_str(&data)
+    }
+
+    /// apply a single `<section>.<key>` override from env or cli
+    pub fn set_key(&mut self, key: &str, value: &str) -> Result<(), HandlerError> {
+        match key {
+            "api.scheme" => self.api.scheme = value.to_string(),
+            "api.host" => self.api.host = value.to_string(),
+            "api.port" => self.api.port = parse_optional_value(key, value)?,
+            "device.name" => self.device.name = value.to_string(),
+            "device.advertise_secs" => self.device.advertise_secs = parse_value(key, value)?,
+            "poll.short_secs" => self.poll.short_secs = parse_value(key, value)?,
+            "poll.medium_secs" => self.poll.medium_secs = parse_value(key, value)?,
+            "localstore.path" => self.localstore.path = value.to_string(),
+            "log.level" => self.log.level = value.to_string(),
+            "executor.default_timeout_secs" => {
+                self.executor.default_timeout_secs = parse_optional_value(key, value)?
+            }
+            "executor.kill_grace_secs" => self.executor.kill_grace_secs = parse_value(key, value)?,
+            "executor.max_concurrent" => self.executor.max_concurrent = parse_value(key, value)?,
+            "executor.per_command_limits" => {
+                self.executor.per_command_limits = parse_map_value(key, value)?
+            }
+            "shutdown.drain_timeout_secs" => {
+                self.shutdown.drain_timeout_secs = parse_value(key, value)?
+            }
+            "transport.websocket" => self.transport.websocket = parse_value(key, value)?,
+            "transport.websocket_path" => self.transport.websocket_path = value.to_string(),
+            "transport.reconnect_secs" => self.transport.reconnect_secs = parse_value(key, value)?,
+            "security.command_public_key" => {
+                self.security.command_public_key = parse_optional_value(key, value)?
+            }
+            "security.allow_unsigned_commands" => {
+                self.security.allow_unsigned_commands = parse_value(key, value)?
+            }
+            "security.signing_secret_path" => {
+                self.security.signing_secret_path = parse_optional_value(key, value)?
+            }
+            "policy.path" => self.policy.path = parse_optional_value(key, value)?,
+            "sandbox.enabled" => self.sandbox.enabled = parse_value(key, value)?,
+            "sandbox.uid" => self.sandbox.uid = parse_value(key, value)?,
+            "sandbox.gid" => self.sandbox.gid = parse_value(key, value)?,
+            "sandbox.isolate_network" => self.sandbox.isolate_network = parse_value(key, value)?,
+            "sandbox.tmp_size_mb" => self.sandbox.tmp_size_mb = parse_value(key, value)?,
+            "sandbox.cpu_secs" => self.sandbox.cpu_secs = parse_optional_value(key, value)?,
+            "sandbox.address_space_mb" => {
+                self.sandbox.address_space_mb = parse_optional_value(key, value)?
+            }
+            "sandbox.open_files" => self.sandbox.open_files = parse_optional_value(key, value)?,
+            "sandbox.processes" => self.sandbox.processes = parse_optional_value(key, value)?,
+            "sandbox.keep_env" => self.sandbox.keep_env = parse_list_value(value),
+            "output.stream" => self.output.stream = parse_value(key, value)?,
+            "output.flush_millis" => self.output.flush_millis = parse_value(key, value)?,
+            "output.max_batch_bytes" => self.output.max_batch_bytes = parse_value(key, value)?,
+            "result.max_output_bytes" => self.result.max_output_bytes = parse_value(key, value)?,
+            "result.gzip_min_bytes" => {
+                self.result.gzip_min_bytes = parse_optional_value(key, value)?
+            }
+            "outbox.path" => self.outbox.path = parse_optional_value(key, value)?,
+            "outbox.retry_base_millis" => self.outbox.retry_base_millis = parse_value(key, value)?,
+            "outbox.retry_max_secs" => self.outbox.retry_max_secs = parse_value(key, value)?,
+            "outbox.flush_secs" => self.outbox.flush_secs = parse_value(key, value)?,
+            "journal.path" => self.journal.path = parse_optional_value(key, value)?,
+            "journal.retain_days" => self.journal.retain_days = parse_value(key, value)?,
+            "retry.base_millis" => self.retry.base_millis = parse_value(key, value)?,
+            "retry.max_secs" => self.retry.max_secs = parse_value(key, value)?,
+            "retry.breaker_failures" => {
+                self.retry.breaker_failures = parse_optional_value(key, value)?
+            }
+            "retry.breaker_open_secs" => self.retry.breaker_open_secs = parse_value(key, value)?,
+            "http.connect_timeout_secs" => {
+                self.http.connect_timeout_secs = parse_value(key, value)?
+            }
+            "http.request_timeout_secs" => {
+                self.http.request_timeout_secs = parse_optional_value(key, value)?
+            }
+            "http.pool_idle_secs" => self.http.pool_idle_secs = parse_value(key, value)?,
+            "http.tcp_keepalive_secs" => {
+                self.http.tcp_keepalive_secs = parse_optional_value(key, value)?
+            }
+            "http.http2" => self.http.http2 = parse_value(key, value)?,
+            "http.http2_prior_knowledge" => {
+                self.http.http2_prior_knowledge = parse_value(key, value)?
+            }
+            "http.proxy" => self.http.proxy = parse_optional_value(key, value)?,
+            "http.env_proxy" => self.http.env_proxy = parse_value(key, value)?,
+            "http.ca_bundle" => self.http.ca_bundle = parse_optional_value(key, value)?,
+            "http.pinned_keys" => self.http.pinned_keys = parse_list_value(value),
+            "identity.client_certificate" => {
+                self.identity.client_certificate = parse_value(key, value)?
+            }
+            "identity.key_path" => self.identity.key_path = parse_optional_value(key, value)?,
+            "identity.renew_before_days" => {
+                self.identity.renew_before_days = parse_value(key, value)?
+            }
+            "update.public_key" => self.update.public_key = parse_optional_value(key, value)?,
+            "update.binary_path" => self.update.binary_path = parse_optional_value(key, value)?,
+            "update.health_deadline_secs" => {
+                self.update.health_deadline_secs = parse_value(key, value)?
+            }
+            "heartbeat.enabled" => self.heartbeat.enabled = parse_value(key, value)?,
+            "heartbeat.interval_secs" => self.heartbeat.interval_secs = parse_value(key, value)?,
+            _ => {
+                return Err(HandlerError::ConfigError(format!(
+                    "unknown config key: {}",
+                    key
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<(), HandlerError> {
+        let mut errors = vec![];
+        if !matches!(self.api.scheme.as_str(), "http" | "https") {
+            errors.push(format!(
+                "api.scheme must be http or https, got {:?}",
+                self.api.scheme
+            ));
+        }
+        if self.api.host.is_empty() || self.api.host.contains("://") {
+            errors.push(format!(
+                "api.host must be a bare hostname, got {:?}",
+                self.api.host
+            ));
+        }
+        if self.api.port == Some(0) {
+            errors.push("api.port must not be 0".to_string());
+        }
+        if self.device.name.trim().is_empty() {
+            errors.push("device.name must not be empty".to_string());
+        }
+        for (key, val) in [
+            ("device.advertise_secs", self.device.advertise_secs),
+            ("poll.short_secs", self.poll.short_secs),
+            ("poll.medium_secs", self.poll.medium_secs),
+        ] {
+            if val == 0 {
+                errors.push(format!("{} must be greater than 0", key));
+            }
+        }
+        if self.localstore.path.is_empty() {
+            errors.push("localstore.path must not be empty".to_string());
+        }
+        if self.executor.default_timeout_secs == Some(0) {
+            errors.push("executor.default_timeout_secs must not be 0".to_string());
+        }
+        if self.executor.max_concurrent == 0 {
+            errors.push("executor.max_concurrent must be greater than 0".to_string());
+        }
+        for (name, limit) in &self.executor.per_command_limits {
+            if *limit == 0 {
+                errors.push(format!(
+                    "executor.per_command_limits.{} must be greater than 0",
+                    name
+                ));
+            }
+        }
+        if !self.transport.websocket_path.starts_with('/') {
+            errors.push("transport.websocket_path must start with /".to_string());
+        }
+        if self.transport.reconnect_secs == 0 {
+            errors.push("transport.reconnect_secs must be greater than 0".to_string());
+        }
+        if self.security.command_public_key.is_none() && !self.security.allow_unsigned_commands {
+            errors.push(
+                "security.command_public_key must be set (or security.allow_unsigned_commands)"
+                    .to_string(),
+            );
+        }
+        let public_keys = [
+            (
+                "security.command_public_key",
+                &self.security.command_public_key,
+            ),
+            ("update.public_key", &self.update.public_key),
+        ];
+        for (setting, public_key) in public_keys {
+            if let Some(public_key) = public_key {
+                if let Err(HandlerError::ConfigError(e)) = parse_public_key(setting, public_key) {
+                    errors.push(e);
+                }
+            }
+        }
+        if self.update.health_deadline_secs == 0 {
+            errors.push("update.health_deadline_secs must be greater than 0".to_string());
+        }
+        if self.sandbox.uid == 0 {
+            errors.push("sandbox.uid must not be root".to_string());
+        }
+        if self.sandbox.tmp_size_mb == 0 {
+            errors.push("sandbox.tmp_size_mb must be greater than 0".to_string());
+        }
+        for (key, val) in [
+            ("sandbox.cpu_secs", self.sandbox.cpu_secs),
+            ("sandbox.address_space_mb", self.sandbox.address_space_mb),
+            ("sandbox.open_files", self.sandbox.open_files),
+            ("sandbox.processes", self.sandbox.processes),
+        ] {
+            if val == Some(0) {
+                errors.push(format!("{} must not be 0", key));
+            }
+        }
+        if self.output.flush_millis == 0 {
+            errors.push("output.flush_millis must be greater than 0".to_string());
+        }
+        if self.output.max_batch_bytes == 0 {
+            errors.push("output.max_batch_bytes must be greater than 0".to_string());
+        }
+        if self.result.max_output_bytes == 0 {
+            errors.push("result.max_output_bytes must be greater than 0".to_string());
+        }
+        if self.outbox.retry_base_millis == 0 {
+            errors.push("outbox.retry_base_millis must be greater than 0".to_string());
+        }
+        if self.outbox.retry_max_secs.saturating_mul(1000) < self.outbox.retry_base_millis {
+            errors.push(
+                "outbox.retry_max_secs must not be below outbox.retry_base_millis".to_string(),
+            );
+        }
+        if self.retry.base_millis == 0 {
+            errors.push("retry.base_millis must be greater than 0".to_string());
+        }
+        if self.retry.max_secs.saturating_mul(1000) < self.retry.base_millis {
+            errors.push("retry.max_secs must not be below retry.base_millis".to_string());
+        }
+        if self.retry.breaker_failures == Some(0) {
+            errors.push("retry.breaker_failures must be greater than 0".to_string());
+        }
+        if self.http.connect_timeout_secs == 0 || self.http.request_timeout_secs == Some(0) {
+            errors.push("http timeouts must be greater than 0".to_string());
+        }
+        if self.http.http2_prior_knowledge && !self.http.http2 {
+            errors.push("http.http2_prior_knowledge needs http.http2".to_string());
+        }
+        if let Some(proxy) = &self.http.proxy {
+            if reqwest::Proxy::all(proxy).is_err() {
+                errors.push(format!("http.proxy is not a valid proxy url: {:?}", proxy));
+            }
+        }
+        for pin in &self.http.pinned_keys {
+            if let Err(HandlerError::ConfigError(e)) = parse_pin(pin) {
+                errors.push(e);
+            }
+        }
+        if self.identity.client_certificate && self.identity.renew_before_days == 0 {
+            errors.push("identity.renew_before_days must be greater than 0".to_string());
+        }
+        if self.heartbeat.enabled && self.heartbeat.interval_secs == 0 {
+            errors.push("heartbeat.interval_secs must be greater than 0".to_string());
+        }
+        if self.journal.retain_days == 0 {
+            errors.push("journal.retain_days must be greater than 0".to_string());
+        }
+        if self.log.level.parse::<LevelFilter>().is_err() {
+            errors.push(format!(
+                "log.level is not a log level: {:?}",
+                self.log.level
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(HandlerError::ConfigError(errors.join("; ")))
+        }
+    }
+
+    pub fn outbox_path(&self) -> PathBuf {
+        self.beside_localstore(&self.outbox.path, "outbox.json")
+    }
+
+    pub fn journal_path(&self) -> PathBuf {
+        self.beside_localstore(&self.journal.path, "journal.json")
+    }
+
+    /// the self-update in progress, see `updater`
+    pub fn update_state_path(&self) -> PathBuf {
+        self.beside_localstore(&None, "update.json")
+    }
+
+    pub fn identity_key_path(&self) -> PathBuf {
+        self.beside_localstore(&self.identity.key_path, "device_key.pem")
+    }
+
+    pub fn signing_secret_path(&self) -> PathBuf {
+        self.beside_localstore(&self.security.signing_secret_path, "signing_secret")
+    }
+
+    fn beside_localstore(&self, path: &Option<String>, file_name: &str) -> PathBuf {
+        match path {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(&self.localstore.path).with_file_name(file_name),
+        }
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        self.log.level.parse().unwrap_or(LevelFilter::Info)
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, HandlerError> {
+    value
+        .parse()
+        .map_err(|_| HandlerError::ConfigError(format!("invalid value for {}: {:?}", key, value)))
+}
+
+/// `none` or an empty value clears an optional setting
+fn parse_optional_value<T: std::str::FromStr>(
+    key: &str,
+    value: &str,
+) -> Result<Option<T>, HandlerError> {
+    if value.is_empty() || value == "none" {
+        Ok(None)
+    } else {
+        Ok(Some(parse_value(key, value)?))
+    }
+}
+
+/// maps are written as `name=value,name=value` outside of the config file
+fn parse_map_value<T: std::str::FromStr>(
+    key: &str,
+    value: &str,
+) -> Result<HashMap<String, T>, HandlerError> {
+    value
+        .split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| match entry.split_once('=') {
+            Some((name, val)) => Ok((name.trim().to_string(), parse_value(key, val.trim())?)),
+            None => Err(HandlerError::ConfigError(format!(
+                "invalid entry for {}: {:?}",
+                key, entry
+            ))),
+        })
+        .collect()
+}
+
+/// lists are written as `a,b,c` outside of the config file
+fn parse_list_value(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|entry| entry.trim().to_string())
+        .filter(|entry| !entry.is_empty())
+        .collect()
+}
+
+pub fn env_var_for_key(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase())
+}
+
+pub fn flag_for_key(key: &str) -> String {
+    format!("--{}", key.replace(['.', '_'], "-"))
+}
+
+fn parse_cli_flags(args: &[String]) -> Result<HashMap<String, String>, HandlerError> {
+    let mut flags = HashMap::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg.clone(), None),
+        };
+        if !flag.starts_with("--") {
+            return Err(HandlerError::ConfigError(format!(
+                "unexpected argument: {}",
+                arg
+            )));
+        }
+        let value = match inline_value {
+            Some(value) => value,
+            None => iter
+                .next()
+                .cloned()
+                .ok_or_else(|| HandlerError::ConfigError(format!("missing value for {}", flag)))?,
+        };
+        flags.insert(flag, value);
+    }
+    Ok(flags)
+}
+
+/// resolve config from the given cli args (without argv[0]) and environment
+pub fn load_from(
+    args: &[String],
+    env: &HashMap<String, String>,
+) -> Result<DaemonConfig, HandlerError> {
+    let mut flags = parse_cli_flags(args)?;
+
+    let explicit_path = flags
+        .remove(CONFIG_FLAG)
+        .or_else(|| env.get(CONFIG_ENV_VAR).cloned());
+    let mut config = match explicit_path {
+        Some(path) => {
+            info!("loading config file from {}", &path);
+            DaemonConfig::from_file(Path::new(&path))?
+        }
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+            info!("loading config file from {}", DEFAULT_CONFIG_PATH);
+            DaemonConfig::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+        }
+        None => DaemonConfig::default(),
+    };
+
+    for key in CONFIG_KEYS {
+        if let Some(value) = env.get(&env_var_for_key(key)) {
+            config.set_key(key, value)?;
+        }
+    }
+
+    for key in CONFIG_KEYS {
+        if let Some(value) = flags.remove(&flag_for_key(key)) {
+            config.set_key(key, &value)?;
+        }
+    }
+    if let Some(flag) = flags.keys().next() {
+        return Err(HandlerError::ConfigError(format!("unknown flag: {}", flag)));
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+pub fn load() -> Result<DaemonConfig, HandlerError> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let env: HashMap<String, String> = std::env::vars().collect();
+    load_from(&args, &env)
+}
+
+/// install the resolved config for the rest of the process; only the first call wins
+pub fn init(config: DaemonConfig) -> &'static DaemonConfig {
+    CONFIG.get_or_init(|| config)
+}
+
+pub fn get_config() -> &'static DaemonConfig {
+    CONFIG.get_or_init(DaemonConfig::default)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::io::Write as _;
+
+    use tempdir::TempDir;
+
+    use crate::config::{env_var_for_key, flag_for_key, load_from, DaemonConfig};
+    use crate::models::HandlerError;
+    use crate::verification::test_keys;
+
+    fn to_args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// the tests below load configs without a command key
+    fn allow_unsigned() -> HashMap<String, String> {
+        HashMap::from([(
+            "ITX_SECURITY_ALLOW_UNSIGNED_COMMANDS".to_string(),
+            "true".to_string(),
+        )])
+    }
+
+    fn write_config_file(dir: &TempDir, data: &str) -> String {
+        let path = dir.path().join("daemon.toml");
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(data.as_bytes())
+            .unwrap();
+        path.display().to_string()
+    }
+
+    #[test]
+    fn test_key_names() {
+        assert_eq!(env_var_for_key("poll.short_secs"), "ITX_POLL_SHORT_SECS");
+        assert_eq!(flag_for_key("poll.short_secs"), "--poll-short-secs");
+    }
+
+    #[test]
+    fn test_default_config_is_valid() {
+        let mut config = DaemonConfig::default();
+        config.security.allow_unsigned_commands = true;
+        assert!(config.validate().is_ok());
+        assert_eq!(config.api.port, Some(5001));
+    }
+
+    #[test]
+    fn test_example_config_parses() {
+        let mut config =
+            DaemonConfig::from_toml_str(include_str!("../daemon.example.toml")).unwrap();
+        // the example leaves the server's key for the operator to fill in
+        assert!(config.validate().is_err());
+        config.security.command_public_key = Some(test_keys::generate().1);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_command_public_key_is_required() {
+        let result = load_from(&[], &HashMap::new());
+        match result {
+            Err(HandlerError::ConfigError(msg)) => {
+                assert!(msg.contains("security.command_public_key"))
+            }
+            other => panic!("expected config error, got {:?}", other),
+        }
+
+        let env = allow_unsigned();
+        assert!(load_from(&[], &env).is_ok());
+    }
+
+    #[test]
+    fn test_file_overrides_defaults() {
+        let dir = TempDir::new("test-config").unwrap();
+        let path = write_config_file(
+            &dir,
+            r#"
+            [api]
+            scheme = "https"
+            host = "api.itx-app.com"
+            port = 443
+
+            [device]
+            name = "filedevice"
+
+            [security]
+            allow_unsigned_commands = true
+            "#,
+        );
+
+        let config = load_from(&to_args(&["--config", &path]), &HashMap::new()).unwrap();
+        assert_eq!(config.api.scheme, "https");
+        assert_eq!(config.api.host, "api.itx-app.com");
+        assert_eq!(config.device.name, "filedevice");
+        assert_eq!(config.poll.medium_secs, 5);
+    }
+
+    #[test]
+    fn test_precedence_cli_over_env_over_file() {
+        let dir = TempDir::new("test-config").unwrap();
+        let path = write_config_file(&dir, "[device]\nname = \"filedevice\"\n");
+
+        let mut env = allow_unsigned();
+        env.extend([
+            ("ITX_CONFIG".to_string(), path),
+            ("ITX_DEVICE_NAME".to_string(), "envdevice".to_string()),
+            ("ITX_POLL_SHORT_SECS".to_string(), "3".to_string()),
+        ]);
+
+        let config = load_from(&to_args(&["--poll-short-secs=7"]), &env).unwrap();
+        assert_eq!(config.device.name, "envdevice");
+        assert_eq!(config.poll.short_secs, 7);
+
+        let config = load_from(&to_args(&["--device-name", "clidevice"]), &env).unwrap();
+        assert_eq!(config.device.name, "clidevice");
+        assert_eq!(config.poll.short_secs, 3);
+    }
+
+    #[test]
+    fn test_map_override() {
+        let mut env = allow_unsigned();
+        env.insert(
+            "ITX_EXECUTOR_PER_COMMAND_LIMITS".to_string(),
+            "Update=1, ShellCmd=2".to_string(),
+        );
+
+        let config = load_from(&[], &env).unwrap();
+        assert_eq!(config.executor.per_command_limits["Update"], 1);
+        assert_eq!(config.executor.per_command_limits["ShellCmd"], 2);
+
+        let result = load_from(&to_args(&["--executor-per-command-limits", "Update"]), &env);
+        assert!(matches!(result, Err(HandlerError::ConfigError(_))));
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let args = to_args(&["--api-scheme", "ftp", "--poll-medium-secs", "0"]);
+        let result = load_from(&args, &HashMap::new());
+        match result {
+            Err(HandlerError::ConfigError(msg)) => {
+                assert!(msg.contains("api.scheme"));
+                assert!(msg.contains("poll.medium_secs"));
+            }
+            other => panic!("expected config error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_unknown_flag_and_key_fail() {
+        let result = load_from(&to_args(&["--nope", "1"]), &HashMap::new());
+        assert!(matches!(result, Err(HandlerError::ConfigError(_))));
+
+        let result = DaemonConfig::from_toml_str("[api]\nnope = 1\n");
+        assert!(matches!(result, Err(HandlerError::ConfigError(_))));
+    }
+}
@@ -1,44 +1,683 @@
-use log::info;
+use std::os::unix::process::{CommandExt as _, ExitStatusExt as _};
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::models::db::commands::{Command, CommandNames};
+use log::{info, warn};
+use tokio::io::{AsyncRead, AsyncReadExt as _};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::config::{DaemonConfig, ExecutorSettings, ResultSettings, SandboxSettings};
+use crate::handlers::{HandlerContext, HandlerRegistry};
+use crate::models::db::commands::Command;
+use crate::models::db::common::Id;
+use crate::models::db::output::{OutputChunk, OutputStream};
+use crate::models::db::results::ExecutionResult;
 use crate::models::HandlerError;
+use crate::policy::{Decision, PolicyEngine};
+use crate::sandbox;
+use crate::updater::Updater;
+use crate::verification::CommandVerifier;
+
+/// bytes read from a command's pipe at a time
+const READ_BUF_SIZE: usize = 8192;
+
+/// everything the executor checks and applies around a command, shared by all workers
+#[derive(Default)]
+pub struct ExecutorContext {
+    pub settings: ExecutorSettings,
+    pub verifier: CommandVerifier,
+    pub policy: PolicyEngine,
+    pub sandbox: SandboxSettings,
+    /// caps the output kept for the result
+    pub result: ResultSettings,
+    /// what runs each command, by name
+    pub handlers: HandlerRegistry,
+    /// the updater behind the `Update` handler, if registered
+    pub updater: Option<Arc<Updater>>,
+}
+
+impl ExecutorContext {
+    pub fn new(config: &DaemonConfig, device_id: &Id) -> Result<Self, HandlerError> {
+        Ok(ExecutorContext {
+            settings: config.executor.clone(),
+            verifier: CommandVerifier::new(&config.security, device_id)?,
+            policy: PolicyEngine::load(config.policy.path.as_deref())?,
+            sandbox: config.sandbox.clone(),
+            result: config.result.clone(),
+            handlers: HandlerRegistry::builtin(),
+            updater: None,
+        })
+    }
+
+    /// runs commands with `handlers`, see `HandlerRegistry::with_updater` for `updater`'s
+    pub fn with_handlers(self, handlers: HandlerRegistry, updater: Option<Arc<Updater>>) -> Self {
+        ExecutorContext {
+            handlers,
+            updater,
+            ..self
+        }
+    }
+}
+
+/// resolves once a command should be stopped, i.e. the server cancelled it
+#[derive(Clone)]
+pub struct CancelSignal {
+    rx: watch::Receiver<bool>,
+}
+
+impl CancelSignal {
+    pub fn channel() -> (watch::Sender<bool>, Self) {
+        let (tx, rx) = watch::channel(false);
+        (tx, CancelSignal { rx })
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// pending forever if the sender is dropped without cancelling
+    pub async fn cancelled(&self) {
+        let mut rx = self.rx.clone();
+        if rx.wait_for(|cancelled| *cancelled).await.is_err() {
+            futures::future::pending::<()>().await;
+        }
+    }
+}
 
 pub async fn handoff_command_to_executor(
     command: &Command,
-) -> Result<Option<String>, HandlerError> {
+    context: &ExecutorContext,
+) -> Result<ExecutionResult, HandlerError> {
+    handoff_command_streaming(command, context, None, None).await
+}
+
+/**
+ * runs a command, with the handler registered for its name, once it passes both gates:
+ * 1. the server signature (`verification`), failing with `InvalidSignature`
+ * 2. the local policy (`policy`), failing with `PolicyDenied(rule id)`; an allowing rule may
+ *    also cap the runtime
+ *
+ * with `output` set, shell output is also sent there as it is read, one `OutputChunk` per
+ * read, numbered across stdout and stderr. the result keeps up to `result.max_output_bytes`
+ * of each and counts the rest.
+ * once `cancel` fires a running shell is terminated like on timeout and the result is marked
+ * cancelled.
+ */
+pub async fn handoff_command_streaming(
+    command: &Command,
+    context: &ExecutorContext,
+    output: Option<UnboundedSender<OutputChunk>>,
+    cancel: Option<CancelSignal>,
+) -> Result<ExecutionResult, HandlerError> {
     info!("handing off command to executor: {:?}", &command);
-    match &command.name {
-        CommandNames::Test => {
-            // TODO @felipearce: add test command here
-            Ok(Some("test".to_string()))
-        }
-        CommandNames::ShellCmd => {
-            // execute args in the shell
-            let args = match command.args.as_ref() {
-                Some(args) => args,
-                None => {
-                    return Err(HandlerError::ParseError(
-                        "no args found for shell cmd".to_string(),
-                    ));
-                }
-            };
+    context.verifier.verify(command)?;
+    let max_runtime_secs = match context.policy.evaluate(command) {
+        Decision::Deny { rule_id } => return Err(HandlerError::PolicyDenied(rule_id)),
+        Decision::Allow {
+            max_runtime_secs, ..
+        } => max_runtime_secs,
+    };
+
+    let handler = context
+        .handlers
+        .get(command.name.as_str())
+        .ok_or_else(|| HandlerError::UnsupportedCommand(command.name.as_str().to_string()))?;
+    let handler_context = HandlerContext {
+        settings: &context.settings,
+        sandbox: &context.sandbox,
+        max_output_bytes: context.result.max_output_bytes,
+        max_runtime_secs,
+        output,
+        cancel,
+    };
+    let started_at_ms = now_ms();
+    let mut result = handler.execute(command, handler_context).await?;
+
+    result.started_at_ms = started_at_ms;
+    result.ended_at_ms = now_ms();
+    result.duration_ms = result.ended_at_ms.saturating_sub(started_at_ms);
+    Ok(result)
+}
+
+/// when a running shell gets terminated
+pub struct Stop {
+    pub timeout: Option<Duration>,
+    /// between SIGTERM and SIGKILL
+    pub grace: Duration,
+    pub cancel: Option<CancelSignal>,
+}
+
+impl Stop {
+    async fn timed_out(&self) {
+        match self.timeout {
+            Some(timeout) => tokio::time::sleep(timeout).await,
+            None => futures::future::pending().await,
+        }
+    }
+
+    async fn cancelled(&self) {
+        match &self.cancel {
+            Some(cancel) => cancel.cancelled().await,
+            None => futures::future::pending().await,
+        }
+    }
+}
+
+/**
+ * runs `sh -c <args>` as the leader of a new process group so that on timeout or
+ * cancellation the whole tree can be signalled: SIGTERM first, then SIGKILL once the grace
+ * period has elapsed. with `sandbox` set the shell is confined as described in
+ * `sandbox::apply`. the first `max_output_bytes` of stdout and of stderr are kept, the rest
+ * is only counted (and still streamed to `output`).
+ */
+pub async fn run_shell(
+    args: &str,
+    stop: Stop,
+    sandbox: Option<&SandboxSettings>,
+    output: Option<UnboundedSender<OutputChunk>>,
+    max_output_bytes: usize,
+) -> Result<ExecutionResult, HandlerError> {
+    let mut std_command = std::process::Command::new("sh");
+    std_command
+        .arg("-c")
+        .arg(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0);
+    if let Some(settings) = sandbox {
+        sandbox::apply(&mut std_command, settings)?;
+    }
+    let mut child = tokio::process::Command::from(std_command)
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| HandlerError::CmdError(e.to_string()))?;
+
+    let pgid = child.id().map(|pid| pid as i32);
+    let sinks = output.map(|tx| {
+        let seq = Arc::new(AtomicU64::new(0));
+        (
+            OutputSink::new(OutputStream::Stdout, tx.clone(), seq.clone()),
+            OutputSink::new(OutputStream::Stderr, tx, seq),
+        )
+    });
+    let (stdout_sink, stderr_sink) = sinks.unzip();
+    let stdout_reader = spawn_reader(child.stdout.take(), stdout_sink, max_output_bytes);
+    let stderr_reader = spawn_reader(child.stderr.take(), stderr_sink, max_output_bytes);
+
+    let mut timed_out = false;
+    let mut cancelled = false;
+    let status = tokio::select! {
+        status = child.wait() => {
+            // whatever the leader left running in its group would hold the pipes open
+            signal_group(pgid, libc::SIGKILL);
+            status?
+        }
+        _ = stop.timed_out() => {
+            warn!("command timed out after {:?}, terminating", stop.timeout);
+            timed_out = true;
+            terminate_group(&mut child, pgid, stop.grace).await?
+        }
+        _ = stop.cancelled() => {
+            info!("command cancelled, terminating");
+            cancelled = true;
+            terminate_group(&mut child, pgid, stop.grace).await?
+        }
+    };
+
+    // a process that left the group (setsid) may still hold a pipe: give it the grace period
+    let deadline = tokio::time::Instant::now() + stop.grace;
+    let (stdout, stdout_dropped_bytes) = stdout_reader.join(deadline).await;
+    let (stderr, stderr_dropped_bytes) = stderr_reader.join(deadline).await;
+    Ok(ExecutionResult {
+        stdout: Some(stdout),
+        stderr: Some(stderr),
+        exit_code: status.code(),
+        signal: status.signal(),
+        timed_out,
+        cancelled,
+        stdout_dropped_bytes,
+        stderr_dropped_bytes,
+        ..Default::default()
+    })
+}
+
+async fn terminate_group(
+    child: &mut tokio::process::Child,
+    pgid: Option<i32>,
+    grace: Duration,
+) -> Result<ExitStatus, HandlerError> {
+    signal_group(pgid, libc::SIGTERM);
+    let status = match tokio::time::timeout(grace, child.wait()).await {
+        Ok(status) => status?,
+        Err(_) => {
+            warn!("command ignored SIGTERM for {:?}, killing", grace);
+            signal_group(pgid, libc::SIGKILL);
+            child.wait().await?
+        }
+    };
+    // the leader may have exited on SIGTERM while the rest of its group did not
+    signal_group(pgid, libc::SIGKILL);
+    Ok(status)
+}
+
+fn signal_group(pgid: Option<i32>, signal: i32) {
+    if let Some(pgid) = pgid {
+        // SAFETY: killpg has no memory safety preconditions
+        unsafe {
+            libc::killpg(pgid, signal);
+        }
+    }
+}
+
+/// forwards what one pipe produces as `OutputChunk`s, sharing the sequence with the other pipe
+struct OutputSink {
+    stream: OutputStream,
+    tx: UnboundedSender<OutputChunk>,
+    seq: Arc<AtomicU64>,
+    /// an incomplete utf-8 sequence at the end of the last read
+    pending: Vec<u8>,
+}
+
+impl OutputSink {
+    fn new(stream: OutputStream, tx: UnboundedSender<OutputChunk>, seq: Arc<AtomicU64>) -> Self {
+        OutputSink {
+            stream,
+            tx,
+            seq,
+            pending: vec![],
+        }
+    }
+
+    /// sends `bytes`, holding back a character split across reads until the next call
+    fn push(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+        let complete = match std::str::from_utf8(&self.pending) {
+            Ok(_) => self.pending.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => self.pending.len(),
+        };
+        let rest = self.pending.split_off(complete);
+        let data = std::mem::replace(&mut self.pending, rest);
+        self.send(&data);
+    }
+
+    fn finish(mut self) {
+        let data = std::mem::take(&mut self.pending);
+        self.send(&data);
+    }
+
+    fn send(&self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let chunk = OutputChunk {
+            stream: self.stream,
+            seq: self.seq.fetch_add(1, Ordering::SeqCst),
+            data: String::from_utf8_lossy(data).to_string(),
+            at_ms: now_ms(),
+        };
+        // the receiver going away only stops streaming; the result keeps its own copy
+        let _ = self.tx.send(chunk);
+    }
+}
+
+/// reads one pipe in the background; what it read so far is kept even if it is aborted
+struct Reader {
+    task: JoinHandle<()>,
+    captured: Arc<Mutex<Captured>>,
+}
+
+/// the first `max_bytes` a pipe produced and how many came after them
+struct Captured {
+    buf: Vec<u8>,
+    max_bytes: usize,
+    dropped: usize,
+}
 
-            let output_result = std::process::Command::new("sh")
-                .arg("-c")
-                .arg(args)
-                .output();
+impl Captured {
+    fn push(&mut self, bytes: &[u8]) {
+        let keep = bytes.len().min(self.max_bytes - self.buf.len());
+        self.buf.extend_from_slice(&bytes[..keep]);
+        self.dropped += bytes.len() - keep;
+    }
+}
 
-            match output_result {
-                Ok(output) => {
-                    let output_str = String::from_utf8(output.stdout).unwrap();
-                    Ok(Some(output_str))
+impl Reader {
+    /// the output read by `deadline` and the bytes dropped past the limit, if any; a pipe
+    /// still open then is abandoned
+    async fn join(mut self, deadline: tokio::time::Instant) -> (String, Option<usize>) {
+        if tokio::time::timeout_at(deadline, &mut self.task)
+            .await
+            .is_err()
+        {
+            warn!("output pipe still open after the command exited, abandoning it");
+            self.task.abort();
+        }
+        let mut captured = self.captured.lock().unwrap();
+        let buf = std::mem::take(&mut captured.buf);
+        let dropped = (captured.dropped > 0).then_some(captured.dropped);
+        (String::from_utf8_lossy(&buf).to_string(), dropped)
+    }
+}
+
+fn spawn_reader<R>(pipe: Option<R>, mut sink: Option<OutputSink>, max_bytes: usize) -> Reader
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let captured = Arc::new(Mutex::new(Captured {
+        buf: vec![],
+        max_bytes,
+        dropped: 0,
+    }));
+    let task = tokio::spawn({
+        let captured = captured.clone();
+        async move {
+            if let Some(mut pipe) = pipe {
+                let mut read_buf = [0; READ_BUF_SIZE];
+                loop {
+                    match pipe.read(&mut read_buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            captured.lock().unwrap().push(&read_buf[..n]);
+                            if let Some(sink) = sink.as_mut() {
+                                sink.push(&read_buf[..n]);
+                            }
+                        }
+                    }
                 }
-                Err(e) => Err(HandlerError::CmdError(e.to_string())),
+            }
+            if let Some(sink) = sink {
+                sink.finish();
             }
         }
-        _ => {
-            // TODO @felipearce: add more commands here
-            Ok(None)
+    });
+    Reader { task, captured }
+}
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use crate::{
+        config::{ExecutorSettings, ResultSettings, SecuritySettings},
+        executor::{CancelSignal, ExecutorContext},
+        models::{
+            db::{
+                commands::{Command, CommandNames, CommandStatus},
+                output::OutputStream,
+            },
+            HandlerError,
+        },
+        policy::PolicyEngine,
+        verification::{test_keys, CommandVerifier},
+    };
+
+    fn shell_command(args: &str) -> Command {
+        let mut command = Command::default();
+        command.name = CommandNames::ShellCmd;
+        command.args = Some(args.to_string());
+        command
+    }
+
+    #[tokio::test]
+    async fn test_shell_cmd_captures_output() {
+        let command = shell_command("echo out; echo err 1>&2");
+
+        let result = super::handoff_command_to_executor(&command, &ExecutorContext::default())
+            .await
+            .unwrap();
+
+        assert_eq!(result.stdout.as_deref(), Some("out\n"));
+        assert_eq!(result.stderr.as_deref(), Some("err\n"));
+        assert_eq!(result.exit_code, Some(0));
+        assert_eq!(result.signal, None);
+        assert!(result.ended_at_ms >= result.started_at_ms);
+        assert!(matches!(result.status(), CommandStatus::Terminated));
+    }
+
+    #[tokio::test]
+    async fn test_shell_cmd_nonzero_exit_is_failed() {
+        let command = shell_command("exit 3");
+
+        let result = super::handoff_command_to_executor(&command, &ExecutorContext::default())
+            .await
+            .unwrap();
+
+        assert_eq!(result.exit_code, Some(3));
+        assert!(matches!(result.status(), CommandStatus::Failed));
+    }
+
+    #[tokio::test]
+    async fn test_shell_cmd_signal_is_failed() {
+        let command = shell_command("kill -9 $$");
+
+        let result = super::handoff_command_to_executor(&command, &ExecutorContext::default())
+            .await
+            .unwrap();
+
+        assert_eq!(result.exit_code, None);
+        assert_eq!(result.signal, Some(9));
+        assert!(matches!(result.status(), CommandStatus::Failed));
+    }
+
+    #[tokio::test]
+    async fn test_shell_cmd_without_args_fails() {
+        let mut command = Command::default();
+        command.name = CommandNames::ShellCmd;
+
+        let result =
+            super::handoff_command_to_executor(&command, &ExecutorContext::default()).await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shell_cmd_times_out() {
+        let mut command = shell_command("echo started; sleep 30");
+        command.timeout_secs = Some(1);
+
+        let result = super::handoff_command_to_executor(&command, &ExecutorContext::default())
+            .await
+            .unwrap();
+
+        assert!(result.timed_out);
+        assert_eq!(result.stdout.as_deref(), Some("started\n"));
+        assert_eq!(result.signal, Some(libc::SIGTERM));
+        assert!(result.duration_ms < 10_000);
+        assert!(matches!(result.status(), CommandStatus::TimedOut));
+    }
+
+    #[tokio::test]
+    async fn test_shell_cmd_ignoring_sigterm_is_killed() {
+        let command = shell_command("trap '' TERM; sleep 30 & wait; sleep 30");
+        let context = ExecutorContext {
+            settings: ExecutorSettings {
+                default_timeout_secs: Some(1),
+                kill_grace_secs: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let result = super::handoff_command_to_executor(&command, &context)
+            .await
+            .unwrap();
+
+        assert!(result.timed_out);
+        assert_eq!(result.signal, Some(libc::SIGKILL));
+        assert!(result.duration_ms < 10_000);
+    }
+
+    #[tokio::test]
+    async fn test_shell_cmd_does_not_wait_for_background_children() {
+        // the first sleep stays in the group, the second leaves it and keeps stdout open
+        let command = shell_command("echo started; sleep 5 & setsid sleep 5 & echo done");
+        let context = ExecutorContext {
+            settings: ExecutorSettings {
+                kill_grace_secs: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let result = super::handoff_command_to_executor(&command, &context)
+            .await
+            .unwrap();
+
+        assert_eq!(result.stdout.as_deref(), Some("started\ndone\n"));
+        assert_eq!(result.exit_code, Some(0));
+        assert!(result.duration_ms < 4_000);
+    }
+
+    #[tokio::test]
+    async fn test_unsigned_command_is_refused() {
+        let (_, public_key) = test_keys::generate();
+        let settings = SecuritySettings {
+            command_public_key: Some(public_key),
+            ..Default::default()
+        };
+        let context = ExecutorContext {
+            verifier: CommandVerifier::new(&settings, &"default".to_string()).unwrap(),
+            ..Default::default()
+        };
+        let command = shell_command("touch /tmp/itx-should-not-exist");
+
+        let result = super::handoff_command_to_executor(&command, &context).await;
+
+        assert!(matches!(result, Err(HandlerError::InvalidSignature(_))));
+    }
+
+    #[tokio::test]
+    async fn test_shell_cmd_streams_output() {
+        let command = shell_command(
+            "echo out; echo err 1>&2; printf 'caf\\303'; sleep 0.1; printf '\\251\\n'",
+        );
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let result =
+            super::handoff_command_streaming(&command, &ExecutorContext::default(), Some(tx), None)
+                .await
+                .unwrap();
+
+        let mut chunks = vec![];
+        while let Some(chunk) = rx.recv().await {
+            chunks.push(chunk);
+        }
+        let mut seqs: Vec<u64> = chunks.iter().map(|chunk| chunk.seq).collect();
+        seqs.sort();
+        assert_eq!(seqs, (0..chunks.len() as u64).collect::<Vec<_>>());
+        let streamed = |stream: OutputStream| -> String {
+            chunks
+                .iter()
+                .filter(|chunk| chunk.stream == stream)
+                .map(|chunk| chunk.data.as_str())
+                .collect()
+        };
+        // the two byte character split across writes is never sent half
+        assert_eq!(streamed(OutputStream::Stdout), "out\ncafé\n");
+        assert_eq!(streamed(OutputStream::Stderr), "err\n");
+        assert_eq!(result.stdout.as_deref(), Some("out\ncafé\n"));
+    }
+
+    #[tokio::test]
+    async fn test_shell_cmd_keeps_output_up_to_limit_but_streams_all() {
+        let command = shell_command("head -c 100000 /dev/zero | tr '\\0' x");
+        let context = ExecutorContext {
+            result: ResultSettings {
+                max_output_bytes: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let result = super::handoff_command_streaming(&command, &context, Some(tx), None)
+            .await
+            .unwrap();
+
+        assert_eq!(result.stdout.as_deref(), Some("xxxxxxxxxx"));
+        assert_eq!(result.stdout_dropped_bytes, Some(99_990));
+        assert_eq!(result.stderr_dropped_bytes, None);
+        let mut streamed = 0;
+        while let Some(chunk) = rx.recv().await {
+            streamed += chunk.data.len();
+        }
+        assert_eq!(streamed, 100_000);
+    }
+
+    #[tokio::test]
+    async fn test_shell_cmd_is_cancelled() {
+        let command = shell_command("echo started; sleep 30");
+        let (cancel_tx, cancel) = CancelSignal::channel();
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            cancel_tx.send(true).unwrap();
+        });
+
+        let result = super::handoff_command_streaming(
+            &command,
+            &ExecutorContext::default(),
+            None,
+            Some(cancel),
+        )
+        .await
+        .unwrap();
+
+        assert!(result.cancelled);
+        assert!(!result.timed_out);
+        assert_eq!(result.stdout.as_deref(), Some("started\n"));
+        assert!(result.duration_ms < 10_000);
+        assert!(matches!(result.status(), CommandStatus::Cancelled));
+    }
+
+    fn get_policy_context(dir: &TempDir, policy: &str) -> ExecutorContext {
+        let path = dir.path().join("policy.toml");
+        std::fs::write(&path, policy).unwrap();
+        ExecutorContext {
+            policy: PolicyEngine::load(path.to_str()).unwrap(),
+            ..Default::default()
         }
     }
+
+    #[tokio::test]
+    async fn test_policy_denied_command_is_refused() {
+        let dir = TempDir::new("test-executor-policy").unwrap();
+        let context = get_policy_context(
+            &dir,
+            "[[rules]]\nid = \"no-rm\"\naction = \"deny\"\nargs = \"^rm \"",
+        );
+
+        let result =
+            super::handoff_command_to_executor(&shell_command("rm -rf /tmp/x"), &context).await;
+
+        assert!(matches!(result, Err(HandlerError::PolicyDenied(rule)) if rule == "no-rm"));
+    }
+
+    #[tokio::test]
+    async fn test_policy_max_runtime_caps_timeout() {
+        let dir = TempDir::new("test-executor-policy").unwrap();
+        let context = get_policy_context(
+            &dir,
+            "[[rules]]\nid = \"short\"\naction = \"allow\"\nmax_runtime_secs = 1",
+        );
+
+        let result = super::handoff_command_to_executor(&shell_command("sleep 30"), &context)
+            .await
+            .unwrap();
+
+        assert!(result.timed_out);
+        assert!(result.duration_ms < 10_000);
+    }
 }
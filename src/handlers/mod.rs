@@ -0,0 +1,246 @@
+pub mod self_test;
+pub mod shell;
+pub mod update;
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::config::{ExecutorSettings, SandboxSettings};
+use crate::executor::CancelSignal;
+use crate::models::db::commands::Command;
+use crate::models::db::output::OutputChunk;
+use crate::models::db::results::ExecutionResult;
+use crate::models::HandlerError;
+use crate::updater::Updater;
+
+/// what a handler gets besides the command, once it passed verification and policy
+pub struct HandlerContext<'a> {
+    pub settings: &'a ExecutorSettings,
+    pub sandbox: &'a SandboxSettings,
+    /// output kept per stream for the result, see `executor::run_shell`
+    pub max_output_bytes: usize,
+    /// runtime cap from the allowing policy rule, on top of the command's own timeout
+    pub max_runtime_secs: Option<u64>,
+    /// streams output while the command runs, all of it even past `max_output_bytes`
+    pub output: Option<UnboundedSender<OutputChunk>>,
+    /// fires when the server cancels the command
+    pub cancel: Option<CancelSignal>,
+}
+
+/**
+ * runs one kind of command. the executor picks the handler registered under
+ * `Command.name`, so a new command is a new handler registered with `HandlerRegistry`, in
+ * this module or anywhere else.
+ *
+ * timing (`started_at_ms` and so on) is filled in by the executor.
+ */
+pub trait CommandHandler: Send + Sync {
+    /// the `Command.name` this handler runs
+    fn name(&self) -> &str;
+
+    /// json schema of the command's `args`, advertised to the server
+    fn args_schema(&self) -> serde_json::Value;
+
+    fn execute<'a>(
+        &'a self,
+        command: &'a Command,
+        context: HandlerContext<'a>,
+    ) -> BoxFuture<'a, Result<ExecutionResult, HandlerError>>;
+}
+
+/// a registered handler as the server sees it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HandlerInfo {
+    pub name: String,
+    pub args_schema: serde_json::Value,
+}
+
+/// the handlers the executor dispatches to, by command name
+#[derive(Clone)]
+pub struct HandlerRegistry {
+    handlers: BTreeMap<String, Arc<dyn CommandHandler>>,
+}
+
+impl HandlerRegistry {
+    pub fn empty() -> Self {
+        HandlerRegistry {
+            handlers: BTreeMap::new(),
+        }
+    }
+
+    /// `Test` and `ShellCmd`; `Update` needs an updater, see `with_updater`
+    pub fn builtin() -> Self {
+        let mut registry = HandlerRegistry::empty();
+        registry.register(Arc::new(self_test::SelfTestHandler));
+        registry.register(Arc::new(shell::ShellHandler));
+        registry
+    }
+
+    /// `builtin`, plus `Update` run by `updater` if it has a release key to check updates with
+    pub fn with_updater(updater: Option<&Arc<Updater>>) -> Self {
+        let mut registry = HandlerRegistry::builtin();
+        if let Some(updater) = updater.filter(|updater| updater.can_update()) {
+            registry.register(Arc::new(update::UpdateHandler::new(updater.clone())));
+        }
+        registry
+    }
+
+    /// replaces a handler already registered under the same name
+    pub fn register(&mut self, handler: Arc<dyn CommandHandler>) {
+        let name = handler.name().to_string();
+        if self.handlers.insert(name.clone(), handler).is_some() {
+            warn!("replacing the handler for command {}", name);
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn CommandHandler>> {
+        self.handlers.get(name)
+    }
+
+    /// every registered handler, by name
+    pub fn describe(&self) -> Vec<HandlerInfo> {
+        self.handlers
+            .values()
+            .map(|handler| HandlerInfo {
+                name: handler.name().to_string(),
+                args_schema: handler.args_schema(),
+            })
+            .collect()
+    }
+}
+
+impl Default for HandlerRegistry {
+    fn default() -> Self {
+        HandlerRegistry::builtin()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use futures::future::BoxFuture;
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    use crate::{
+        config::DaemonConfig,
+        executor::{handoff_command_to_executor, ExecutorContext},
+        models::{
+            db::{
+                commands::{Command, CommandNames},
+                results::ExecutionResult,
+            },
+            HandlerError,
+        },
+        shutdown,
+        updater::Updater,
+        verification::test_keys,
+    };
+
+    use super::{CommandHandler, HandlerContext, HandlerRegistry};
+
+    struct Reverse;
+
+    impl CommandHandler for Reverse {
+        fn name(&self) -> &str {
+            "Test"
+        }
+
+        fn args_schema(&self) -> serde_json::Value {
+            json!({ "type": "string" })
+        }
+
+        fn execute<'a>(
+            &'a self,
+            command: &'a Command,
+            _context: HandlerContext<'a>,
+        ) -> BoxFuture<'a, Result<ExecutionResult, HandlerError>> {
+            Box::pin(async move {
+                Ok(ExecutionResult {
+                    stdout: command
+                        .args
+                        .as_ref()
+                        .map(|args| args.chars().rev().collect()),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    fn command(name: CommandNames, args: &str) -> Command {
+        let mut command = Command::default();
+        command.name = name;
+        command.args = Some(args.to_string());
+        command
+    }
+
+    #[tokio::test]
+    async fn test_executor_dispatches_to_registered_handler() {
+        let mut context = ExecutorContext::default();
+        context.handlers.register(Arc::new(Reverse));
+
+        let result = handoff_command_to_executor(&command(CommandNames::Test, "abc"), &context)
+            .await
+            .unwrap();
+
+        assert_eq!(result.stdout.as_deref(), Some("cba"));
+        assert!(result.ended_at_ms >= result.started_at_ms);
+    }
+
+    #[tokio::test]
+    async fn test_unregistered_command_is_unsupported() {
+        let context = ExecutorContext {
+            handlers: HandlerRegistry::empty(),
+            ..Default::default()
+        };
+
+        let result =
+            handoff_command_to_executor(&command(CommandNames::ShellCmd, "echo hi"), &context)
+                .await;
+
+        assert!(matches!(
+            result,
+            Err(HandlerError::UnsupportedCommand(name)) if name == "ShellCmd"
+        ));
+    }
+
+    #[test]
+    fn test_registry_describes_handlers() {
+        let names: Vec<String> = HandlerRegistry::builtin()
+            .describe()
+            .into_iter()
+            .map(|info| info.name)
+            .collect();
+
+        assert_eq!(names, vec!["ShellCmd", "Test"]);
+    }
+
+    #[test]
+    fn test_registry_with_updater_needs_a_release_key() {
+        let dir = TempDir::new("test-registry").unwrap();
+        let names = |public_key: Option<String>| -> Vec<String> {
+            let mut config = DaemonConfig::default();
+            config.localstore.path = dir.path().join("localstore.json").display().to_string();
+            config.update.binary_path = Some(dir.path().join("itx-daemon").display().to_string());
+            config.update.public_key = public_key;
+            let updater = Arc::new(Updater::new(&config, shutdown::channel().0).unwrap());
+            HandlerRegistry::with_updater(Some(&updater))
+                .describe()
+                .into_iter()
+                .map(|info| info.name)
+                .collect()
+        };
+
+        assert_eq!(names(None), vec!["ShellCmd", "Test"]);
+        assert_eq!(
+            names(Some(test_keys::generate().1)),
+            vec!["ShellCmd", "Test", "Update"]
+        );
+    }
+}
@@ -0,0 +1,34 @@
+use futures::future::BoxFuture;
+use serde_json::json;
+
+use crate::models::db::commands::Command;
+use crate::models::db::results::ExecutionResult;
+use crate::models::HandlerError;
+
+use super::{CommandHandler, HandlerContext};
+
+/// `Test`: answers without running anything, to check a device end to end
+pub struct SelfTestHandler;
+
+impl CommandHandler for SelfTestHandler {
+    fn name(&self) -> &str {
+        "Test"
+    }
+
+    fn args_schema(&self) -> serde_json::Value {
+        json!({ "type": "null" })
+    }
+
+    fn execute<'a>(
+        &'a self,
+        _command: &'a Command,
+        _context: HandlerContext<'a>,
+    ) -> BoxFuture<'a, Result<ExecutionResult, HandlerError>> {
+        Box::pin(async {
+            Ok(ExecutionResult {
+                stdout: Some("test".to_string()),
+                ..Default::default()
+            })
+        })
+    }
+}
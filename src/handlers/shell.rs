@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use serde_json::json;
+
+use crate::executor::{run_shell, Stop};
+use crate::models::db::commands::Command;
+use crate::models::db::results::ExecutionResult;
+use crate::models::HandlerError;
+
+use super::{CommandHandler, HandlerContext};
+
+/**
+ * `ShellCmd`: runs the args with `sh -c`, see `executor::run_shell`. the shortest of the
+ * command's timeout (or the configured default) and the policy's runtime cap applies; the
+ * sandbox is used when configured or asked for by the command.
+ */
+pub struct ShellHandler;
+
+impl CommandHandler for ShellHandler {
+    fn name(&self) -> &str {
+        "ShellCmd"
+    }
+
+    fn args_schema(&self) -> serde_json::Value {
+        json!({
+            "type": "string",
+            "description": "command line for sh -c",
+        })
+    }
+
+    fn execute<'a>(
+        &'a self,
+        command: &'a Command,
+        context: HandlerContext<'a>,
+    ) -> BoxFuture<'a, Result<ExecutionResult, HandlerError>> {
+        Box::pin(async move {
+            let args = command.args.as_ref().ok_or_else(|| {
+                HandlerError::ParseError("no args found for shell cmd".to_string())
+            })?;
+            let settings = context.settings;
+            let timeout = command
+                .timeout_secs
+                .or(settings.default_timeout_secs)
+                .into_iter()
+                .chain(context.max_runtime_secs)
+                .min()
+                .map(Duration::from_secs);
+            let stop = Stop {
+                timeout,
+                grace: Duration::from_secs(settings.kill_grace_secs),
+                cancel: context.cancel,
+            };
+            let sandbox = (context.sandbox.enabled || command.sandbox).then_some(context.sandbox);
+            run_shell(
+                args,
+                stop,
+                sandbox,
+                context.output,
+                context.max_output_bytes,
+            )
+            .await
+        })
+    }
+}
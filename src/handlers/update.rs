@@ -0,0 +1,53 @@
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use serde_json::json;
+
+use crate::models::db::commands::Command;
+use crate::models::db::results::ExecutionResult;
+use crate::models::HandlerError;
+use crate::updater::Updater;
+
+use super::{CommandHandler, HandlerContext};
+
+/// `Update`: a signed self-update, see `updater::Updater`
+pub struct UpdateHandler {
+    updater: Arc<Updater>,
+}
+
+impl UpdateHandler {
+    pub fn new(updater: Arc<Updater>) -> Self {
+        UpdateHandler { updater }
+    }
+}
+
+impl CommandHandler for UpdateHandler {
+    fn name(&self) -> &str {
+        "Update"
+    }
+
+    fn args_schema(&self) -> serde_json::Value {
+        json!({
+            "type": "string",
+            "description": "json update manifest",
+            "contentMediaType": "application/json",
+            "contentSchema": {
+                "type": "object",
+                "properties": {
+                    "version": { "type": "string" },
+                    "sha256": { "type": "string" },
+                    "signature": { "type": "string" },
+                },
+                "required": ["version", "sha256", "signature"],
+            },
+        })
+    }
+
+    fn execute<'a>(
+        &'a self,
+        command: &'a Command,
+        _context: HandlerContext<'a>,
+    ) -> BoxFuture<'a, Result<ExecutionResult, HandlerError>> {
+        Box::pin(self.updater.stage(command))
+    }
+}
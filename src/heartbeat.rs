@@ -0,0 +1,145 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+
+use crate::api::control_plane::ControlPlane;
+use crate::api::models::heartbeat::{DiskUsage, HeartbeatRequest, LoadAverage, MemoryUsage};
+use crate::executor::now_ms;
+use crate::models::db::common::Id;
+use crate::shutdown::Shutdown;
+use crate::updater::VERSION;
+
+/// when a worker last started running a command, 0 if none has since the daemon started
+static LAST_COMMAND_MS: AtomicU64 = AtomicU64::new(0);
+
+/// a command starts running now, for the next heartbeat's `last_command_at_ms`
+pub fn record_command() {
+    LAST_COMMAND_MS.store(now_ms(), Ordering::Relaxed);
+}
+
+/// the device's health right now; a metric the platform does not provide is left out
+pub fn collect(device_id: &Id, started: Instant) -> HeartbeatRequest {
+    let last_command_ms = LAST_COMMAND_MS.load(Ordering::Relaxed);
+    HeartbeatRequest {
+        device_id: device_id.clone(),
+        version: VERSION.to_string(),
+        sent_at_ms: now_ms(),
+        uptime_secs: started.elapsed().as_secs(),
+        system_uptime_secs: system_uptime_secs(),
+        load_average: sys_info::loadavg().ok().map(|load| LoadAverage {
+            one: load.one,
+            five: load.five,
+            fifteen: load.fifteen,
+        }),
+        memory: sys_info::mem_info().ok().map(|memory| MemoryUsage {
+            total_kb: memory.total,
+            available_kb: memory.avail,
+        }),
+        disk: sys_info::disk_info().ok().map(|disk| DiskUsage {
+            total_kb: disk.total,
+            free_kb: disk.free,
+        }),
+        last_command_at_ms: (last_command_ms > 0).then_some(last_command_ms),
+    }
+}
+
+/// on linux `sys_info::boottime` reads `/proc/uptime`, i.e. it is the uptime, not the boot time
+#[cfg(target_os = "linux")]
+fn system_uptime_secs() -> Option<u64> {
+    sys_info::boottime().ok().map(|uptime| uptime.tv_sec as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn system_uptime_secs() -> Option<u64> {
+    None
+}
+
+/**
+ * posts a heartbeat every `interval` until shutdown, independent of the command loop, so the
+ * server sees the device alive (and how it is doing) while the loop is busy or backing off.
+ *
+ * a failed heartbeat is not retried: the next one is due soon enough, unless the server
+ * asked to wait longer (`Retry-After`, or an open circuit).
+ */
+pub async fn keep_beating(
+    control_plane: Arc<dyn ControlPlane>,
+    device_id: Id,
+    started: Instant,
+    interval: Duration,
+    shutdown: Shutdown,
+) {
+    loop {
+        let request = collect(&device_id, started);
+        let delay = match control_plane.send_heartbeat(&request).await {
+            Ok(()) => {
+                debug!("heartbeat sent, up {}s", request.uptime_secs);
+                interval
+            }
+            Err(e) => {
+                warn!("cannot send heartbeat: {}", e);
+                e.retry_after()
+                    .map_or(interval, |after| after.max(interval))
+            }
+        };
+        if !shutdown.sleep(delay).await {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use crate::{
+        api::control_plane::{ControlPlane, InMemoryControlPlane},
+        shutdown,
+        updater::VERSION,
+    };
+
+    #[test]
+    fn test_collect_reports_last_command() {
+        let started = Instant::now() - Duration::from_secs(5);
+
+        super::record_command();
+        let request = super::collect(&"testdeviceid".to_string(), started);
+
+        assert_eq!(request.device_id, "testdeviceid");
+        assert_eq!(request.version, VERSION);
+        assert!(request.uptime_secs >= 5);
+        assert!(request.last_command_at_ms.unwrap() <= request.sent_at_ms);
+        let memory = request.memory.unwrap();
+        assert!(memory.available_kb <= memory.total_kb);
+    }
+
+    #[tokio::test]
+    async fn test_keep_beating_continues_after_a_failure() {
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.fail_next(1);
+        let (trigger, shutdown) = shutdown::channel();
+        let plane: Arc<dyn ControlPlane> = control_plane.clone();
+        let task = tokio::spawn(super::keep_beating(
+            plane,
+            "testdeviceid".to_string(),
+            Instant::now(),
+            Duration::from_millis(10),
+            shutdown,
+        ));
+
+        for _ in 0..200 {
+            if control_plane.heartbeats().len() >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        trigger.trigger();
+        task.await.unwrap();
+
+        let heartbeats = control_plane.heartbeats();
+        assert!(heartbeats.len() >= 2);
+        assert!(heartbeats[0].sent_at_ms <= heartbeats[1].sent_at_ms);
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::executor::now_ms;
+use crate::localstore::write_atomically;
+use crate::models::db::commands::{Command, CommandStatus};
+use crate::models::db::common::{HasId, Id};
+use crate::models::HandlerError;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JournalState {
+    /// fetched and about to be acknowledged, not started yet
+    Received,
+    Running,
+    Finished(CommandStatus),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct JournalEntry {
+    command: Command,
+    state: JournalState,
+    updated_ms: u64,
+}
+
+/// what `Journal::recover` found unfinished from the previous run
+#[derive(Debug, Default)]
+pub struct Recovery {
+    /// never started, or idempotent: run them (again)
+    pub resume: Vec<Command>,
+    /// were running when the daemon died; now recorded as failed
+    pub interrupted: Vec<Command>,
+}
+
+/**
+ * every command the daemon has taken on, with its last known state, written to disk before
+ * each step so it survives a crash:
+ * - a command id already in the journal is never executed again (see `begin`)
+ * - after a restart `recover` sorts out what the last run left unfinished
+ *
+ * finished entries are kept for `journal.retain_days`, which bounds how late the server may
+ * resend a command without it running twice.
+ */
+pub struct Journal {
+    path: PathBuf,
+    entries: Mutex<HashMap<Id, JournalEntry>>,
+}
+
+impl Journal {
+    /// loads the journal at `path`, dropping finished entries older than `retain`
+    pub fn open(path: PathBuf, retain: Duration) -> Self {
+        let mut entries: HashMap<Id, JournalEntry> = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                let aside = path.with_extension("json.corrupt");
+                error!(
+                    "cannot read journal {}, moving it to {}: {}",
+                    path.display(),
+                    aside.display(),
+                    e
+                );
+                let _ = std::fs::rename(&path, &aside);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        let cutoff = now_ms().saturating_sub(retain.as_millis() as u64);
+        entries.retain(|_, entry| {
+            !matches!(entry.state, JournalState::Finished(_)) || entry.updated_ms > cutoff
+        });
+
+        let journal = Journal {
+            path,
+            entries: Mutex::new(entries),
+        };
+        journal.save(&journal.entries.lock().unwrap());
+        journal
+    }
+
+    /// records a newly fetched command; false if this id was seen before and must not run
+    pub fn begin(&self, command: &Command) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if let Some(entry) = entries.get(command.get_id()) {
+            info!(
+                "command {} is already in the journal as {:?}",
+                command.get_id(),
+                &entry.state
+            );
+            return false;
+        }
+        entries.insert(
+            command.get_id().clone(),
+            JournalEntry {
+                command: command.clone(),
+                state: JournalState::Received,
+                updated_ms: now_ms(),
+            },
+        );
+        self.save(&entries);
+        true
+    }
+
+    /// forgets a command that was never acknowledged, so it can be fetched again
+    pub fn forget(&self, command_id: &Id) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.remove(command_id).is_some() {
+            self.save(&entries);
+        }
+    }
+
+    pub fn start(&self, command_id: &Id) {
+        self.set_state(command_id, JournalState::Running);
+    }
+
+    pub fn finish(&self, command_id: &Id, status: CommandStatus) {
+        self.set_state(command_id, JournalState::Finished(status));
+    }
+
+    /**
+     * to be called once at startup: commands left `Received` never started and are resumed,
+     * as are `Running` ones marked idempotent. the other `Running` ones may have had side
+     * effects, so they are marked failed and returned as interrupted for reporting.
+     */
+    pub fn recover(&self) -> Recovery {
+        let mut entries = self.entries.lock().unwrap();
+        let mut recovery = Recovery::default();
+        for entry in entries.values_mut() {
+            match entry.state {
+                JournalState::Received => recovery.resume.push(entry.command.clone()),
+                JournalState::Running if entry.command.idempotent => {
+                    recovery.resume.push(entry.command.clone())
+                }
+                JournalState::Running => {
+                    warn!(
+                        "command {} was interrupted by a restart",
+                        entry.command.get_id()
+                    );
+                    entry.state = JournalState::Finished(CommandStatus::Failed);
+                    entry.updated_ms = now_ms();
+                    recovery.interrupted.push(entry.command.clone());
+                }
+                JournalState::Finished(_) => {}
+            }
+        }
+        self.save(&entries);
+        recovery
+    }
+
+    fn set_state(&self, command_id: &Id, state: JournalState) {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(command_id) {
+            Some(entry) => {
+                entry.state = state;
+                entry.updated_ms = now_ms();
+            }
+            None => {
+                warn!("command {} is not in the journal", command_id);
+                return;
+            }
+        }
+        self.save(&entries);
+    }
+
+    fn save(&self, entries: &HashMap<Id, JournalEntry>) {
+        let result = serde_json::to_vec_pretty(entries)
+            .map_err(HandlerError::from)
+            .and_then(|data| write_atomically(&self.path, &data));
+        if let Err(e) = result {
+            error!("cannot save journal to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use crate::models::db::{
+        commands::{Command, CommandStatus},
+        common::HasId,
+    };
+
+    use super::{Journal, JournalState};
+
+    const RETAIN: Duration = Duration::from_secs(3600);
+
+    fn command_with_id(id: &str, idempotent: bool) -> Command {
+        let mut value = serde_json::to_value(Command::default()).unwrap();
+        value["_id"] = id.into();
+        value["idempotent"] = idempotent.into();
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_command_is_only_begun_once() {
+        let dir = TempDir::new("test-journal").unwrap();
+        let path = dir.path().join("journal.json");
+        let command = command_with_id("one", false);
+
+        let journal = Journal::open(path.clone(), RETAIN);
+        assert!(journal.begin(&command));
+        assert!(!journal.begin(&command));
+        journal.finish(command.get_id(), CommandStatus::Terminated);
+        drop(journal);
+
+        let journal = Journal::open(path, RETAIN);
+        assert!(!journal.begin(&command));
+    }
+
+    #[test]
+    fn test_forgotten_command_can_begin_again() {
+        let dir = TempDir::new("test-journal").unwrap();
+        let journal = Journal::open(dir.path().join("journal.json"), RETAIN);
+        let command = command_with_id("one", false);
+
+        assert!(journal.begin(&command));
+        journal.forget(command.get_id());
+        assert!(journal.begin(&command));
+    }
+
+    #[test]
+    fn test_recover_sorts_unfinished_commands() {
+        let dir = TempDir::new("test-journal").unwrap();
+        let path = dir.path().join("journal.json");
+        let journal = Journal::open(path.clone(), RETAIN);
+        for (id, idempotent) in [
+            ("received", false),
+            ("running", false),
+            ("idempotent", true),
+            ("done", false),
+        ] {
+            journal.begin(&command_with_id(id, idempotent));
+        }
+        for id in ["running", "idempotent", "done"] {
+            journal.start(&id.to_string());
+        }
+        journal.finish(&"done".to_string(), CommandStatus::Terminated);
+        drop(journal);
+
+        let journal = Journal::open(path.clone(), RETAIN);
+        let recovery = journal.recover();
+
+        let mut resumed: Vec<&str> = recovery
+            .resume
+            .iter()
+            .map(|command| command.get_id().as_str())
+            .collect();
+        resumed.sort();
+        assert_eq!(resumed, vec!["idempotent", "received"]);
+        assert_eq!(recovery.interrupted.len(), 1);
+        assert_eq!(recovery.interrupted[0].get_id(), "running");
+
+        // the interrupted command counts as finished from now on
+        let recovery = Journal::open(path, RETAIN).recover();
+        assert_eq!(recovery.resume.len(), 2);
+        assert!(recovery.interrupted.is_empty());
+    }
+
+    #[test]
+    fn test_old_finished_entries_are_pruned() {
+        let dir = TempDir::new("test-journal").unwrap();
+        let path = dir.path().join("journal.json");
+        let journal = Journal::open(path.clone(), RETAIN);
+        journal.begin(&command_with_id("done", false));
+        journal.finish(&"done".to_string(), CommandStatus::Terminated);
+        journal.begin(&command_with_id("received", false));
+        drop(journal);
+
+        let journal = Journal::open(path, Duration::ZERO);
+
+        let entries = journal.entries.lock().unwrap();
+        assert!(!entries.contains_key("done"));
+        assert_eq!(entries["received"].state, JournalState::Received);
+    }
+}
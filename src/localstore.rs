@@ -1,10 +1,13 @@
+use crate::config::get_config;
 use crate::models::HandlerError;
 use jfs::{self};
 use lazy_static::lazy_static;
 use log::{debug, info};
 use std::collections::HashMap;
+use std::path::Path;
 
 use std::io::Write as _;
+use std::os::unix::fs::{OpenOptionsExt as _, PermissionsExt as _};
 
 use std::sync::Mutex;
 use tempdir::TempDir;
@@ -23,7 +26,7 @@ pub fn get_default_filepath() -> String {
             .display()
             .to_string();
     }
-    "localstore.json".to_string()
+    get_config().localstore.path.clone()
 }
 
 lazy_static! {
@@ -52,6 +55,7 @@ pub fn get_handle() -> Result<jfs::Store, HandlerError> {
             .open(&file_path)?
             .write_all(b"{}")?;
     }
+    restrict_permissions(Path::new(&file_path))?;
 
     info!("creating or getting store from path: {:#?}", &file_path);
     let result = jfs::Store::new_with_cfg(
@@ -94,6 +98,8 @@ pub fn write_single(data: &String, key: &str) -> Result<(), HandlerError> {
     let binding = HANDLE.lock().unwrap();
     let handle = binding.as_ref().unwrap();
     handle.save_with_id(data, key)?;
+    // jfs replaces the file on every save, which resets its mode to the umask
+    restrict_permissions(handle.path())?;
     Ok(())
 }
 
@@ -116,6 +122,36 @@ pub fn query_data(key: &str) -> Result<Option<String>, HandlerError> {
     query_internal(handle, key)
 }
 
+/// replaces `path` through a temporary file, so a crash never leaves it half written
+pub fn write_atomically(path: &Path, data: &[u8]) -> Result<(), HandlerError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, data)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// like `write_atomically`, but readable by the owner only, for keys and credentials
+pub fn write_private(path: &Path, data: &[u8]) -> Result<(), HandlerError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let _ = std::fs::remove_file(&tmp);
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?
+        .write_all(data)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// the localstore holds the device credential and certificate, so only its owner may read it
+fn restrict_permissions(path: &Path) -> Result<(), HandlerError> {
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
 
@@ -1,20 +1,67 @@
 #![feature(async_closure)]
-#![feature(never_type)]
+
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use log::error;
 use main_event_loop::run_main_event_loop;
-use pre_event_loop::{get_device_id, get_user_id, get_user_secret};
+use pre_event_loop::{get_device_id, get_device_name, get_user_id, get_user_secret};
 
-use crate::main_event_loop::{sleep_in_seconds, SLEEP_LONG};
+use crate::api::auth::{DeviceAuth, Enrollment, LocalstoreCredentials};
+use crate::api::client;
+use crate::api::control_plane::{self, HttpControlPlane};
+use crate::api::identity::{self, DeviceIdentity};
+use crate::api::requests::ApiConfig;
+use crate::api::retry::{Backoff, CircuitBreaker, RetryPolicy};
+use crate::handlers::HandlerRegistry;
+use crate::main_event_loop::sleep_for;
+use crate::shutdown::{EXIT_CONFIG, EXIT_OK};
+use crate::updater::Updater;
 mod models;
 
 #[tokio::main]
-async fn main() -> ! {
-    std::env::set_var("RUST_LOG", "info");
-    simple_logger::SimpleLogger::new().env().init().unwrap();
+async fn main() -> ExitCode {
+    let started = Instant::now();
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some(updater::WATCHDOG_FLAG) {
+        simple_logger::SimpleLogger::new().init().unwrap();
+        return updater::run_watchdog(args.next().as_deref());
+    }
+    let config = match config::load() {
+        Ok(config) => config::init(config),
+        Err(e) => {
+            eprintln!("invalid daemon configuration: {}", e);
+            return ExitCode::from(EXIT_CONFIG);
+        }
+    };
+    simple_logger::SimpleLogger::new()
+        .with_level(config.log_level())
+        .init()
+        .unwrap();
+    if let Err(e) = client::init(&config.http, &config.api) {
+        error!("cannot set up the http client: {}", e);
+        return ExitCode::from(EXIT_CONFIG);
+    }
+    let identity = if config.identity.client_certificate {
+        let loaded = DeviceIdentity::load_or_create(&config.identity_key_path())
+            .and_then(|identity| identity.install().map(|_| identity));
+        match loaded {
+            Ok(identity) => Some(Arc::new(identity)),
+            Err(e) => {
+                error!("cannot set up the device identity: {}", e);
+                return ExitCode::from(EXIT_CONFIG);
+            }
+        }
+    } else {
+        None
+    };
+    let retry_policy = RetryPolicy::from(&config.retry);
+    let (restart, shutdown) = shutdown::listen_for_signals();
 
     // pre event loop
     let user_id;
+    let mut backoff = Backoff::new(retry_policy.clone());
     loop {
         let resp = get_user_id();
         match resp {
@@ -24,12 +71,15 @@ async fn main() -> ! {
             }
             Err(e) => {
                 error!("error getting user id: {:#?}", e);
-                sleep_in_seconds(SLEEP_LONG);
+                if !sleep_for(backoff.next_delay(&e), &shutdown).await {
+                    return ExitCode::from(EXIT_OK);
+                }
             }
         }
     }
 
     let user_secret;
+    let mut backoff = Backoff::new(retry_policy.clone());
     loop {
         let resp = get_user_secret();
         match resp {
@@ -39,14 +89,38 @@ async fn main() -> ! {
             }
             Err(e) => {
                 error!("error getting user secret: {:#?}", e);
-                sleep_in_seconds(SLEEP_LONG);
+                if !sleep_for(backoff.next_delay(&e), &shutdown).await {
+                    return ExitCode::from(EXIT_OK);
+                }
             }
         }
     }
 
+    // built before registering, so the commands advertised then are the ones the daemon runs
+    let updater = match Updater::new(config, restart) {
+        Ok(updater) => Arc::new(updater),
+        Err(e) => {
+            error!("cannot set up self-updates: {}", e);
+            return ExitCode::from(EXIT_CONFIG);
+        }
+    };
+    let handlers = HandlerRegistry::with_updater(Some(&updater));
+    let capabilities = capabilities::collect(config, &handlers);
+    let registration_plane = HttpControlPlane::new(ApiConfig::from(&config.api))
+        .with_circuit_breaker(CircuitBreaker::from_settings(&config.retry).map(Arc::new));
+    let credentials = LocalstoreCredentials::new(config.signing_secret_path());
     let device_id;
+    let mut backoff = Backoff::new(retry_policy.clone());
     loop {
-        let resp = get_device_id(&user_id, &user_secret).await;
+        let resp = get_device_id(
+            &user_id,
+            &user_secret,
+            &registration_plane,
+            &credentials,
+            identity.as_deref(),
+            Some(&capabilities),
+        )
+        .await;
         match resp {
             Ok(id) => {
                 device_id = id;
@@ -54,20 +128,81 @@ async fn main() -> ! {
             }
             Err(e) => {
                 error!("error getting device id: {:#?}", e);
-                sleep_in_seconds(SLEEP_LONG);
+                if !sleep_for(backoff.next_delay(&e), &shutdown).await {
+                    return ExitCode::from(EXIT_OK);
+                }
             }
         }
     }
 
+    let enrollment = Enrollment {
+        device_id: device_id.clone(),
+        user_id: user_id.clone(),
+        user_secret,
+        device_name: get_device_name(),
+    };
+    let auth = Arc::new(DeviceAuth::new(
+        ApiConfig::from(&config.api),
+        enrollment,
+        Box::new(credentials),
+    ));
+
     // run main event loop
-    run_main_event_loop(&device_id, &user_id).await
+    let control_plane = control_plane::connect(config, &device_id, auth, &shutdown);
+    if config.heartbeat.enabled {
+        tokio::spawn(heartbeat::keep_beating(
+            control_plane.clone(),
+            device_id.clone(),
+            started,
+            Duration::from_secs(config.heartbeat.interval_secs),
+            shutdown.clone(),
+        ));
+    }
+    if let Some(identity) = identity {
+        tokio::spawn(identity::keep_renewed(
+            identity,
+            control_plane.clone(),
+            device_id.clone(),
+            Duration::from_secs(config.identity.renew_before_days * 24 * 3600),
+            retry_policy,
+            shutdown.clone(),
+        ));
+    }
+    updater.connect(control_plane.clone());
+    let code = run_main_event_loop(
+        config,
+        control_plane,
+        &device_id,
+        &user_id,
+        &shutdown,
+        Some(updater.clone()),
+        handlers,
+    )
+    .await;
+    // only returns if there is no update to restart into, or starting it failed
+    if let Err(e) = updater.restart() {
+        error!("cannot restart into the update: {}", e);
+    }
+    code
 }
 
 pub mod api;
+pub mod capabilities;
+pub mod config;
 pub mod executor;
+pub mod handlers;
+pub mod heartbeat;
+pub mod journal;
 pub mod localstore;
 pub mod main_event_loop;
+pub mod outbox;
+pub mod policy;
 pub mod pre_event_loop;
+pub mod sandbox;
+pub mod shutdown;
+pub mod updater;
+pub mod verification;
+pub mod worker_pool;
 
 #[cfg(test)]
 pub mod test_commons;
@@ -1,117 +1,495 @@
-use log::{error, info, trace};
+use log::{error, info, trace, warn};
 use std::error::Error;
-use std::thread;
+use std::process::ExitCode;
+use std::sync::Arc;
 use std::time::Duration;
 
-use crate::executor::handoff_command_to_executor;
-use crate::{
-    api::{self, requests::ApiConfig},
-    models::{
-        db::{
-            commands::{Command, CommandStatus},
-            common::Id,
-        },
-        HandlerError,
+use tokio::sync::{mpsc, OwnedSemaphorePermit};
+
+use crate::api::control_plane::ControlPlane;
+use crate::api::models::upload_command_result::UploadCommandResultRequest;
+use crate::api::retry::{Backoff, RetryPolicy};
+use crate::capabilities;
+use crate::config::{DaemonConfig, OutputSettings};
+use crate::executor::{handoff_command_streaming, CancelSignal, ExecutorContext};
+use crate::handlers::HandlerRegistry;
+use crate::heartbeat;
+use crate::journal::Journal;
+use crate::models::{
+    db::{
+        commands::{Command, CommandStatus},
+        common::{HasId, Id},
+        output::OutputChunk,
+        results::ExecutionResult,
     },
+    HandlerError,
 };
-
-pub const SLEEP_SHORT: u64 = 1;
-pub const SLEEP_MEDIUM: u64 = 5;
-pub const SLEEP_LONG: u64 = 10;
+use crate::outbox::{Outbox, OutboxMessage};
+use crate::shutdown::{Shutdown, EXIT_ABANDONED, EXIT_CONFIG, EXIT_OK};
+use crate::updater::{UpdateOutcome, Updater};
+use crate::worker_pool::{Canceller, WorkerPool};
 
 /**
- * main (post-registered) run loop:
- * 1. call server to fetch commands using the deviceId (TODO @felipearce: add some auth eventually)
- *      a. if no commands found:
- *          - sleep for foobar seconds and then redo loop
+ * main (post-registered) run loop, split into a fetcher (this loop) and a pool of workers:
+ * 1. wait for a free worker slot (`executor.max_concurrent`)
+ * 2. call server to fetch commands using the deviceId and the device's bearer token,
+ *    or wait for one to be pushed if the command websocket (`transport.websocket`) is connected
+ *    a. if no commands found, sleep for foobar seconds and then redo loop
+ *    b. if the call failed, back off (see `api::retry`) before the next one
+ *
+ * 3. call server to ACK the command as received and hand it off to a worker, which:
+ *    a. waits on the per-command-name limit, then marks the command as running
+ *    b. executes the command; one whose server signature does not verify, or that the
+ *    local policy denies, is reported as blocked instead (with the rule id as its
+ *    result), and one with no registered handler (see `handlers`) as failed, as
+ *    unsupported. while it runs, its output is uploaded in batches (`output.stream`)
+ *    c. uploads the execution result (stdout/stderr cut to `result.max_output_bytes`,
+ *    exit code, signal, timings) to the server
+ *    d. sends the final status: terminated on a zero exit, failed otherwise
+ *    the running status, result and final status go through the outbox (`outbox`), which
+ *    keeps them, in order, until the server accepts them
+ *
+ * every command is written to the journal (`journal`) before it is acknowledged, and again
+ * as it starts and finishes; an id the journal already holds is never run again. on startup
+ * the journal is reconciled first (see `recover_commands`).
+ *
+ * while commands are in flight a watcher asks the server for cancellations every
+ * `poll.short_secs` (or takes them from the websocket); a cancelled command is terminated,
+ * or never started if it is still queued, and ends as cancelled.
  *
- * 2. call server to update command status as executing/etc. and send ACK to server
- * 3. execute command
- * 4. call server to send outgoing update commands status request if success or err. or blocking or etc.
- * 5. return data from command (if any)
+ * on shutdown the loop stops fetching, drains the pool (see `stop_workers`) and gives the
+ * outbox `outbox.flush_secs` to empty.
  */
-pub async fn run_main_event_loop(device_id: &Id, _user_id: &Id) -> ! {
-    loop {
-        // get most recent command
-        let command_resp = fetch_command(device_id).await;
+pub async fn run_main_event_loop(
+    config: &DaemonConfig,
+    control_plane: Arc<dyn ControlPlane>,
+    device_id: &Id,
+    _user_id: &Id,
+    shutdown: &Shutdown,
+    updater: Option<Arc<Updater>>,
+    handlers: HandlerRegistry,
+) -> ExitCode {
+    let poll = &config.poll;
+    let context = match ExecutorContext::new(config, device_id) {
+        Ok(context) => Arc::new(context.with_handlers(handlers, updater.clone())),
+        Err(e) => {
+            error!("cannot set up the executor: {}", e);
+            return ExitCode::from(EXIT_CONFIG);
+        }
+    };
+    // the same handlers were advertised at registration
+    tokio::spawn(capabilities::keep_advertised(
+        capabilities::collect(config, &context.handlers),
+        control_plane.clone(),
+        device_id.clone(),
+        Duration::from_secs(config.device.advertise_secs),
+        RetryPolicy::from(&config.retry),
+        shutdown.clone(),
+    ));
+    let outbox = Arc::new(Outbox::open(config.outbox_path(), &config.outbox));
+    let journal = Arc::new(Journal::open(
+        config.journal_path(),
+        Duration::from_secs(config.journal.retain_days * 24 * 60 * 60),
+    ));
+    let worker = Worker {
+        context,
+        config: Arc::new(config.clone()),
+        control_plane: control_plane.clone(),
+        outbox: outbox.clone(),
+        journal: journal.clone(),
+    };
+    let delivery = tokio::spawn(outbox.clone().run(control_plane.clone()));
+    let mut pool = WorkerPool::new(&config.executor);
+    let mut backoff = Backoff::new(RetryPolicy::from(&config.retry));
+    recover_commands(&mut pool, &worker, shutdown).await;
+    let watcher = tokio::spawn(watch_cancellations(
+        pool.canceller(),
+        control_plane.clone(),
+        device_id.clone(),
+        Duration::from_secs(poll.short_secs),
+    ));
+    while !shutdown.is_triggered() {
+        let slot = tokio::select! {
+            slot = pool.reserve() => slot,
+            _ = shutdown.wait() => break,
+        };
+
+        // get most recent command; push transports wait for one inside fetch_command
+        let pushed = control_plane.is_push();
+        let command_resp = control_plane.fetch_command(device_id).await;
+        if command_resp.is_ok() {
+            backoff.reset();
+            if let Some(outcome) = updater.as_ref().and_then(|updater| updater.check_in()) {
+                report_update(outcome, &worker);
+            }
+        }
         let sleep_int = match command_resp {
+            Ok(Some(command)) if pool.is_in_flight(command.get_id()) => {
+                info!("command {} is already in flight", command.get_id());
+                poll.short_secs
+            }
+            Ok(Some(command)) if !journal.begin(&command) => {
+                info!(
+                    "command {} was already taken on, skipping",
+                    command.get_id()
+                );
+                poll.short_secs
+            }
             Ok(Some(command)) => {
                 dbg!(&command);
-                let resp = update_command_status(&command, CommandStatus::Received).await;
-                match resp {
+                match control_plane
+                    .update_command_status(&command, CommandStatus::Received)
+                    .await
+                {
                     Err(e) => {
+                        // not acknowledged, so the server may hand it out again
+                        journal.forget(command.get_id());
                         handle_err(e);
                     }
-                    Ok(_) => {
-                        let resp = execute_command(&command).await;
-                        let command_status = match resp {
-                            Ok(data_opt) => {
-                                info!("command executed is OK, data: {:?}", data_opt);
-                                CommandStatus::Terminated
-                            }
-                            Err(e) => {
-                                handle_err(e);
-                                CommandStatus::Failed
-                            }
-                        };
-
-                        if let Err(e) = update_command_status(&command, command_status).await {
-                            handle_err(e);
-                        }
-                    }
+                    Ok(_) => spawn_command(&mut pool, slot, command, &worker),
                 }
 
-                SLEEP_SHORT
+                poll.short_secs
             }
             Ok(None) => {
                 info!("no commands found");
-                SLEEP_MEDIUM
+                poll.medium_secs
             }
             Err(e) => {
+                // also while pushed: the socket may be up while the server is not
+                let delay = backoff.next_delay(&e);
                 handle_err(e);
-                SLEEP_LONG
+                sleep_for(delay, shutdown).await;
+                continue;
             }
         };
 
-        sleep_in_seconds(sleep_int);
+        if !pushed {
+            sleep_for(Duration::from_secs(sleep_int), shutdown).await;
+        }
+    }
+
+    let code = stop_workers(&mut pool, config, &outbox, &journal).await;
+    watcher.abort();
+    if !outbox
+        .flush(Duration::from_secs(config.outbox.flush_secs))
+        .await
+    {
+        warn!(
+            "{} messages left in the outbox, they are sent after the next start",
+            outbox.len()
+        );
     }
+    delivery.abort();
+    code
 }
 
-pub async fn fetch_command(device_id: &Id) -> Result<Option<Command>, HandlerError> {
-    let response =
-        api::requests::fetch_commands::fetch_commands(device_id.clone(), &ApiConfig::default())
-            .await?;
-    if response.is_none() {
-        Ok(None)
-    } else {
-        let command = response.unwrap().command;
-        Ok(Some(command))
+/**
+ * startup: reports the commands the last run was executing when it died as failed, with the
+ * reason as the result's error, and puts the ones that can safely run (again) back in the pool.
+ */
+async fn recover_commands(pool: &mut WorkerPool, worker: &Worker, shutdown: &Shutdown) {
+    let recovery = worker.journal.recover();
+    for command in recovery.interrupted {
+        let result = ExecutionResult {
+            error: Some("interrupted by a daemon restart".to_string()),
+            ..Default::default()
+        };
+        queue_result(&command, result, &worker.config, &worker.outbox);
+        worker.outbox.push(OutboxMessage::Status {
+            command,
+            status: CommandStatus::Failed,
+        });
+    }
+    for command in recovery.resume {
+        info!("resuming command {}", command.get_id());
+        let slot = tokio::select! {
+            slot = pool.reserve() => slot,
+            _ = shutdown.wait() => return,
+        };
+        spawn_command(pool, slot, command, worker);
     }
 }
 
-pub async fn update_command_status(
-    command: &Command,
-    new_status: CommandStatus,
-) -> Result<(), HandlerError> {
-    api::requests::update_command_status::update_command_status(
+/// the final status of an update, from the version the daemon came back on
+fn report_update(outcome: UpdateOutcome, worker: &Worker) {
+    let UpdateOutcome {
         command,
-        new_status,
-        &ApiConfig::default(),
-    )
-    .await?;
+        result,
+        status,
+    } = outcome;
+    queue_result(&command, result, &worker.config, &worker.outbox);
+    worker.journal.finish(command.get_id(), status.clone());
+    worker
+        .outbox
+        .push(OutboxMessage::Status { command, status });
+}
+
+fn spawn_command(
+    pool: &mut WorkerPool,
+    slot: OwnedSemaphorePermit,
+    command: Command,
+    worker: &Worker,
+) {
+    let worker = worker.clone();
+    pool.spawn(slot, command, |command, cancel| worker.run(command, cancel));
+}
+
+/// forwards the server's cancellations to the pool, including while it drains on shutdown
+async fn watch_cancellations(
+    canceller: Canceller,
+    control_plane: Arc<dyn ControlPlane>,
+    device_id: Id,
+    interval: Duration,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if canceller.is_idle() {
+            continue;
+        }
+        let command_ids = match control_plane.fetch_cancellations(&device_id).await {
+            Ok(command_ids) => command_ids,
+            Err(e) => {
+                handle_err(e);
+                continue;
+            }
+        };
+        for command_id in command_ids {
+            if canceller.cancel(&command_id) {
+                info!("cancelling command {}", &command_id);
+            } else {
+                info!(
+                    "cancellation for command {} which is not running",
+                    &command_id
+                );
+            }
+        }
+    }
+}
+
+/**
+ * shutdown: stop fetching, give in-flight commands until the drain deadline, then report
+ * whatever is left as failed so the server does not wait on them forever.
+ */
+async fn stop_workers(
+    pool: &mut WorkerPool,
+    config: &DaemonConfig,
+    outbox: &Outbox,
+    journal: &Journal,
+) -> ExitCode {
+    let deadline = Duration::from_secs(config.shutdown.drain_timeout_secs);
+    let abandoned = pool.drain(deadline).await;
+    if abandoned.is_empty() {
+        info!("all commands finished, exiting");
+        return ExitCode::from(EXIT_OK);
+    }
+
+    for command in abandoned {
+        journal.finish(command.get_id(), CommandStatus::Failed);
+        outbox.push(OutboxMessage::Status {
+            command,
+            status: CommandStatus::Failed,
+        });
+    }
+    ExitCode::from(EXIT_ABANDONED)
+}
+
+/// what each worker needs, shared across the pool
+#[derive(Clone)]
+struct Worker {
+    context: Arc<ExecutorContext>,
+    config: Arc<DaemonConfig>,
+    control_plane: Arc<dyn ControlPlane>,
+    outbox: Arc<Outbox>,
+    journal: Arc<Journal>,
+}
+
+impl Worker {
+    /// worker side of the loop: runs a single received command through to its final status
+    async fn run(self, command: Command, cancel: CancelSignal) {
+        let Worker {
+            context,
+            config,
+            control_plane,
+            outbox,
+            journal,
+        } = self;
+        if cancel.is_cancelled() {
+            info!(
+                "command {} was cancelled before it started",
+                command.get_id()
+            );
+            journal.finish(command.get_id(), CommandStatus::Cancelled);
+            outbox.push(OutboxMessage::Status {
+                command,
+                status: CommandStatus::Cancelled,
+            });
+            return;
+        }
+        journal.start(command.get_id());
+        heartbeat::record_command();
+        outbox.push(OutboxMessage::Status {
+            command: command.clone(),
+            status: CommandStatus::Running,
+        });
+
+        let resp = if config.output.stream {
+            let (tx, rx) = mpsc::unbounded_channel();
+            // the uploader ends once the executor drops its sender, before the result is reported
+            let (resp, _) = tokio::join!(
+                handoff_command_streaming(&command, &context, Some(tx), Some(cancel)),
+                upload_output(&command, rx, &config.output, control_plane.as_ref())
+            );
+            resp
+        } else {
+            execute_command(&command, &context, Some(cancel)).await
+        };
+        let command_status = match resp {
+            Ok(_)
+                if context
+                    .updater
+                    .as_ref()
+                    .is_some_and(|u| u.is_staged(command.get_id())) =>
+            {
+                // settled by the version the daemon restarts into, see `updater`; finished
+                // here only so that this run does not report it as interrupted
+                info!("command {} restarts the daemon", command.get_id());
+                journal.finish(command.get_id(), CommandStatus::Running);
+                return;
+            }
+            Ok(result) => {
+                info!(
+                    "command {} executed, result: {:?}",
+                    command.get_id(),
+                    result
+                );
+                let status = result.status();
+                queue_result(&command, result, &config, &outbox);
+                status
+            }
+            Err(HandlerError::InvalidSignature(reason)) => {
+                warn!("refusing command {}: {}", command.get_id(), &reason);
+                let result = ExecutionResult {
+                    error: Some(format!("invalid signature: {}", reason)),
+                    ..Default::default()
+                };
+                queue_result(&command, result, &config, &outbox);
+                CommandStatus::Blocked
+            }
+            Err(HandlerError::PolicyDenied(rule_id)) => {
+                warn!(
+                    "command {} denied by policy rule {}",
+                    command.get_id(),
+                    &rule_id
+                );
+                let result = ExecutionResult {
+                    policy_rule: Some(rule_id),
+                    ..Default::default()
+                };
+                queue_result(&command, result, &config, &outbox);
+                CommandStatus::Blocked
+            }
+            Err(HandlerError::UnsupportedCommand(name)) => {
+                warn!("command {} is not supported: {:?}", command.get_id(), &name);
+                let result = ExecutionResult {
+                    error: Some(format!("unsupported command {:?}", name)),
+                    ..Default::default()
+                };
+                queue_result(&command, result, &config, &outbox);
+                CommandStatus::Failed
+            }
+            Err(HandlerError::UpdateFailed(reason)) => {
+                warn!("update {} failed: {}", command.get_id(), &reason);
+                let result = ExecutionResult {
+                    error: Some(reason),
+                    ..Default::default()
+                };
+                queue_result(&command, result, &config, &outbox);
+                CommandStatus::Failed
+            }
+            Err(e) => {
+                handle_err(e);
+                CommandStatus::Failed
+            }
+        };
+
+        journal.finish(command.get_id(), command_status.clone());
+        outbox.push(OutboxMessage::Status {
+            command,
+            status: command_status,
+        });
+    }
+}
 
-    Ok(())
+fn queue_result(
+    command: &Command,
+    result: ExecutionResult,
+    config: &DaemonConfig,
+    outbox: &Outbox,
+) {
+    let request = UploadCommandResultRequest::new(
+        command.get_id().clone(),
+        result,
+        config.result.max_output_bytes,
+    );
+    if request.is_truncated() {
+        warn!(
+            "output of command {} was truncated for upload",
+            command.get_id()
+        );
+    }
+    outbox.push(OutboxMessage::Result { request });
 }
 
-pub async fn execute_command(command: &Command) -> Result<Option<String>, HandlerError> {
-    let resp = handoff_command_to_executor(command).await?;
+pub async fn execute_command(
+    command: &Command,
+    context: &ExecutorContext,
+    cancel: Option<CancelSignal>,
+) -> Result<ExecutionResult, HandlerError> {
+    let resp = handoff_command_streaming(command, context, None, cancel).await?;
     Ok(resp)
 }
 
-pub fn sleep_in_seconds(units: u64) {
-    let sleep_in_ms = units * 1000;
-    info!("sleeping for {} seconds...", units);
-    thread::sleep(Duration::from_millis(sleep_in_ms));
+/**
+ * batches streamed output: a batch starts with the first chunk to arrive and is uploaded
+ * after `flush_millis`, or sooner once it reaches `max_batch_bytes`. a failed upload is only
+ * logged; the full output still goes out with the execution result.
+ */
+async fn upload_output(
+    command: &Command,
+    mut output: mpsc::UnboundedReceiver<OutputChunk>,
+    settings: &OutputSettings,
+    control_plane: &dyn ControlPlane,
+) {
+    let flush = Duration::from_millis(settings.flush_millis);
+    while let Some(chunk) = output.recv().await {
+        let deadline = tokio::time::Instant::now() + flush;
+        let mut batch_bytes = chunk.data.len();
+        let mut batch = vec![chunk];
+        while batch_bytes < settings.max_batch_bytes {
+            match tokio::time::timeout_at(deadline, output.recv()).await {
+                Ok(Some(chunk)) => {
+                    batch_bytes += chunk.data.len();
+                    batch.push(chunk);
+                }
+                _ => break,
+            }
+        }
+
+        if let Err(e) = control_plane.upload_output(command, &batch).await {
+            warn!(
+                "output upload for command {} failed: {}",
+                command.get_id(),
+                e
+            );
+        }
+    }
+}
+
+/// returns early (with false) once shutdown is requested
+pub async fn sleep_for(delay: Duration, shutdown: &Shutdown) -> bool {
+    info!("sleeping for {:?}...", delay);
+    shutdown.sleep(delay).await
 }
 
 fn handle_err(err: HandlerError) {
@@ -126,3 +504,599 @@ fn handle_err(err: HandlerError) {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use crate::{
+        api::control_plane::{ControlPlane, InMemoryControlPlane},
+        config::DaemonConfig,
+        handlers::HandlerRegistry,
+        journal::Journal,
+        models::db::{
+            commands::{Command, CommandNames, CommandStatus},
+            common::HasId,
+            output::OutputStream,
+        },
+        outbox::{Outbox, OutboxMessage},
+        shutdown::{self, EXIT_OK},
+        test_commons::before_each,
+        updater::{test_release, Updater},
+        verification::test_keys,
+    };
+
+    fn shell_command(args: &str) -> Command {
+        let mut command = Command::default();
+        command.name = CommandNames::ShellCmd;
+        command.args = Some(args.to_string());
+        command
+    }
+
+    /// commands in these tests are unsigned unless a test sets a key
+    fn test_config() -> DaemonConfig {
+        let mut config = DaemonConfig::default();
+        config.security.allow_unsigned_commands = true;
+        config
+    }
+
+    /// runs the loop until `done` holds for the recorded statuses, then shuts it down
+    async fn run_until(
+        control_plane: Arc<InMemoryControlPlane>,
+        done: impl Fn(&[CommandStatus]) -> bool + Send + 'static,
+    ) -> std::process::ExitCode {
+        run_with_config_until(test_config(), control_plane, done).await
+    }
+
+    async fn run_with_config_until(
+        mut config: DaemonConfig,
+        control_plane: Arc<InMemoryControlPlane>,
+        done: impl Fn(&[CommandStatus]) -> bool + Send + 'static,
+    ) -> std::process::ExitCode {
+        let dir = TempDir::new("test-loop-outbox").unwrap();
+        if config.outbox.path.is_none() {
+            config.outbox.path = Some(dir.path().join("outbox.json").display().to_string());
+        }
+        if config.journal.path.is_none() {
+            config.journal.path = Some(dir.path().join("journal.json").display().to_string());
+        }
+        // keeps the update state in a temp dir too
+        if config.localstore.path == DaemonConfig::default().localstore.path {
+            config.localstore.path = dir.path().join("localstore.json").display().to_string();
+        }
+        let (trigger, shutdown) = shutdown::channel();
+        let plane: Arc<dyn ControlPlane> = control_plane.clone();
+        let updater = Arc::new(Updater::new(&config, trigger.clone()).unwrap());
+        updater.connect(plane.clone());
+        let handlers = HandlerRegistry::with_updater(Some(&updater));
+        let watcher = {
+            let control_plane = control_plane.clone();
+            tokio::spawn(async move {
+                for _ in 0..500 {
+                    let statuses: Vec<CommandStatus> = control_plane
+                        .statuses()
+                        .into_iter()
+                        .map(|(_, s)| s)
+                        .collect();
+                    if done(&statuses) {
+                        break;
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                trigger.trigger();
+            })
+        };
+
+        let device_id = "testdeviceid".to_string();
+        let user_id = "testuserid".to_string();
+        let code = super::run_main_event_loop(
+            &config,
+            plane,
+            &device_id,
+            &user_id,
+            &shutdown,
+            Some(updater),
+            handlers,
+        )
+        .await;
+        watcher.await.unwrap();
+        code
+    }
+
+    #[tokio::test]
+    async fn test_command_status_transitions() {
+        before_each();
+
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.push_command(shell_command("echo hi"));
+
+        let code = run_until(control_plane.clone(), |statuses| statuses.len() >= 3).await;
+
+        let statuses: Vec<CommandStatus> = control_plane
+            .statuses()
+            .into_iter()
+            .map(|(_, s)| s)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                CommandStatus::Received,
+                CommandStatus::Running,
+                CommandStatus::Terminated
+            ]
+        );
+        let results = control_plane.results();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1.stdout.as_deref(), Some("hi\n"));
+        assert_eq!(code, std::process::ExitCode::from(EXIT_OK));
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_command_fails() {
+        before_each();
+
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        let mut command = Command::default();
+        command.name = CommandNames::Unknown("Reboot".to_string());
+        control_plane.push_command(command);
+
+        run_until(control_plane.clone(), |statuses| statuses.len() >= 3).await;
+
+        let statuses: Vec<CommandStatus> = control_plane
+            .statuses()
+            .into_iter()
+            .map(|(_, s)| s)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                CommandStatus::Received,
+                CommandStatus::Running,
+                CommandStatus::Failed
+            ]
+        );
+        let results = control_plane.results();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].1.error.as_deref(),
+            Some("unsupported command \"Reboot\"")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_outbox_left_from_last_run_is_delivered() {
+        before_each();
+
+        let dir = TempDir::new("test-loop-outbox").unwrap();
+        let path = dir.path().join("outbox.json");
+        let outbox = Outbox::open(path.clone(), &Default::default());
+        outbox.push(OutboxMessage::Status {
+            command: shell_command("echo hi"),
+            status: CommandStatus::Terminated,
+        });
+        drop(outbox);
+        let mut config = test_config();
+        config.outbox.path = Some(path.display().to_string());
+
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        run_with_config_until(config, control_plane.clone(), |statuses| {
+            !statuses.is_empty()
+        })
+        .await;
+
+        assert_eq!(
+            control_plane.statuses(),
+            vec![("default".to_string(), CommandStatus::Terminated)]
+        );
+        assert_eq!(Outbox::open(path, &Default::default()).len(), 0);
+    }
+
+    fn with_id(command: Command, id: &str, idempotent: bool) -> Command {
+        let mut value = serde_json::to_value(command).unwrap();
+        value["_id"] = id.into();
+        value["idempotent"] = idempotent.into();
+        serde_json::from_value(value).unwrap()
+    }
+
+    /// a config whose journal already holds `commands`, started but not finished
+    fn config_with_running(dir: &TempDir, commands: &[Command]) -> DaemonConfig {
+        let path = dir.path().join("journal.json");
+        let journal = Journal::open(path.clone(), Duration::from_secs(3600));
+        for command in commands {
+            journal.begin(command);
+            journal.start(command.get_id());
+        }
+        let mut config = test_config();
+        config.journal.path = Some(path.display().to_string());
+        config
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_command_is_reported_failed() {
+        before_each();
+
+        let dir = TempDir::new("test-loop-journal").unwrap();
+        let command = with_id(shell_command("echo hi"), "interrupted", false);
+        let config = config_with_running(&dir, std::slice::from_ref(&command));
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        // the server still thinks it is pending and hands it out again
+        control_plane.push_command(command);
+
+        run_with_config_until(config, control_plane.clone(), |statuses| {
+            !statuses.is_empty()
+        })
+        .await;
+
+        assert_eq!(
+            control_plane.statuses(),
+            vec![("interrupted".to_string(), CommandStatus::Failed)]
+        );
+        let results = control_plane.results();
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].1.error.as_deref(),
+            Some("interrupted by a daemon restart")
+        );
+        assert_eq!(results[0].1.stdout, None);
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_command_is_resumed() {
+        before_each();
+
+        let dir = TempDir::new("test-loop-journal").unwrap();
+        let command = with_id(shell_command("echo again"), "idempotent", true);
+        let config = config_with_running(&dir, &[command]);
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+
+        run_with_config_until(config, control_plane.clone(), |statuses| {
+            statuses.contains(&CommandStatus::Terminated)
+        })
+        .await;
+
+        let statuses: Vec<CommandStatus> = control_plane
+            .statuses()
+            .into_iter()
+            .map(|(_, s)| s)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![CommandStatus::Running, CommandStatus::Terminated]
+        );
+        assert_eq!(
+            control_plane.results()[0].1.stdout.as_deref(),
+            Some("again\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_command_id_is_never_run_twice() {
+        before_each();
+
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.push_command(with_id(shell_command("echo once"), "twice", false));
+        control_plane.push_command(with_id(shell_command("echo once"), "twice", false));
+        control_plane.push_command(with_id(shell_command("echo other"), "other", false));
+
+        run_until(control_plane.clone(), |statuses| {
+            statuses
+                .iter()
+                .filter(|s| **s == CommandStatus::Terminated)
+                .count()
+                >= 2
+        })
+        .await;
+
+        let results = control_plane.results();
+        assert_eq!(results.len(), 2);
+        let ids: Vec<&str> = results.iter().map(|(id, _)| id.as_str()).collect();
+        assert!(ids.contains(&"twice") && ids.contains(&"other"));
+    }
+
+    #[tokio::test]
+    async fn test_failing_command_is_reported_failed() {
+        before_each();
+
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.push_command(shell_command("exit 1"));
+
+        run_until(control_plane.clone(), |statuses| statuses.len() >= 3).await;
+
+        let statuses = control_plane.statuses();
+        assert_eq!(statuses.last().unwrap().1, CommandStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn test_unverified_command_is_blocked() {
+        before_each();
+
+        let (key, public_key) = test_keys::generate();
+        let mut config = test_config();
+        config.security.command_public_key = Some(public_key);
+
+        let mut signed = shell_command("echo signed");
+        signed.device_id = "testdeviceid".to_string();
+        // a distinct id so the pool does not treat it as the unsigned command still in flight
+        let mut value = serde_json::to_value(&signed).unwrap();
+        value["_id"] = "signedid".into();
+        let mut signed: Command = serde_json::from_value(value).unwrap();
+        test_keys::sign(&key, &mut signed);
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.push_command(shell_command("echo unsigned"));
+        control_plane.push_command(signed);
+
+        run_with_config_until(config, control_plane.clone(), |statuses| {
+            statuses.len() >= 6
+        })
+        .await;
+
+        let statuses = control_plane.statuses();
+        assert!(statuses.contains(&("default".to_string(), CommandStatus::Blocked)));
+        assert!(statuses.contains(&("signedid".to_string(), CommandStatus::Terminated)));
+        let results = control_plane.results();
+        assert_eq!(results.len(), 2);
+        let result = |id: &str| {
+            results
+                .iter()
+                .find(|(command_id, _)| command_id == id)
+                .map(|(_, result)| result.clone())
+                .unwrap()
+        };
+        assert_eq!(result("signedid").stdout.as_deref(), Some("signed\n"));
+        let refused = result("default");
+        assert_eq!(
+            refused.error.as_deref(),
+            Some("invalid signature: command is not signed")
+        );
+        assert!(refused.stdout.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_policy_denied_command_is_blocked_with_rule() {
+        before_each();
+
+        let dir = TempDir::new("test-loop-policy").unwrap();
+        let path = dir.path().join("policy.toml");
+        std::fs::write(&path, "default = \"deny\"").unwrap();
+        let mut config = test_config();
+        config.policy.path = Some(path.display().to_string());
+
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.push_command(shell_command("echo hi"));
+
+        run_with_config_until(config, control_plane.clone(), |statuses| {
+            statuses.len() >= 3
+        })
+        .await;
+
+        let statuses = control_plane.statuses();
+        assert_eq!(statuses.last().unwrap().1, CommandStatus::Blocked);
+        let results = control_plane.results();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1.policy_rule.as_deref(), Some("default"));
+    }
+
+    #[tokio::test]
+    async fn test_output_is_uploaded_before_result() {
+        before_each();
+
+        let mut config = test_config();
+        config.output.flush_millis = 50;
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.push_command(shell_command("echo one; sleep 0.3; echo two 1>&2"));
+
+        run_with_config_until(config, control_plane.clone(), |statuses| {
+            statuses.len() >= 3
+        })
+        .await;
+
+        let batches = control_plane.output();
+        // the sleep outlasts the flush interval, so the two lines go out separately
+        assert!(batches.len() >= 2);
+        let chunks: Vec<_> = batches.into_iter().flat_map(|(_, chunks)| chunks).collect();
+        let seqs: Vec<u64> = chunks.iter().map(|chunk| chunk.seq).collect();
+        assert_eq!(seqs, (0..chunks.len() as u64).collect::<Vec<_>>());
+        assert_eq!(chunks[0].stream, OutputStream::Stdout);
+        assert_eq!(chunks[0].data, "one\n");
+        assert_eq!(chunks.last().unwrap().stream, OutputStream::Stderr);
+        assert_eq!(chunks.last().unwrap().data, "two\n");
+        assert_eq!(control_plane.results().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_command_is_terminated() {
+        before_each();
+
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.push_command(shell_command("sleep 30"));
+        let canceller = {
+            let control_plane = control_plane.clone();
+            tokio::spawn(async move {
+                while !control_plane
+                    .statuses()
+                    .iter()
+                    .any(|(_, status)| *status == CommandStatus::Running)
+                {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                control_plane.cancel_command("default");
+            })
+        };
+
+        let code = run_until(control_plane.clone(), |statuses| statuses.len() >= 3).await;
+        canceller.await.unwrap();
+
+        assert_eq!(
+            control_plane.statuses().last().unwrap().1,
+            CommandStatus::Cancelled
+        );
+        assert!(control_plane.results()[0].1.cancelled);
+        assert_eq!(code, std::process::ExitCode::from(EXIT_OK));
+    }
+
+    #[tokio::test]
+    async fn test_large_result_is_truncated_for_upload() {
+        before_each();
+
+        let mut config = test_config();
+        config.result.max_output_bytes = 10;
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.push_command(shell_command("printf 0123456789abcdef"));
+
+        run_with_config_until(config, control_plane.clone(), |statuses| {
+            statuses.len() >= 3
+        })
+        .await;
+
+        let results = control_plane.results();
+        assert_eq!(
+            results[0].1.stdout.as_deref(),
+            Some("0123456789\n[truncated 6 of 16 bytes]")
+        );
+        assert_eq!(
+            control_plane.statuses().last().unwrap().1,
+            CommandStatus::Terminated
+        );
+    }
+
+    #[tokio::test]
+    async fn test_output_is_not_streamed_when_disabled() {
+        before_each();
+
+        let mut config = test_config();
+        config.output.stream = false;
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.push_command(shell_command("echo hi"));
+
+        run_with_config_until(config, control_plane.clone(), |statuses| {
+            statuses.len() >= 3
+        })
+        .await;
+
+        assert!(control_plane.output().is_empty());
+        assert_eq!(control_plane.results()[0].1.stdout.as_deref(), Some("hi\n"));
+    }
+
+    fn update_config(dir: &TempDir, public_key: String) -> DaemonConfig {
+        let binary = dir.path().join("itx-daemon");
+        std::fs::write(&binary, "old").unwrap();
+        let mut config = test_config();
+        config.update.public_key = Some(public_key);
+        config.update.binary_path = Some(binary.display().to_string());
+        config.localstore.path = dir.path().join("localstore.json").display().to_string();
+        config
+    }
+
+    fn update_command(args: String) -> Command {
+        let mut command = Command::default();
+        command.name = CommandNames::Update;
+        command.args = Some(args);
+        command
+    }
+
+    #[tokio::test]
+    async fn test_advertises_the_executor_handlers() {
+        before_each();
+
+        let advertised = |config: DaemonConfig| async move {
+            let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+            control_plane.push_command(shell_command("echo hi"));
+            run_with_config_until(config, control_plane.clone(), |statuses| {
+                statuses.len() >= 3
+            })
+            .await;
+            let names: Vec<String> = control_plane.advertisements()[0]
+                .commands
+                .iter()
+                .map(|info| info.name.clone())
+                .collect();
+            names
+        };
+
+        assert_eq!(advertised(test_config()).await, vec!["ShellCmd", "Test"]);
+        let dir = TempDir::new("test-loop-update").unwrap();
+        let (_, public_key) = test_keys::generate();
+        assert_eq!(
+            advertised(update_config(&dir, public_key)).await,
+            vec!["ShellCmd", "Test", "Update"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_is_installed_and_restarted_into() {
+        before_each();
+
+        let dir = TempDir::new("test-loop-update").unwrap();
+        let (key, public_key) = test_keys::generate();
+        let config = update_config(&dir, public_key);
+        let manifest = test_release::manifest(&key, "99.0.0", b"new");
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.publish_release("99.0.0", b"new");
+        control_plane.push_command(update_command(serde_json::to_string(&manifest).unwrap()));
+
+        let code = run_with_config_until(config, control_plane.clone(), |statuses| {
+            statuses.len() >= 2
+        })
+        .await;
+
+        assert_eq!(code, std::process::ExitCode::from(EXIT_OK));
+        let binary = dir.path().join("itx-daemon");
+        assert_eq!(std::fs::read(&binary).unwrap(), b"new");
+        assert_eq!(
+            std::fs::read(dir.path().join("itx-daemon.previous")).unwrap(),
+            b"old"
+        );
+        // the outcome is reported by the version the daemon restarts into
+        let statuses: Vec<CommandStatus> = control_plane
+            .statuses()
+            .into_iter()
+            .map(|(_, s)| s)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![CommandStatus::Received, CommandStatus::Running]
+        );
+        assert!(control_plane.results().is_empty());
+        let state = std::fs::read(dir.path().join("update.json")).unwrap();
+        let state: serde_json::Value = serde_json::from_slice(&state).unwrap();
+        assert_eq!(state["phase"], "staged");
+        assert_eq!(state["to_version"], "99.0.0");
+    }
+
+    #[tokio::test]
+    async fn test_update_with_bad_checksum_is_reported_failed() {
+        before_each();
+
+        let dir = TempDir::new("test-loop-update").unwrap();
+        let (key, public_key) = test_keys::generate();
+        let config = update_config(&dir, public_key);
+        let manifest = test_release::manifest(&key, "99.0.0", b"new");
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.publish_release("99.0.0", b"tampered");
+        control_plane.push_command(update_command(serde_json::to_string(&manifest).unwrap()));
+
+        run_with_config_until(config, control_plane.clone(), |statuses| {
+            statuses.len() >= 3
+        })
+        .await;
+
+        assert_eq!(
+            control_plane.statuses().last().unwrap().1,
+            CommandStatus::Failed
+        );
+        let results = control_plane.results();
+        assert!(results[0]
+            .1
+            .error
+            .as_deref()
+            .unwrap()
+            .contains("checksum mismatch"));
+        assert_eq!(
+            std::fs::read(dir.path().join("itx-daemon")).unwrap(),
+            b"old"
+        );
+    }
+}
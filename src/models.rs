@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use thiserror::Error;
 
 use self::db::common::Id;
@@ -28,6 +30,64 @@ pub enum HandlerError {
     ServerError,
     #[error("input error 4XX")]
     InputError,
+    #[error("config error: {0}")]
+    ConfigError(String),
+    #[error("websocket error")]
+    WebSocketError(#[from] Box<tokio_tungstenite::tungstenite::Error>),
+    #[error("transport not connected")]
+    NotConnected,
+    #[error("unauthorized 401")]
+    Unauthorized,
+    #[error("crypto error")]
+    CryptoError(#[from] openssl::error::ErrorStack),
+    #[error("invalid signature: {0}")]
+    InvalidSignature(String),
+    #[error("invalid certificate: {0}")]
+    InvalidCertificate(String),
+    #[error("update failed: {0}")]
+    UpdateFailed(String),
+    #[error("no handler for command {0}")]
+    UnsupportedCommand(String),
+    #[error("denied by policy rule {0}")]
+    PolicyDenied(String),
+    #[error("server busy 429/503, retry after {0:?}")]
+    Throttled(Option<Duration>),
+    #[error("circuit open, no api calls for {0:?}")]
+    CircuitOpen(Duration),
+}
+
+impl HandlerError {
+    /// the server is down or overloaded (or unreachable), so the same call may succeed later
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            HandlerError::ServerError
+                | HandlerError::ApiError
+                | HandlerError::Throttled(_)
+                | HandlerError::ReqwestError(_)
+        )
+    }
+
+    /// the server refused the call itself (a 400/422 or 404), so repeating it cannot
+    /// succeed. anything else, local or on the way there, may clear up on its own
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, HandlerError::InputError | HandlerError::NotFound)
+    }
+
+    /// how long the server (or the circuit breaker) asked to wait before the next call
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            HandlerError::Throttled(after) => *after,
+            HandlerError::CircuitOpen(after) => Some(*after),
+            _ => None,
+        }
+    }
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for HandlerError {
+    fn from(err: tokio_tungstenite::tungstenite::Error) -> Self {
+        HandlerError::WebSocketError(Box::new(err))
+    }
 }
 
 pub mod db {
@@ -46,14 +106,33 @@ pub mod db {
         use super::common::{HasId, Id};
         use serde::{Deserialize, Serialize};
 
-        #[derive(Serialize, Deserialize, Debug)]
+        /// fields the server leaves out take their defaults and ones it adds are ignored;
+        /// only the id is required
+        #[derive(Serialize, Deserialize, Debug, Clone)]
         pub struct Command {
+            #[serde(default)]
             pub status: CommandStatus,
+            #[serde(default)]
             pub args: Option<String>,
+            #[serde(default)]
             pub name: CommandNames,
+            #[serde(default)]
             pub issuer_id: Id,
+            #[serde(default)]
             pub device_id: Id,
             _id: Id,
+            /// overrides the configured executor default when set
+            #[serde(default)]
+            pub timeout_secs: Option<u64>,
+            /// server ed25519 signature, see `verification::CommandVerifier`
+            #[serde(default)]
+            pub signature: Option<String>,
+            /// run in the sandbox even when `sandbox.enabled` is off
+            #[serde(default)]
+            pub sandbox: bool,
+            /// safe to run again from the start if the daemon died while running it
+            #[serde(default)]
+            pub idempotent: bool,
         }
 
         impl Default for Command {
@@ -65,6 +144,10 @@ pub mod db {
                     issuer_id: "default".to_string(),
                     device_id: "default".to_string(),
                     _id: "default".to_string(),
+                    timeout_secs: None,
+                    signature: None,
+                    sandbox: false,
+                    idempotent: false,
                 }
             }
         }
@@ -75,14 +158,57 @@ pub mod db {
             }
         }
 
-        #[derive(Serialize, Deserialize, Debug)]
+        /// (de)serialized as the bare name; names this daemon does not know are kept as
+        /// `Unknown` so the command can still be reported back as unsupported
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+        #[serde(from = "String", into = "String")]
         pub enum CommandNames {
             Update,
             Test,
             ShellCmd,
+            Unknown(String),
         }
 
-        #[derive(Serialize, Deserialize, Debug)]
+        impl CommandNames {
+            pub fn as_str(&self) -> &str {
+                match self {
+                    CommandNames::Update => "Update",
+                    CommandNames::Test => "Test",
+                    CommandNames::ShellCmd => "ShellCmd",
+                    CommandNames::Unknown(name) => name,
+                }
+            }
+        }
+
+        impl Default for CommandNames {
+            fn default() -> Self {
+                Self::Unknown(String::new())
+            }
+        }
+
+        impl From<String> for CommandNames {
+            fn from(name: String) -> Self {
+                match name.as_str() {
+                    "Update" => CommandNames::Update,
+                    "Test" => CommandNames::Test,
+                    "ShellCmd" => CommandNames::ShellCmd,
+                    _ => CommandNames::Unknown(name),
+                }
+            }
+        }
+
+        impl From<CommandNames> for String {
+            fn from(name: CommandNames) -> Self {
+                match name {
+                    CommandNames::Unknown(name) => name,
+                    known => known.as_str().to_string(),
+                }
+            }
+        }
+
+        /// like `CommandNames`, a status this daemon does not know is kept as `Unknown`
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+        #[serde(from = "String", into = "String")]
         pub enum CommandStatus {
             Running,
             Blocked,
@@ -92,6 +218,55 @@ pub mod db {
             Pending,
             Sent,
             Received,
+            TimedOut,
+            /// stopped on the server's request
+            Cancelled,
+            Unknown(String),
+        }
+
+        impl CommandStatus {
+            pub fn as_str(&self) -> &str {
+                match self {
+                    CommandStatus::Running => "Running",
+                    CommandStatus::Blocked => "Blocked",
+                    CommandStatus::Terminated => "Terminated",
+                    CommandStatus::Failed => "Failed",
+                    CommandStatus::Ready => "Ready",
+                    CommandStatus::Pending => "Pending",
+                    CommandStatus::Sent => "Sent",
+                    CommandStatus::Received => "Received",
+                    CommandStatus::TimedOut => "TimedOut",
+                    CommandStatus::Cancelled => "Cancelled",
+                    CommandStatus::Unknown(status) => status,
+                }
+            }
+        }
+
+        impl From<String> for CommandStatus {
+            fn from(status: String) -> Self {
+                match status.as_str() {
+                    "Running" => CommandStatus::Running,
+                    "Blocked" => CommandStatus::Blocked,
+                    "Terminated" => CommandStatus::Terminated,
+                    "Failed" => CommandStatus::Failed,
+                    "Ready" => CommandStatus::Ready,
+                    "Pending" => CommandStatus::Pending,
+                    "Sent" => CommandStatus::Sent,
+                    "Received" => CommandStatus::Received,
+                    "TimedOut" => CommandStatus::TimedOut,
+                    "Cancelled" => CommandStatus::Cancelled,
+                    _ => CommandStatus::Unknown(status),
+                }
+            }
+        }
+
+        impl From<CommandStatus> for String {
+            fn from(status: CommandStatus) -> Self {
+                match status {
+                    CommandStatus::Unknown(status) => status,
+                    known => known.as_str().to_string(),
+                }
+            }
         }
 
         impl Default for CommandStatus {
@@ -101,6 +276,79 @@ pub mod db {
         }
     }
 
+    pub mod results {
+        use super::commands::CommandStatus;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+        pub struct ExecutionResult {
+            pub stdout: Option<String>,
+            pub stderr: Option<String>,
+            pub exit_code: Option<i32>,
+            pub signal: Option<i32>,
+            pub started_at_ms: u64,
+            pub ended_at_ms: u64,
+            pub duration_ms: u64,
+            #[serde(default)]
+            pub timed_out: bool,
+            #[serde(default)]
+            pub cancelled: bool,
+            /// id of the local policy rule that blocked the command
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub policy_rule: Option<String>,
+            /// why the daemon could not run the command to completion
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub error: Option<String>,
+            /// bytes of stdout not kept once the captured output reached its limit
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub stdout_dropped_bytes: Option<usize>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub stderr_dropped_bytes: Option<usize>,
+        }
+
+        impl ExecutionResult {
+            /// commands without a child process (no exit code) count as successful
+            pub fn is_success(&self) -> bool {
+                !self.timed_out
+                    && !self.cancelled
+                    && self.signal.is_none()
+                    && self.exit_code.is_none_or(|code| code == 0)
+            }
+
+            pub fn status(&self) -> CommandStatus {
+                if self.cancelled {
+                    CommandStatus::Cancelled
+                } else if self.timed_out {
+                    CommandStatus::TimedOut
+                } else if self.is_success() {
+                    CommandStatus::Terminated
+                } else {
+                    CommandStatus::Failed
+                }
+            }
+        }
+    }
+
+    pub mod output {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+        #[serde(rename_all = "lowercase")]
+        pub enum OutputStream {
+            Stdout,
+            Stderr,
+        }
+
+        /// piece of a running command's output; `seq` orders chunks across both streams
+        #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+        pub struct OutputChunk {
+            pub stream: OutputStream,
+            pub seq: u64,
+            pub data: String,
+            pub at_ms: u64,
+        }
+    }
+
     pub mod devices {
         use super::common::{Id, Metadata};
         use serde::{Deserialize, Serialize};
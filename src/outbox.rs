@@ -0,0 +1,460 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::api::control_plane::ControlPlane;
+use crate::api::models::upload_command_result::UploadCommandResultRequest;
+use crate::api::requests::ApiResult;
+use crate::api::retry::RetryPolicy;
+use crate::config::OutboxSettings;
+use crate::executor::now_ms;
+use crate::localstore::write_atomically;
+use crate::models::db::commands::{Command, CommandStatus};
+use crate::models::db::common::{HasId, Id};
+use crate::models::HandlerError;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum OutboxMessage {
+    Status {
+        command: Command,
+        status: CommandStatus,
+    },
+    Result {
+        request: UploadCommandResultRequest,
+    },
+}
+
+impl OutboxMessage {
+    fn command_id(&self) -> &Id {
+        match self {
+            OutboxMessage::Status { command, .. } => command.get_id(),
+            OutboxMessage::Result { request } => &request.command_id,
+        }
+    }
+
+    /// a result counts as its own status for deduplication
+    fn is_duplicate_of(&self, other: &OutboxMessage) -> bool {
+        if self.command_id() != other.command_id() {
+            return false;
+        }
+        match (self, other) {
+            (
+                OutboxMessage::Status { status, .. },
+                OutboxMessage::Status {
+                    status: other_status,
+                    ..
+                },
+            ) => status == other_status,
+            (OutboxMessage::Result { .. }, OutboxMessage::Result { .. }) => true,
+            _ => false,
+        }
+    }
+
+    async fn send(&self, control_plane: &dyn ControlPlane) -> ApiResult<()> {
+        match self {
+            OutboxMessage::Status { command, status } => {
+                control_plane
+                    .update_command_status(command, status.clone())
+                    .await
+            }
+            OutboxMessage::Result { request } => control_plane.upload_command_result(request).await,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    message: OutboxMessage,
+    attempts: u32,
+    next_attempt_ms: u64,
+}
+
+/// a message the server refused for good, kept for inspection
+#[derive(Serialize, Deserialize, Debug)]
+struct DeadLetter {
+    message: OutboxMessage,
+    error: String,
+    at_ms: u64,
+}
+
+/**
+ * persistent queue of status updates and results for the server. messages are delivered
+ * strictly in order by `run`: a failed delivery is retried after a jittered, exponentially
+ * growing delay (see `RetryPolicy`: `outbox.retry_base_millis` doubling up to
+ * `outbox.retry_max_secs`, or longer if the server sent `Retry-After`) and holds back
+ * everything queued behind it. the queue is rewritten to `path` on every change, so whatever
+ * was not delivered before a crash or shutdown goes out after the next start.
+ *
+ * a message the server refuses for good (`HandlerError::is_permanent`, e.g. a 400) would
+ * hold back the queue forever, so it is appended to the dead-letter file next to `path`
+ * (`outbox.dead.json`) instead and delivery carries on with the next one.
+ */
+pub struct Outbox {
+    path: PathBuf,
+    dead_letter_path: PathBuf,
+    policy: RetryPolicy,
+    entries: Mutex<VecDeque<Entry>>,
+    /// number of queued messages, for waking `run` and `flush`
+    pending: watch::Sender<usize>,
+}
+
+impl Outbox {
+    /// loads undelivered messages left at `path`; an unreadable file is set aside
+    pub fn open(path: PathBuf, settings: &OutboxSettings) -> Self {
+        let entries: VecDeque<Entry> = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                let aside = path.with_extension("json.corrupt");
+                error!(
+                    "cannot read outbox {}, moving it to {}: {}",
+                    path.display(),
+                    aside.display(),
+                    e
+                );
+                let _ = std::fs::rename(&path, &aside);
+                VecDeque::new()
+            }),
+            Err(_) => VecDeque::new(),
+        };
+        if !entries.is_empty() {
+            info!("{} undelivered messages in the outbox", entries.len());
+        }
+        let (pending, _) = watch::channel(entries.len());
+        Outbox {
+            dead_letter_path: path.with_extension("dead.json"),
+            path,
+            policy: RetryPolicy {
+                base: Duration::from_millis(settings.retry_base_millis),
+                max: Duration::from_secs(settings.retry_max_secs),
+            },
+            entries: Mutex::new(entries),
+            pending,
+        }
+    }
+
+    /// queues `message` unless the same status (or a result) for its command is already queued
+    pub fn push(&self, message: OutboxMessage) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        if entries
+            .iter()
+            .any(|entry| entry.message.is_duplicate_of(&message))
+        {
+            info!(
+                "outbox already holds {:?} for command {}",
+                &message,
+                message.command_id()
+            );
+            return false;
+        }
+        entries.push_back(Entry {
+            message,
+            attempts: 0,
+            next_attempt_ms: 0,
+        });
+        self.save(&entries);
+        self.pending.send_replace(entries.len());
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// delivers queued messages forever; meant to run on its own task
+    pub async fn run(self: Arc<Self>, control_plane: Arc<dyn ControlPlane>) {
+        let mut pending = self.pending.subscribe();
+        loop {
+            let front = self.entries.lock().unwrap().front().cloned();
+            let entry = match front {
+                Some(entry) => entry,
+                None => {
+                    let _ = pending.wait_for(|pending| *pending > 0).await;
+                    continue;
+                }
+            };
+
+            let wait_ms = entry.next_attempt_ms.saturating_sub(now_ms());
+            if wait_ms > 0 {
+                tokio::time::sleep(Duration::from_millis(wait_ms)).await;
+            }
+            let result = entry.message.send(control_plane.as_ref()).await;
+            self.settle(result);
+        }
+    }
+
+    /// waits until the outbox is empty or `timeout` has passed; true if it emptied
+    pub async fn flush(&self, timeout: Duration) -> bool {
+        let mut pending = self.pending.subscribe();
+        let emptied =
+            tokio::time::timeout(timeout, pending.wait_for(|pending| *pending == 0)).await;
+        emptied.is_ok()
+    }
+
+    /// removes the front entry once delivered (or refused for good), else schedules a retry
+    fn settle(&self, result: ApiResult<()>) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = match entries.front_mut() {
+            Some(entry) => entry,
+            None => return,
+        };
+        match result {
+            Ok(_) => {
+                entries.pop_front();
+            }
+            Err(e) if e.is_permanent() => {
+                error!(
+                    "server refused {:?} for command {}, moving it to {}: {}",
+                    &entry.message,
+                    entry.message.command_id(),
+                    self.dead_letter_path.display(),
+                    e
+                );
+                if let Some(entry) = entries.pop_front() {
+                    self.bury(entry.message, &e);
+                }
+            }
+            Err(e) => {
+                let delay = self.policy.delay(entry.attempts, e.retry_after());
+                entry.attempts += 1;
+                entry.next_attempt_ms = now_ms() + delay.as_millis() as u64;
+                warn!(
+                    "outbox delivery failed (attempt {}), retrying in {:?}: {}",
+                    entry.attempts, delay, e
+                );
+            }
+        }
+        self.save(&entries);
+        self.pending.send_replace(entries.len());
+    }
+
+    /// appends `message` to the dead-letter file; an unreadable one is set aside first
+    fn bury(&self, message: OutboxMessage, error: &HandlerError) {
+        let mut letters: Vec<DeadLetter> = match std::fs::read(&self.dead_letter_path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|_| {
+                let _ = std::fs::rename(
+                    &self.dead_letter_path,
+                    self.dead_letter_path.with_extension("json.corrupt"),
+                );
+                vec![]
+            }),
+            Err(_) => vec![],
+        };
+        letters.push(DeadLetter {
+            message,
+            error: error.to_string(),
+            at_ms: now_ms(),
+        });
+        let result = serde_json::to_vec_pretty(&letters)
+            .map_err(HandlerError::from)
+            .and_then(|data| write_atomically(&self.dead_letter_path, &data));
+        if let Err(e) = result {
+            error!(
+                "cannot save dead letters to {}: {}",
+                self.dead_letter_path.display(),
+                e
+            );
+        }
+    }
+
+    fn save(&self, entries: &VecDeque<Entry>) {
+        let result = serde_json::to_vec_pretty(entries)
+            .map_err(HandlerError::from)
+            .and_then(|data| write_atomically(&self.path, &data));
+        if let Err(e) = result {
+            error!("cannot save outbox to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tempdir::TempDir;
+
+    use crate::{
+        api::control_plane::{ControlPlane, HttpControlPlane, InMemoryControlPlane},
+        config::OutboxSettings,
+        models::{
+            db::commands::{Command, CommandStatus},
+            HandlerError,
+        },
+        test_commons::setup_server,
+    };
+    use tokio_tungstenite::tungstenite;
+
+    use super::{DeadLetter, Outbox, OutboxMessage};
+
+    fn get_settings() -> OutboxSettings {
+        OutboxSettings {
+            retry_base_millis: 10,
+            retry_max_secs: 1,
+            ..Default::default()
+        }
+    }
+
+    fn status(status: CommandStatus) -> OutboxMessage {
+        OutboxMessage::Status {
+            command: Command::default(),
+            status,
+        }
+    }
+
+    #[test]
+    fn test_messages_survive_reopen() {
+        let dir = TempDir::new("test-outbox").unwrap();
+        let path = dir.path().join("outbox.json");
+
+        let outbox = Outbox::open(path.clone(), &get_settings());
+        assert!(outbox.push(status(CommandStatus::Running)));
+        assert!(outbox.push(status(CommandStatus::Terminated)));
+        drop(outbox);
+
+        let outbox = Outbox::open(path, &get_settings());
+        let entries = outbox.entries.lock().unwrap();
+        let statuses: Vec<_> = entries
+            .iter()
+            .map(|entry| match &entry.message {
+                OutboxMessage::Status { status, .. } => status.clone(),
+                other => panic!("unexpected message {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![CommandStatus::Running, CommandStatus::Terminated]
+        );
+    }
+
+    #[test]
+    fn test_duplicate_status_is_dropped() {
+        let dir = TempDir::new("test-outbox").unwrap();
+        let outbox = Outbox::open(dir.path().join("outbox.json"), &get_settings());
+
+        assert!(outbox.push(status(CommandStatus::Running)));
+        assert!(!outbox.push(status(CommandStatus::Running)));
+        assert!(outbox.push(status(CommandStatus::Failed)));
+        assert_eq!(outbox.len(), 2);
+    }
+
+    #[test]
+    fn test_corrupt_outbox_is_set_aside() {
+        let dir = TempDir::new("test-outbox").unwrap();
+        let path = dir.path().join("outbox.json");
+        std::fs::write(&path, "not json").unwrap();
+
+        let outbox = Outbox::open(path, &get_settings());
+
+        assert_eq!(outbox.len(), 0);
+        assert!(dir.path().join("outbox.json.corrupt").exists());
+    }
+
+    #[test]
+    fn test_retry_delay_doubles_up_to_max() {
+        let dir = TempDir::new("test-outbox").unwrap();
+        let outbox = Outbox::open(dir.path().join("outbox.json"), &get_settings());
+
+        assert_eq!(outbox.policy.ceiling(0), Duration::from_millis(10));
+        assert_eq!(outbox.policy.ceiling(3), Duration::from_millis(80));
+        assert_eq!(outbox.policy.ceiling(20), Duration::from_secs(1));
+        assert_eq!(outbox.policy.ceiling(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_delivers_in_order_after_failures() {
+        let dir = TempDir::new("test-outbox").unwrap();
+        let path = dir.path().join("outbox.json");
+        let outbox = Arc::new(Outbox::open(path.clone(), &get_settings()));
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        control_plane.fail_next(3);
+
+        outbox.push(status(CommandStatus::Running));
+        outbox.push(status(CommandStatus::Terminated));
+        let plane: Arc<dyn ControlPlane> = control_plane.clone();
+        let runner = tokio::spawn(outbox.clone().run(plane));
+
+        assert!(outbox.flush(Duration::from_secs(5)).await);
+        runner.abort();
+        let statuses: Vec<CommandStatus> = control_plane
+            .statuses()
+            .into_iter()
+            .map(|(_, status)| status)
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![CommandStatus::Running, CommandStatus::Terminated]
+        );
+        assert_eq!(std::fs::read_to_string(path).unwrap(), "[]");
+    }
+
+    #[test]
+    fn test_local_and_connection_errors_stay_queued() {
+        let dir = TempDir::new("test-outbox").unwrap();
+        let outbox = Outbox::open(dir.path().join("outbox.json"), &get_settings());
+        outbox.push(status(CommandStatus::Running));
+
+        outbox.settle(Err(HandlerError::IoError(std::io::Error::other(
+            "disk full",
+        ))));
+        outbox.settle(Err(HandlerError::WebSocketError(Box::new(
+            tungstenite::Error::ConnectionClosed,
+        ))));
+
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox.entries.lock().unwrap()[0].attempts, 2);
+        assert!(!dir.path().join("outbox.dead.json").exists());
+    }
+
+    #[tokio::test]
+    async fn test_refused_message_is_dead_lettered_and_delivery_continues() {
+        let dir = TempDir::new("test-outbox").unwrap();
+        let outbox = Arc::new(Outbox::open(
+            dir.path().join("outbox.json"),
+            &get_settings(),
+        ));
+        let (mut server, config) = setup_server();
+        let refused = server
+            .mock("PATCH", "/commands/update/status")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"status": "Running"}),
+            ))
+            .with_status(400)
+            .create();
+        let delivered = server
+            .mock("PATCH", "/commands/update/status")
+            .match_body(mockito::Matcher::PartialJson(
+                serde_json::json!({"status": "Terminated"}),
+            ))
+            .with_status(200)
+            .create();
+
+        outbox.push(status(CommandStatus::Running));
+        outbox.push(status(CommandStatus::Terminated));
+        let plane: Arc<dyn ControlPlane> = Arc::new(HttpControlPlane::new(config));
+        let runner = tokio::spawn(outbox.clone().run(plane));
+
+        assert!(outbox.flush(Duration::from_secs(5)).await);
+        runner.abort();
+        refused.assert();
+        delivered.assert();
+        let data = std::fs::read(dir.path().join("outbox.dead.json")).unwrap();
+        let letters: Vec<DeadLetter> = serde_json::from_slice(&data).unwrap();
+        assert_eq!(letters.len(), 1);
+        assert!(matches!(
+            &letters[0].message,
+            OutboxMessage::Status {
+                status: CommandStatus::Running,
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,473 @@
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use log::{error, info};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::executor::now_ms;
+use crate::models::db::commands::Command;
+use crate::models::db::common::Id;
+use crate::models::HandlerError;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+const DAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// outcome of checking a command against the policy
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Allow {
+        rule_id: Option<String>,
+        max_runtime_secs: Option<u64>,
+    },
+    Deny {
+        rule_id: String,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    default: Action,
+    #[serde(default)]
+    rules: Vec<RuleSpec>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct RuleSpec {
+    id: String,
+    action: Action,
+    #[serde(default)]
+    names: Vec<String>,
+    args: Option<String>,
+    #[serde(default)]
+    issuers: Vec<Id>,
+    window: Option<WindowSpec>,
+    max_runtime_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct WindowSpec {
+    #[serde(default)]
+    days: Vec<String>,
+    start: String,
+    end: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Policy {
+    default: Action,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug)]
+struct Rule {
+    id: String,
+    action: Action,
+    names: Vec<String>,
+    args: Option<Regex>,
+    issuers: Vec<Id>,
+    window: Option<TimeWindow>,
+    max_runtime_secs: Option<u64>,
+}
+
+/// minutes since midnight utc; `end` before `start` wraps past midnight
+#[derive(Debug)]
+struct TimeWindow {
+    days: [bool; 7],
+    start: u64,
+    end: u64,
+}
+
+impl Policy {
+    pub fn from_toml_str(data: &str) -> Result<Self, HandlerError> {
+        let file: PolicyFile = toml::from_str(data).map_err(|e| policy_error(&e.to_string()))?;
+        let rules = file
+            .rules
+            .into_iter()
+            .map(Rule::compile)
+            .collect::<Result<_, _>>()?;
+        Ok(Policy {
+            default: file.default,
+            rules,
+        })
+    }
+
+    pub fn evaluate(&self, command: &Command, now_secs: u64) -> Decision {
+        match self
+            .rules
+            .iter()
+            .find(|rule| rule.matches(command, now_secs))
+        {
+            Some(rule) if rule.action == Action::Deny => Decision::Deny {
+                rule_id: rule.id.clone(),
+            },
+            Some(rule) => Decision::Allow {
+                rule_id: Some(rule.id.clone()),
+                max_runtime_secs: rule.max_runtime_secs,
+            },
+            None if self.default == Action::Deny => Decision::Deny {
+                rule_id: "default".to_string(),
+            },
+            None => Decision::Allow {
+                rule_id: None,
+                max_runtime_secs: None,
+            },
+        }
+    }
+}
+
+impl Rule {
+    fn compile(spec: RuleSpec) -> Result<Self, HandlerError> {
+        let args = match &spec.args {
+            Some(pattern) => Some(Regex::new(pattern).map_err(|e| {
+                policy_error(&format!("rule {}: invalid args regex: {}", spec.id, e))
+            })?),
+            None => None,
+        };
+        let window =
+            match &spec.window {
+                Some(window) => Some(TimeWindow::compile(window).map_err(|e| {
+                    policy_error(&format!("rule {}: invalid window: {}", spec.id, e))
+                })?),
+                None => None,
+            };
+        Ok(Rule {
+            id: spec.id,
+            action: spec.action,
+            names: spec.names,
+            args,
+            issuers: spec.issuers,
+            window,
+            max_runtime_secs: spec.max_runtime_secs,
+        })
+    }
+
+    fn matches(&self, command: &Command, now_secs: u64) -> bool {
+        (self.names.is_empty() || self.names.iter().any(|name| name == command.name.as_str()))
+            && self
+                .args
+                .as_ref()
+                .is_none_or(|args| args.is_match(command.args.as_deref().unwrap_or_default()))
+            && (self.issuers.is_empty() || self.issuers.contains(&command.issuer_id))
+            && self
+                .window
+                .as_ref()
+                .is_none_or(|window| window.contains(now_secs))
+    }
+}
+
+impl TimeWindow {
+    fn compile(spec: &WindowSpec) -> Result<Self, String> {
+        let mut days = [spec.days.is_empty(); 7];
+        for day in &spec.days {
+            let prefix = day.to_lowercase().chars().take(3).collect::<String>();
+            match DAY_NAMES.iter().position(|name| *name == prefix) {
+                Some(index) => days[index] = true,
+                None => return Err(format!("unknown day {:?}", day)),
+            }
+        }
+        Ok(TimeWindow {
+            days,
+            start: parse_minutes(&spec.start)?,
+            end: parse_minutes(&spec.end)?,
+        })
+    }
+
+    fn contains(&self, now_secs: u64) -> bool {
+        // the unix epoch was a thursday
+        let weekday = ((now_secs / SECS_PER_DAY + 4) % 7) as usize;
+        let minute = (now_secs % SECS_PER_DAY) / 60;
+        if self.start <= self.end {
+            self.days[weekday] && self.start <= minute && minute < self.end
+        } else if minute >= self.start {
+            self.days[weekday]
+        } else {
+            // early morning part of a window that started the day before
+            self.days[(weekday + 6) % 7] && minute < self.end
+        }
+    }
+}
+
+/// `HH:MM`
+fn parse_minutes(value: &str) -> Result<u64, String> {
+    let parsed = value.split_once(':').and_then(|(hours, minutes)| {
+        let hours: u64 = hours.parse().ok()?;
+        let minutes: u64 = minutes.parse().ok()?;
+        (hours < 24 && minutes < 60).then_some(hours * 60 + minutes)
+    });
+    parsed.ok_or_else(|| format!("expected HH:MM, got {:?}", value))
+}
+
+fn policy_error(reason: &str) -> HandlerError {
+    HandlerError::ConfigError(format!("invalid policy: {}", reason))
+}
+
+/**
+ * device-side command policy, read from the toml file at `policy.path`:
+ *
+ *     default = "allow"            # or "deny" for an allowlist
+ *
+ *     [[rules]]
+ *     id = "no-root-wipe"
+ *     action = "deny"
+ *     names = ["ShellCmd"]         # command names, any if omitted
+ *     args = "rm\\s+-rf\\s+/"      # regex searched in the args
+ *     issuers = ["someissuerid"]   # issuer ids, any if omitted
+ *     window = { days = ["mon", "fri"], start = "09:00", end = "17:00" }  # utc
+ *     max_runtime_secs = 600       # allow rules only: caps the command timeout
+ *
+ * rules are checked in order and the first one whose conditions all hold decides. the file is
+ * re-read whenever its modification time changes; a file that fails to parse is logged and the
+ * previous policy stays in force.
+ */
+#[derive(Default)]
+pub struct PolicyEngine {
+    path: Option<PathBuf>,
+    state: Mutex<LoadedPolicy>,
+}
+
+#[derive(Default)]
+struct LoadedPolicy {
+    policy: Policy,
+    modified: Option<SystemTime>,
+}
+
+impl PolicyEngine {
+    /// without a path every command is allowed
+    pub fn load(path: Option<&str>) -> Result<Self, HandlerError> {
+        let path = path.map(PathBuf::from);
+        let state = match &path {
+            Some(path) => {
+                let modified = modified_at(path);
+                let policy = read_policy(path)?;
+                info!("loaded command policy from {}", path.display());
+                LoadedPolicy { policy, modified }
+            }
+            None => LoadedPolicy::default(),
+        };
+        Ok(PolicyEngine {
+            path,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn evaluate(&self, command: &Command) -> Decision {
+        let mut state = self.state.lock().unwrap();
+        if let Some(path) = &self.path {
+            reload_if_changed(path, &mut state);
+        }
+        state.policy.evaluate(command, now_ms() / 1000)
+    }
+}
+
+fn reload_if_changed(path: &Path, state: &mut LoadedPolicy) {
+    let modified = modified_at(path);
+    if modified == state.modified {
+        return;
+    }
+    state.modified = modified;
+    match read_policy(path) {
+        Ok(policy) => {
+            info!("reloaded command policy from {}", path.display());
+            state.policy = policy;
+        }
+        Err(e) => error!("keeping the previous command policy: {}", e),
+    }
+}
+
+fn read_policy(path: &Path) -> Result<Policy, HandlerError> {
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| policy_error(&format!("cannot read {}: {}", path.display(), e)))?;
+    Policy::from_toml_str(&data)
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use tempdir::TempDir;
+
+    use crate::models::db::commands::{Command, CommandNames};
+
+    use super::{Decision, Policy, PolicyEngine};
+
+    // 2024-01-01 was a monday
+    const MONDAY_NOON: u64 = 1_704_110_400;
+
+    fn shell_command(args: &str) -> Command {
+        let mut command = Command::default();
+        command.name = CommandNames::ShellCmd;
+        command.args = Some(args.to_string());
+        command
+    }
+
+    fn deny(rule_id: &str) -> Decision {
+        Decision::Deny {
+            rule_id: rule_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let policy = Policy::from_toml_str(
+            r#"
+            [[rules]]
+            id = "allow-ls"
+            action = "allow"
+            args = "^ls( |$)"
+            max_runtime_secs = 10
+
+            [[rules]]
+            id = "no-shell"
+            action = "deny"
+            names = ["ShellCmd"]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            policy.evaluate(&shell_command("ls -la"), MONDAY_NOON),
+            Decision::Allow {
+                rule_id: Some("allow-ls".to_string()),
+                max_runtime_secs: Some(10)
+            }
+        );
+        assert_eq!(
+            policy.evaluate(&shell_command("rm -rf /"), MONDAY_NOON),
+            deny("no-shell")
+        );
+        assert!(matches!(
+            policy.evaluate(&Command::default(), MONDAY_NOON),
+            Decision::Allow { rule_id: None, .. }
+        ));
+    }
+
+    #[test]
+    fn test_default_deny_and_issuers() {
+        let policy = Policy::from_toml_str(
+            r#"
+            default = "deny"
+
+            [[rules]]
+            id = "trusted"
+            action = "allow"
+            issuers = ["trustedissuer"]
+            "#,
+        )
+        .unwrap();
+
+        let mut command = shell_command("uptime");
+        assert_eq!(policy.evaluate(&command, MONDAY_NOON), deny("default"));
+
+        command.issuer_id = "trustedissuer".to_string();
+        assert!(matches!(
+            policy.evaluate(&command, MONDAY_NOON),
+            Decision::Allow { .. }
+        ));
+    }
+
+    #[test]
+    fn test_time_windows() {
+        let policy = Policy::from_toml_str(
+            r#"
+            [[rules]]
+            id = "office-hours"
+            action = "deny"
+            window = { days = ["mon", "tue"], start = "09:00", end = "17:00" }
+
+            [[rules]]
+            id = "overnight"
+            action = "deny"
+            window = { days = ["sat"], start = "22:00", end = "02:00" }
+            "#,
+        )
+        .unwrap();
+        let command = shell_command("uptime");
+        let hour = 60 * 60;
+
+        assert_eq!(policy.evaluate(&command, MONDAY_NOON), deny("office-hours"));
+        assert!(matches!(
+            policy.evaluate(&command, MONDAY_NOON + 6 * hour),
+            Decision::Allow { .. }
+        ));
+        // wednesday noon
+        assert!(matches!(
+            policy.evaluate(&command, MONDAY_NOON + 48 * hour),
+            Decision::Allow { .. }
+        ));
+        // saturday 23:00 and sunday 01:00
+        assert_eq!(
+            policy.evaluate(&command, MONDAY_NOON + 5 * 24 * hour + 11 * hour),
+            deny("overnight")
+        );
+        assert_eq!(
+            policy.evaluate(&command, MONDAY_NOON + 6 * 24 * hour - 11 * hour),
+            deny("overnight")
+        );
+    }
+
+    #[test]
+    fn test_invalid_policies_are_rejected() {
+        for data in [
+            "[[rules]]\nid = \"x\"\naction = \"maybe\"",
+            "[[rules]]\nid = \"x\"\naction = \"deny\"\nargs = \"(\"",
+            "[[rules]]\nid = \"x\"\naction = \"deny\"\nwindow = { start = \"25:00\", end = \"01:00\" }",
+            "[[rules]]\nid = \"x\"\naction = \"deny\"\nwindow = { days = [\"someday\"], start = \"01:00\", end = \"02:00\" }",
+            "unknown = 1",
+        ] {
+            assert!(Policy::from_toml_str(data).is_err(), "{}", data);
+        }
+    }
+
+    #[test]
+    fn test_engine_reloads_changed_file() {
+        let dir = TempDir::new("test-policy").unwrap();
+        let path = dir.path().join("policy.toml");
+        std::fs::write(&path, "default = \"allow\"").unwrap();
+        let engine = PolicyEngine::load(Some(path.to_str().unwrap())).unwrap();
+        let command = shell_command("uptime");
+
+        assert!(matches!(engine.evaluate(&command), Decision::Allow { .. }));
+
+        std::fs::write(&path, "default = \"deny\"").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(engine.evaluate(&command), deny("default"));
+
+        // a broken edit keeps the last good policy
+        std::fs::write(&path, "default = ").unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(engine.evaluate(&command), deny("default"));
+    }
+
+    #[test]
+    fn test_engine_without_path_allows_everything() {
+        let engine = PolicyEngine::load(None).unwrap();
+
+        assert!(matches!(
+            engine.evaluate(&shell_command("rm -rf /")),
+            Decision::Allow { .. }
+        ));
+    }
+}
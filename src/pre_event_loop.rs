@@ -1,7 +1,11 @@
-use log::info;
+use log::{error, info};
 
 use crate::{
-    api::{self, requests::ApiConfig},
+    api::auth::CredentialStore,
+    api::control_plane::ControlPlane,
+    api::identity::DeviceIdentity,
+    api::models::capabilities::DeviceCapabilities,
+    config::get_config,
     localstore::{query_data, write_single},
     models::{db::common::Id, HandlerError},
 };
@@ -12,7 +16,7 @@ use crate::{
  * 2. test connection to server
  */
 pub fn get_device_name() -> String {
-    "testnewdevicefelipe".to_string()
+    get_config().device.name.clone()
 }
 
 pub fn get_user_id() -> Result<Id, HandlerError> {
@@ -32,16 +36,28 @@ pub fn get_user_secret() -> Result<String, HandlerError> {
     Ok(user_secret)
 }
 
-async fn get_device_id_inner(
+/// `store` keeps the credential and signing secret the server issues at registration
+pub async fn get_device_id(
     user_id: &Id,
     user_secret: &str,
-    config: ApiConfig,
+    control_plane: &dyn ControlPlane,
+    store: &dyn CredentialStore,
+    identity: Option<&DeviceIdentity>,
+    capabilities: Option<&DeviceCapabilities>,
 ) -> Result<Id, HandlerError> {
     // get device id or register it if not set
     let device_id_key = "device_id";
     let device_id_resp = query_data(device_id_key);
     let device_id = if device_id_resp.is_err() {
-        let received_id = register_device_inner(user_id, user_secret, config).await?;
+        let received_id = register_device_inner(
+            user_id,
+            user_secret,
+            control_plane,
+            store,
+            identity,
+            capabilities,
+        )
+        .await?;
         info!("received device id from call and storing: {}", &received_id);
         write_single(&received_id, device_id_key)?;
         info!("stored device id: {}", &received_id);
@@ -54,22 +70,44 @@ async fn get_device_id_inner(
     Ok(device_id)
 }
 
-pub async fn get_device_id(user_id: &Id, user_secret: &str) -> Result<Id, HandlerError> {
-    get_device_id_inner(user_id, user_secret, ApiConfig::default()).await
-}
-
 async fn register_device_inner(
     user_id: &Id,
     user_secret: &str,
-    config: ApiConfig,
+    control_plane: &dyn ControlPlane,
+    store: &dyn CredentialStore,
+    identity: Option<&DeviceIdentity>,
+    capabilities: Option<&DeviceCapabilities>,
 ) -> Result<Id, HandlerError> {
     let device_name = get_device_name();
     info!("registering device with name: {}", device_name);
-    Ok(
-        api::requests::register_device::register_device(user_id, user_secret, device_name, &config)
-            .await?
-            .device_id,
-    )
+    let csr = identity
+        .map(|identity| identity.csr(&device_name))
+        .transpose()?;
+    let response = control_plane
+        .register_device(
+            user_id,
+            user_secret,
+            device_name,
+            csr,
+            capabilities.cloned(),
+        )
+        .await?;
+    if let Some(credential) = &response.credential {
+        info!("storing device credential from registration");
+        store.save(credential)?;
+    }
+    if let Some(secret) = &response.signing_secret {
+        info!("storing signing secret from registration");
+        store.save_signing_secret(secret)?;
+    }
+    if let (Some(identity), Some(certificate)) = (identity, &response.certificate) {
+        info!("storing device certificate from registration");
+        // the device is registered either way; renewal asks again for a certificate
+        if let Err(e) = identity.store_certificate(certificate) {
+            error!("cannot use the certificate issued at registration: {}", e);
+        }
+    }
+    Ok(response.device_id)
 }
 
 #[cfg(test)]
@@ -78,13 +116,21 @@ mod test {
     use std::sync::Mutex;
 
     use crate::{
+        api::auth::{test_auth::MemoryCredentials, CredentialStore},
+        api::control_plane::{HttpControlPlane, InMemoryControlPlane},
+        api::identity::DeviceIdentity,
         api::models::register_device::{RegisterDeviceRequest, RegisterDeviceResponse},
+        capabilities,
+        config::DaemonConfig,
+        handlers::HandlerRegistry,
         localstore::{get_handle, write_single},
         models::db::common::Id,
         test_commons::{before_each_fs, setup_server},
     };
 
     use lazy_static::lazy_static;
+    use openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::PKey, x509::X509Builder};
+    use tempdir::TempDir;
 
     fn get_json_payload(device_id: Id) -> (RegisterDeviceResponse, String) {
         let data = RegisterDeviceResponse::new(device_id);
@@ -126,7 +172,16 @@ mod test {
             .create();
 
         let input = RegisterDeviceRequest::default();
-        let result = super::register_device_inner(&input.user_id, &input.user_secret, config).await;
+        let control_plane = HttpControlPlane::new(config);
+        let result = super::register_device_inner(
+            &input.user_id,
+            &input.user_secret,
+            &control_plane,
+            &MemoryCredentials::default(),
+            None,
+            None,
+        )
+        .await;
         dbg!(&result);
 
         assert!(result.is_ok());
@@ -135,6 +190,40 @@ mod test {
         mock.assert();
     }
 
+    #[tokio::test]
+    async fn test_register_device_stores_issued_secrets() {
+        let _tmp = LOCK.lock().unwrap();
+        before_each_fs();
+
+        let _ = get_handle().unwrap();
+        let (mut server, config) = setup_server();
+        let mock = server
+            .mock("POST", "/devices/register")
+            .with_status(200)
+            .with_body(
+                r#"{"device_id": "testdeviceid", "credential": {"token": "issued"}, "signing_secret": "signing"}"#,
+            )
+            .create();
+
+        let input = RegisterDeviceRequest::default();
+        let control_plane = HttpControlPlane::new(config);
+        let store = MemoryCredentials::default();
+        let result = super::register_device_inner(
+            &input.user_id,
+            &input.user_secret,
+            &control_plane,
+            &store,
+            None,
+            None,
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), "testdeviceid");
+        assert_eq!(store.load().unwrap().token, "issued");
+        assert_eq!(store.load_signing_secret().as_deref(), Some("signing"));
+        mock.assert();
+    }
+
     #[tokio::test]
     async fn test_get_device_id_ok_when_no_existing() {
         let _tmp = LOCK.lock().unwrap();
@@ -153,7 +242,16 @@ mod test {
 
         let user_id = "testid".to_string();
         let user_secret = "secret".to_string();
-        let result = super::get_device_id_inner(&user_id, &user_secret, config).await;
+        let control_plane = HttpControlPlane::new(config);
+        let result = super::get_device_id(
+            &user_id,
+            &user_secret,
+            &control_plane,
+            &MemoryCredentials::default(),
+            None,
+            None,
+        )
+        .await;
 
         assert!(result.is_ok());
         assert!(result.unwrap() == data.device_id);
@@ -172,9 +270,93 @@ mod test {
 
         let user_id = "testid".to_string();
         let user_secret = "testsecret".to_string();
-        let result = super::get_device_id(&user_id, &user_secret).await;
+        let control_plane = InMemoryControlPlane::new("otherdeviceid");
+        let result = super::get_device_id(
+            &user_id,
+            &user_secret,
+            &control_plane,
+            &MemoryCredentials::default(),
+            None,
+            None,
+        )
+        .await;
 
         assert!(result.is_ok());
         assert!(result.unwrap() == device_id);
+        assert!(control_plane.registrations().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_device_id_registers_with_device_name() {
+        let _tmp = LOCK.lock().unwrap();
+        before_each_fs();
+
+        let _ = get_handle().unwrap();
+        let control_plane = InMemoryControlPlane::new("testdeviceid");
+
+        let user_id = "testid".to_string();
+        let user_secret = "testsecret".to_string();
+        let capabilities =
+            capabilities::collect(&DaemonConfig::default(), &HandlerRegistry::builtin());
+        let result = super::get_device_id(
+            &user_id,
+            &user_secret,
+            &control_plane,
+            &MemoryCredentials::default(),
+            None,
+            Some(&capabilities),
+        )
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), "testdeviceid");
+        assert_eq!(
+            control_plane.registrations(),
+            vec![super::get_device_name()]
+        );
+        assert_eq!(control_plane.advertisements(), vec![capabilities]);
+    }
+
+    #[tokio::test]
+    async fn test_get_device_id_stores_issued_certificate() {
+        let _tmp = LOCK.lock().unwrap();
+        before_each_fs();
+
+        let _ = get_handle().unwrap();
+        let dir = TempDir::new("test-identity").unwrap();
+        let identity = DeviceIdentity::load_or_create(&dir.path().join("key.pem")).unwrap();
+        let control_plane = InMemoryControlPlane::new("testdeviceid");
+        control_plane.issue_certificate(&self_signed(&dir.path().join("key.pem")));
+
+        let user_id = "testid".to_string();
+        let user_secret = "testsecret".to_string();
+        let result = super::get_device_id(
+            &user_id,
+            &user_secret,
+            &control_plane,
+            &MemoryCredentials::default(),
+            Some(&identity),
+            None,
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), "testdeviceid");
+        assert_eq!(control_plane.csrs().len(), 1);
+        assert!(identity.certificate().is_some());
+    }
+
+    /// a certificate for the key at `key_path`, as the control plane would issue
+    fn self_signed(key_path: &std::path::Path) -> String {
+        let key = PKey::private_key_from_pem(&std::fs::read(key_path).unwrap()).unwrap();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(30).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        String::from_utf8(builder.build().to_pem().unwrap()).unwrap()
     }
 }
@@ -0,0 +1,371 @@
+use std::ffi::{CStr, CString};
+use std::io;
+use std::os::unix::process::CommandExt as _;
+use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use libc::{c_int, c_ulong};
+
+use crate::config::SandboxSettings;
+use crate::models::HandlerError;
+
+const ROOT: &CStr = c"/";
+const TMP: &CStr = c"/tmp";
+const PROC: &CStr = c"/proc";
+const TMPFS: &CStr = c"tmpfs";
+const PROCFS: &CStr = c"proc";
+const NONE: &CStr = c"none";
+/// the whole environment a sandboxed command starts with, besides `sandbox.keep_env`
+const BASE_ENV: &[(&str, &str)] = &[
+    (
+        "PATH",
+        "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+    ),
+    ("HOME", "/tmp"),
+    ("TMPDIR", "/tmp"),
+];
+/// signals the relay passes on to the namespace as SIGKILL, see `stop_namespace`
+const STOP_SIGNALS: [c_int; 3] = [libc::SIGTERM, libc::SIGINT, libc::SIGHUP];
+/// the namespace's pid 1, as the relay sees it
+static CHILD: AtomicI32 = AtomicI32::new(0);
+/// the stop signal the relay got, reported instead of the SIGKILL it turned into
+static STOPPED_BY: AtomicI32 = AtomicI32::new(0);
+
+/**
+ * confines a shell command (the daemon must run as root):
+ * - new mount, pid and (unless `isolate_network = false`) network namespaces
+ * - a private tmpfs on /tmp, which is also the working directory, and a fresh /proc
+ * - rlimits for cpu seconds, address space, open files and processes
+ * - an environment reduced to `BASE_ENV` plus the variables named in `keep_env`
+ * - the unprivileged `uid`/`gid`, with supplementary groups dropped
+ *
+ * the spawned process unshares and forks once more, so that the shell is pid 1 of its own
+ * namespace; the process left outside relays its exit status (or signal) to the executor.
+ * pid 1 ignores every signal from outside its namespace that it has no handler for, SIGTERM
+ * included, so the relay turns SIGTERM (and SIGINT, SIGHUP) into a SIGKILL for it: timeouts
+ * and cancellations take effect at once rather than after `kill_grace_secs`.
+ *
+ * RLIMIT_NPROC counts every process of `uid` on the host, not only the sandbox's, so `uid`
+ * should be dedicated to the sandbox: with a shared one (e.g. nobody) other processes use up
+ * the limit, and sandboxed commands can keep theirs from starting.
+ */
+pub fn apply(
+    command: &mut std::process::Command,
+    settings: &SandboxSettings,
+) -> Result<(), HandlerError> {
+    command.env_clear().envs(BASE_ENV.iter().copied());
+    for name in &settings.keep_env {
+        if let Some(value) = std::env::var_os(name) {
+            command.env(name, value);
+        }
+    }
+
+    let plan = Plan::new(settings)?;
+    // SAFETY: `Plan::enter` only makes async-signal-safe libc calls and does not allocate
+    unsafe {
+        command.pre_exec(move || plan.enter());
+    }
+    Ok(())
+}
+
+/// everything the forked child needs, prepared up front so nothing allocates after fork
+struct Plan {
+    namespaces: c_int,
+    tmp_options: CString,
+    rlimits: Vec<(c_int, libc::rlim_t)>,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+impl Plan {
+    fn new(settings: &SandboxSettings) -> Result<Self, HandlerError> {
+        let mut namespaces = libc::CLONE_NEWNS | libc::CLONE_NEWPID;
+        if settings.isolate_network {
+            namespaces |= libc::CLONE_NEWNET;
+        }
+        let tmp_options = CString::new(format!("mode=1777,size={}m", settings.tmp_size_mb))
+            .map_err(|_| HandlerError::ConfigError("invalid sandbox.tmp_size_mb".to_string()))?;
+        let rlimits = [
+            (libc::RLIMIT_CPU, settings.cpu_secs),
+            (
+                libc::RLIMIT_AS,
+                settings.address_space_mb.map(|mb| mb * 1024 * 1024),
+            ),
+            (libc::RLIMIT_NOFILE, settings.open_files),
+            (libc::RLIMIT_NPROC, settings.processes),
+        ]
+        .into_iter()
+        .filter_map(|(resource, limit)| limit.map(|limit| (resource as c_int, limit)))
+        .collect();
+
+        Ok(Plan {
+            namespaces,
+            tmp_options,
+            rlimits,
+            uid: settings.uid,
+            gid: settings.gid,
+        })
+    }
+
+    fn enter(&self) -> io::Result<()> {
+        // SAFETY: plain syscalls on pointers to static or pre-built c strings
+        unsafe {
+            check(libc::unshare(self.namespaces))?;
+            check(mount(
+                NONE,
+                ROOT,
+                None,
+                libc::MS_REC | libc::MS_PRIVATE,
+                None,
+            ))?;
+            check(mount(
+                TMPFS,
+                TMP,
+                Some(TMPFS),
+                libc::MS_NOSUID | libc::MS_NODEV,
+                Some(&self.tmp_options),
+            ))?;
+
+            // held back until the relay can pass them on, restored for the shell
+            let mut stop_signals: libc::sigset_t = std::mem::zeroed();
+            let mut mask: libc::sigset_t = std::mem::zeroed();
+            libc::sigemptyset(&mut stop_signals);
+            for signal in STOP_SIGNALS {
+                libc::sigaddset(&mut stop_signals, signal);
+            }
+            check(libc::sigprocmask(libc::SIG_BLOCK, &stop_signals, &mut mask))?;
+
+            match libc::fork() {
+                -1 => Err(io::Error::last_os_error()),
+                0 => {
+                    check(libc::sigprocmask(libc::SIG_SETMASK, &mask, ptr::null_mut()))?;
+                    self.enter_child()
+                }
+                pid => relay_exit(pid, &mask),
+            }
+        }
+    }
+
+    /// pid 1 of the new namespace, about to exec the shell
+    unsafe fn enter_child(&self) -> io::Result<()> {
+        check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+        check(mount(
+            PROCFS,
+            PROC,
+            Some(PROCFS),
+            libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+            None,
+        ))?;
+        check(libc::chdir(TMP.as_ptr()))?;
+        for (resource, limit) in &self.rlimits {
+            let rlimit = libc::rlimit {
+                rlim_cur: *limit,
+                rlim_max: *limit,
+            };
+            check(libc::setrlimit(*resource as _, &rlimit))?;
+        }
+        check(libc::setgroups(0, ptr::null()))?;
+        check(libc::setgid(self.gid))?;
+        check(libc::setuid(self.uid))?;
+        Ok(())
+    }
+}
+
+/**
+ * waits for the namespaced child and exits the same way it did, or with the stop signal that
+ * ended it. `mask` is the signal mask to restore once the stop signals are handled here
+ * rather than by the handlers inherited from the daemon.
+ */
+unsafe fn relay_exit(pid: libc::pid_t, mask: &libc::sigset_t) -> ! {
+    close_all_fds();
+    CHILD.store(pid, Ordering::SeqCst);
+    let mut action: libc::sigaction = std::mem::zeroed();
+    action.sa_sigaction = stop_namespace as extern "C" fn(c_int) as libc::sighandler_t;
+    libc::sigemptyset(&mut action.sa_mask);
+    for signal in STOP_SIGNALS {
+        libc::sigaction(signal, &action, ptr::null_mut());
+    }
+    libc::sigprocmask(libc::SIG_SETMASK, mask, ptr::null_mut());
+
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) == -1 {
+        if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+            libc::_exit(127);
+        }
+    }
+    let stopped_by = STOPPED_BY.load(Ordering::SeqCst);
+    if stopped_by != 0 || libc::WIFSIGNALED(status) {
+        let signal = match stopped_by {
+            0 => libc::WTERMSIG(status),
+            stopped_by => stopped_by,
+        };
+        libc::signal(signal, libc::SIG_DFL);
+        libc::kill(libc::getpid(), signal);
+        libc::_exit(128 + signal);
+    }
+    libc::_exit(libc::WEXITSTATUS(status))
+}
+
+/// stop signal handler of the relay; only async-signal-safe calls
+extern "C" fn stop_namespace(signal: c_int) {
+    STOPPED_BY.store(signal, Ordering::SeqCst);
+    // SAFETY: kill has no memory safety preconditions
+    unsafe {
+        libc::kill(CHILD.load(Ordering::SeqCst), libc::SIGKILL);
+    }
+}
+
+/**
+ * the relaying process must not hold on to any pipe: std only returns from `spawn` once its
+ * exec error pipe is closed by every process, and the output readers wait for eof.
+ */
+unsafe fn close_all_fds() {
+    if libc::syscall(libc::SYS_close_range, 0, libc::c_uint::MAX, 0) == 0 {
+        return;
+    }
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit);
+    for fd in 0..limit.rlim_cur.min(c_int::MAX as libc::rlim_t) {
+        libc::close(fd as c_int);
+    }
+}
+
+unsafe fn mount(
+    source: &CStr,
+    target: &CStr,
+    fstype: Option<&CStr>,
+    flags: c_ulong,
+    data: Option<&CStr>,
+) -> c_int {
+    libc::mount(
+        source.as_ptr(),
+        target.as_ptr(),
+        fstype.map_or(ptr::null(), CStr::as_ptr),
+        flags,
+        data.map_or(ptr::null(), |data| data.as_ptr().cast()),
+    )
+}
+
+fn check(result: c_int) -> io::Result<()> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    use crate::{
+        config::{ExecutorSettings, SandboxSettings},
+        executor::{handoff_command_to_executor, ExecutorContext},
+        models::db::{
+            commands::{Command, CommandNames},
+            results::ExecutionResult,
+        },
+    };
+
+    /// namespaces and setuid need root; elsewhere these tests do nothing
+    fn is_root() -> bool {
+        // SAFETY: geteuid has no preconditions
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    async fn run_sandboxed(args: &str, settings: SandboxSettings) -> ExecutionResult {
+        run_sandboxed_with_timeout(args, settings, None).await
+    }
+
+    async fn run_sandboxed_with_timeout(
+        args: &str,
+        settings: SandboxSettings,
+        timeout_secs: Option<u64>,
+    ) -> ExecutionResult {
+        let mut command = Command::default();
+        command.name = CommandNames::ShellCmd;
+        command.args = Some(args.to_string());
+        command.sandbox = true;
+        command.timeout_secs = timeout_secs;
+        let context = ExecutorContext {
+            sandbox: settings,
+            // long enough that a timeout only ends in time if SIGTERM takes effect
+            settings: ExecutorSettings {
+                kill_grace_secs: 30,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        handoff_command_to_executor(&command, &context)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sandboxed_shell_is_confined() {
+        if !is_root() {
+            return;
+        }
+
+        let result = run_sandboxed(
+            "echo $$; id -u; id -g; pwd; ls -A /tmp | wc -l; ulimit -n; \
+             grep -c ':' /proc/net/dev; echo ${CARGO_PKG_NAME:-scrubbed}",
+            SandboxSettings::default(),
+        )
+        .await;
+
+        assert_eq!(result.exit_code, Some(0), "{:?}", result.stderr);
+        let lines: Vec<&str> = result.stdout.as_deref().unwrap().lines().collect();
+        // pid 1, nobody:nogroup, empty private /tmp, rlimit, loopback only, no daemon env
+        assert_eq!(
+            lines,
+            vec!["1", "65534", "65534", "/tmp", "0", "256", "1", "scrubbed"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sandboxed_exit_code_is_relayed() {
+        if !is_root() {
+            return;
+        }
+
+        let result = run_sandboxed("exit 7", SandboxSettings::default()).await;
+        assert_eq!(result.exit_code, Some(7));
+    }
+
+    #[tokio::test]
+    async fn test_sandboxed_timeout_kills_the_namespace() {
+        if !is_root() {
+            return;
+        }
+
+        // as in the daemon, whose handler the relay inherits since it never execs
+        let _handled = signal(SignalKind::terminate()).unwrap();
+        // sleep is pid 1 of the namespace, which ignores SIGTERM from outside it
+        let result =
+            run_sandboxed_with_timeout("exec sleep 60", SandboxSettings::default(), Some(1)).await;
+
+        assert!(result.timed_out);
+        assert_eq!(result.signal, Some(libc::SIGTERM));
+        assert!(result.duration_ms < 10_000);
+    }
+
+    #[tokio::test]
+    async fn test_sandbox_keeps_network_when_asked() {
+        if !is_root() {
+            return;
+        }
+
+        let settings = SandboxSettings {
+            isolate_network: false,
+            ..Default::default()
+        };
+        let result = run_sandboxed("grep -c ':' /proc/net/dev", settings).await;
+
+        let interfaces: u32 = result.stdout.unwrap().trim().parse().unwrap();
+        assert!(interfaces >= 1);
+    }
+}
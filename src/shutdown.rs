@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+/// clean exit, including after SIGINT/SIGTERM once in-flight commands finished
+pub const EXIT_OK: u8 = 0;
+/// in-flight commands were still running at the drain deadline and got killed
+pub const EXIT_ABANDONED: u8 = 1;
+/// the daemon configuration failed to load or validate
+pub const EXIT_CONFIG: u8 = 2;
+/// a second signal arrived while draining
+pub const EXIT_FORCED: i32 = 130;
+
+/// cloneable handle that resolves once shutdown has been requested
+#[derive(Clone)]
+pub struct Shutdown {
+    rx: watch::Receiver<bool>,
+}
+
+/// requests the shutdown; the daemon also triggers it itself to restart into an update
+#[derive(Clone)]
+pub struct ShutdownTrigger {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (tx, rx) = watch::channel(false);
+    (ShutdownTrigger { tx: Arc::new(tx) }, Shutdown { rx })
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        let _ = self.tx.send(true);
+    }
+}
+
+impl Shutdown {
+    pub fn is_triggered(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    pub async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        // an Err means every trigger was dropped, which only happens in tests; treat as pending
+        if rx.wait_for(|triggered| *triggered).await.is_err() {
+            futures::future::pending::<()>().await;
+        }
+    }
+
+    /// sleeps for `duration` unless shutdown is requested first; returns false if interrupted
+    pub async fn sleep(&self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => true,
+            _ = self.wait() => false,
+        }
+    }
+}
+
+/**
+ * the first SIGINT/SIGTERM requests a graceful shutdown: no new commands are fetched and
+ * in-flight ones get until `shutdown.drain_timeout_secs` to finish. a second signal exits
+ * immediately.
+ */
+pub fn listen_for_signals() -> (ShutdownTrigger, Shutdown) {
+    let (trigger, shutdown) = channel();
+    let signalled = trigger.clone();
+    tokio::spawn(async move {
+        let mut sigint = signal(SignalKind::interrupt()).expect("can install SIGINT handler");
+        let mut sigterm = signal(SignalKind::terminate()).expect("can install SIGTERM handler");
+
+        tokio::select! {
+            _ = sigint.recv() => info!("received SIGINT, shutting down"),
+            _ = sigterm.recv() => info!("received SIGTERM, shutting down"),
+        }
+        signalled.trigger();
+
+        tokio::select! {
+            _ = sigint.recv() => {},
+            _ = sigterm.recv() => {},
+        }
+        warn!("received second signal, exiting without waiting for commands");
+        std::process::exit(EXIT_FORCED);
+    });
+    (trigger, shutdown)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_sleep_completes_without_trigger() {
+        let (_trigger, shutdown) = super::channel();
+
+        assert!(shutdown.sleep(Duration::from_millis(10)).await);
+        assert!(!shutdown.is_triggered());
+    }
+
+    #[tokio::test]
+    async fn test_sleep_is_interrupted_by_trigger() {
+        let (trigger, shutdown) = super::channel();
+
+        let sleeper = {
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move { shutdown.sleep(Duration::from_secs(60)).await })
+        };
+        trigger.trigger();
+
+        let completed = tokio::time::timeout(Duration::from_secs(5), sleeper)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!completed);
+        assert!(shutdown.is_triggered());
+    }
+}
@@ -1,8 +1,14 @@
 use std::sync::Mutex;
 
-use crate::{api::requests::ApiConfig, localstore::get_default_filepath};
+use crate::{
+    api::requests::ApiConfig,
+    api::signing::{RequestSigner, NONCE_HEADER, SIGNATURE_HEADER, TIMESTAMP_HEADER},
+    executor::now_ms,
+    localstore::get_default_filepath,
+};
 use lazy_static::lazy_static;
 use mockito;
+use warp::Filter as _;
 
 lazy_static! {
     static ref SETUP_DONE: Mutex<bool> = Mutex::new(false);
@@ -50,6 +56,29 @@ pub fn setup_server() -> (mockito::Server, ApiConfig) {
     (server, get_api_config_with_port(port))
 }
 
+/// answers every request with `body`, signed with `secret`, and returns a config carrying it
+pub fn setup_signed_server(secret: &str, body: String) -> ApiConfig {
+    let signer_secret = secret.to_string();
+    let route = warp::any()
+        .and(warp::header::<String>(NONCE_HEADER))
+        .map(move |nonce: String| {
+            let signer = RequestSigner::from_secret(&signer_secret).unwrap();
+            let timestamp = now_ms() / 1000;
+            let signature = signer
+                .sign_response(200, body.as_bytes(), timestamp, &nonce)
+                .unwrap();
+            warp::http::Response::builder()
+                .header(TIMESTAMP_HEADER, timestamp)
+                .header(SIGNATURE_HEADER, signature)
+                .body(body.clone())
+        });
+    let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    get_api_config_with_port(address.port())
+        .with_token("testtoken".to_string())
+        .with_signing_secret(secret.to_string())
+}
+
 pub fn get_404_json_string() -> String {
     r#"{"error": "not found"}"#.to_string()
 }
@@ -0,0 +1,886 @@
+use std::ffi::OsString;
+use std::io::Write as _;
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::os::unix::process::CommandExt as _;
+use std::path::{Path, PathBuf};
+use std::process::{ExitCode, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use log::{error, info, warn};
+use openssl::pkey::{PKey, Public};
+use openssl::sha::sha256;
+use openssl::sign::Verifier;
+use serde::{Deserialize, Serialize};
+
+use crate::api::control_plane::ControlPlane;
+use crate::config::DaemonConfig;
+use crate::executor::now_ms;
+use crate::localstore::write_atomically;
+use crate::models::db::commands::{Command, CommandStatus};
+use crate::models::db::common::{HasId, Id};
+use crate::models::db::results::ExecutionResult;
+use crate::models::HandlerError;
+use crate::shutdown::{ShutdownTrigger, EXIT_CONFIG, EXIT_OK};
+use crate::verification::parse_public_key;
+
+/// the version of this binary, which an update is confirmed against after the restart
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// first argument of the rollback watchdog started for an update, see `run_watchdog`
+pub const WATCHDOG_FLAG: &str = "--update-watchdog";
+/// how long the watchdog gives a daemon that missed its deadline to exit before killing it
+const KILL_GRACE: Duration = Duration::from_secs(10);
+/// how often the watchdog looks at the update state
+const WATCHDOG_POLL: Duration = Duration::from_secs(1);
+
+/**
+ * what an `Update` command carries in its args, as json. `signature` is the release key's
+ * ed25519 signature (base64) over the json array `[version, sha256]`, so a binary is only
+ * ever installed as the version it was released as.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpdateManifest {
+    pub version: String,
+    /// hex sha256 of the binary
+    pub sha256: String,
+    pub signature: String,
+}
+
+impl UpdateManifest {
+    pub fn parse(args: Option<&str>) -> Result<Self, HandlerError> {
+        let args = args.ok_or_else(|| failed("no update manifest in the command args"))?;
+        let manifest: UpdateManifest = serde_json::from_str(args)
+            .map_err(|e| failed(&format!("invalid update manifest: {}", e)))?;
+        // it ends up in the download path
+        let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '+' | '_');
+        if manifest.version.is_empty() || !manifest.version.chars().all(valid) {
+            return Err(failed(&format!("invalid version {:?}", manifest.version)));
+        }
+        Ok(manifest)
+    }
+
+    /// bytes the release key signs
+    pub fn signed_payload(&self) -> Result<Vec<u8>, HandlerError> {
+        Ok(serde_json::to_vec(&(&self.version, &self.sha256))?)
+    }
+
+    pub fn verify(&self, public_key: &PKey<Public>) -> Result<(), HandlerError> {
+        let signature = STANDARD
+            .decode(&self.signature)
+            .map_err(|_| failed("release signature is not base64"))?;
+        let mut verifier = Verifier::new_without_digest(public_key)?;
+        if !verifier
+            .verify_oneshot(&signature, &self.signed_payload()?)
+            .unwrap_or(false)
+        {
+            return Err(failed("release signature does not match"));
+        }
+        Ok(())
+    }
+
+    /// whether `binary` is the one the manifest was signed for
+    pub fn check(&self, binary: &[u8]) -> Result<(), HandlerError> {
+        let digest = hex::encode(sha256(binary));
+        if !digest.eq_ignore_ascii_case(&self.sha256) {
+            return Err(failed(&format!(
+                "checksum mismatch: expected {}, downloaded {}",
+                self.sha256, digest
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpdatePhase {
+    /// the new binary is in place and has until the deadline to reach the server
+    Staged,
+    /// the previous binary was put back
+    RolledBack { reason: String },
+}
+
+/// an update across the restart into the new version, kept in `update.json`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingUpdate {
+    pub command: Command,
+    pub from_version: String,
+    pub to_version: String,
+    pub binary: PathBuf,
+    pub previous: PathBuf,
+    /// the daemon's pid, which the re-exec keeps; 0 until the restart
+    pub pid: u32,
+    /// `start_time` of `pid`, so that a pid reused by another process is never signalled;
+    /// missing in state written by versions that did not record it
+    #[serde(default)]
+    pub pid_started: Option<u64>,
+    pub watchdog_pid: Option<u32>,
+    #[serde(default)]
+    pub watchdog_started: Option<u64>,
+    /// 0 until the restart
+    pub deadline_ms: u64,
+    pub health_deadline_secs: u64,
+    pub phase: UpdatePhase,
+}
+
+impl PendingUpdate {
+    fn read(path: &Path) -> Option<Self> {
+        let data = std::fs::read(path).ok()?;
+        match serde_json::from_slice(&data) {
+            Ok(pending) => Some(pending),
+            Err(e) => {
+                warn!("ignoring unreadable update state {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn write(&self, path: &Path) -> Result<(), HandlerError> {
+        write_atomically(path, &serde_json::to_vec_pretty(self)?)
+    }
+
+    fn missed_deadline(&self) -> String {
+        format!(
+            "version {} did not reach the server within {}s",
+            self.to_version, self.health_deadline_secs
+        )
+    }
+
+    /// starts `run_watchdog` from the previous binary, in its own process group so that
+    /// stopping the daemon does not stop it
+    fn spawn_watchdog(&mut self, state_path: &Path) {
+        let watchdog = std::process::Command::new(&self.previous)
+            .arg(WATCHDOG_FLAG)
+            .arg(state_path)
+            .stdin(Stdio::null())
+            .process_group(0)
+            .spawn();
+        match watchdog {
+            Ok(child) => {
+                self.watchdog_pid = Some(child.id());
+                self.watchdog_started = start_time(child.id());
+            }
+            Err(e) => warn!("cannot start the update watchdog, no rollback: {}", e),
+        }
+    }
+
+    /// records this process as the daemon the update was restarted into
+    fn own(&mut self) {
+        self.pid = std::process::id();
+        self.pid_started = start_time(self.pid);
+    }
+
+    fn watchdog_alive(&self) -> bool {
+        self.watchdog_pid
+            .is_some_and(|pid| is_alive(pid, self.watchdog_started))
+    }
+
+    /// kills the watchdog, if it is still the process that was started
+    fn stop_watchdog(&self) {
+        if let Some(pid) = self.watchdog_pid {
+            if signal(pid, self.watchdog_started, libc::SIGKILL) {
+                reap(pid);
+            }
+        }
+    }
+
+    /// puts the previous binary back and records why
+    fn roll_back(&mut self, reason: String, path: &Path) -> Result<(), HandlerError> {
+        warn!("rolling back to version {}: {}", self.from_version, reason);
+        std::fs::rename(&self.previous, &self.binary)?;
+        self.phase = UpdatePhase::RolledBack { reason };
+        self.write(path)
+    }
+}
+
+/// the final status of an update, known once the daemon came back after the restart
+pub struct UpdateOutcome {
+    pub command: Command,
+    pub result: ExecutionResult,
+    pub status: CommandStatus,
+}
+
+/**
+ * the `Update` command: a signed self-update with automatic rollback.
+ *
+ * 1. `stage` downloads the release named in the manifest, checks its checksum and signature,
+ *    swaps it in place of the daemon binary (keeping the old one next to it as `.previous`)
+ *    and asks the daemon to shut down
+ * 2. once in-flight commands drained, `restart` starts a watchdog from the previous binary and
+ *    re-execs into the new one
+ * 3. the new version confirms the update with `check_in` as soon as it reaches the server. if
+ *    it has not by `update.health_deadline_secs`, the watchdog restores the previous binary
+ *    and stops the daemon, for its supervisor to start it again; the failure is then reported
+ *    by the restored version.
+ *
+ * the watchdog may not survive the daemon being stopped (e.g. a supervisor killing the whole
+ * cgroup), so every start of the new version while the update is still staged checks the
+ * deadline itself: past it, it rolls back and stops like the watchdog would, otherwise it
+ * starts a new watchdog if the last one is gone.
+ */
+pub struct Updater {
+    public_key: Option<PKey<Public>>,
+    binary: PathBuf,
+    state_path: PathBuf,
+    health_deadline_secs: u64,
+    /// where releases are downloaded from, see `connect`
+    control_plane: OnceLock<Arc<dyn ControlPlane>>,
+    restart: ShutdownTrigger,
+    /// an update staged by this process, or the one it was started for
+    pending: Mutex<Option<PendingUpdate>>,
+    /// `pending` is this process's own, not yet restarted into
+    staged_here: AtomicBool,
+}
+
+impl Updater {
+    pub fn new(config: &DaemonConfig, restart: ShutdownTrigger) -> Result<Self, HandlerError> {
+        let settings = &config.update;
+        let public_key = settings
+            .public_key
+            .as_deref()
+            .map(|encoded| parse_public_key("update.public_key", encoded))
+            .transpose()?;
+        let binary = match &settings.binary_path {
+            Some(path) => PathBuf::from(path),
+            None => std::env::current_exe()?,
+        };
+        let state_path = config.update_state_path();
+        let mut pending = PendingUpdate::read(&state_path);
+        if let Some(pending) = pending.as_mut() {
+            if pending.phase == UpdatePhase::Staged && pending.to_version == VERSION {
+                // started again by a supervisor: the watchdog has to stop this process now
+                pending.own();
+                if pending.deadline_ms == 0 {
+                    // installed, but the old version never got to restart into it
+                    pending.deadline_ms = now_ms() + pending.health_deadline_secs * 1000;
+                }
+                if now_ms() >= pending.deadline_ms {
+                    let reason = pending.missed_deadline();
+                    match pending.roll_back(reason, &state_path) {
+                        Ok(()) => restart.trigger(),
+                        Err(e) => error!("cannot roll back the update: {}", e),
+                    }
+                } else {
+                    if !pending.watchdog_alive() {
+                        warn!("the update watchdog is gone, starting a new one");
+                        pending.spawn_watchdog(&state_path);
+                    }
+                    pending.write(&state_path)?;
+                }
+            }
+        }
+        Ok(Updater {
+            public_key,
+            binary,
+            health_deadline_secs: settings.health_deadline_secs,
+            control_plane: OnceLock::new(),
+            restart,
+            pending: Mutex::new(pending),
+            staged_here: AtomicBool::new(false),
+            state_path,
+        })
+    }
+
+    /// downloads releases from `control_plane`, which only exists once the device is registered
+    pub fn connect(&self, control_plane: Arc<dyn ControlPlane>) {
+        if self.control_plane.set(control_plane).is_err() {
+            warn!("the updater is already connected");
+        }
+    }
+
+    /// whether `update.public_key` is set, without which nothing is installed
+    pub fn can_update(&self) -> bool {
+        self.public_key.is_some()
+    }
+
+    /// installs the release `command` names and asks the daemon to restart into it
+    pub async fn stage(&self, command: &Command) -> Result<ExecutionResult, HandlerError> {
+        let public_key = self
+            .public_key
+            .as_ref()
+            .ok_or_else(|| failed("update.public_key is not set"))?;
+        let manifest = UpdateManifest::parse(command.args.as_deref())?;
+        manifest.verify(public_key)?;
+        if manifest.version == VERSION {
+            return Ok(ExecutionResult {
+                stdout: Some(format!("already running version {}", VERSION)),
+                ..Default::default()
+            });
+        }
+        if self.pending.lock().unwrap().is_some() {
+            return Err(failed("another update is in progress"));
+        }
+
+        let control_plane = self
+            .control_plane
+            .get()
+            .ok_or_else(|| failed("not connected to the control plane yet"))?;
+        info!("downloading version {}", &manifest.version);
+        let binary = control_plane
+            .download_update(&manifest.version)
+            .await
+            .map_err(|e| failed(&format!("download failed: {}", e)))?;
+        manifest.check(&binary)?;
+
+        let pending = PendingUpdate {
+            command: command.clone(),
+            from_version: VERSION.to_string(),
+            to_version: manifest.version.clone(),
+            binary: self.binary.clone(),
+            previous: sibling(&self.binary, ".previous"),
+            pid: 0,
+            pid_started: None,
+            watchdog_pid: None,
+            watchdog_started: None,
+            deadline_ms: 0,
+            health_deadline_secs: self.health_deadline_secs,
+            phase: UpdatePhase::Staged,
+        };
+        install(&pending.binary, &pending.previous, &binary)
+            .map_err(|e| failed(&format!("cannot install the new binary: {}", e)))?;
+        pending.write(&self.state_path)?;
+        *self.pending.lock().unwrap() = Some(pending);
+        self.staged_here.store(true, Ordering::SeqCst);
+        info!(
+            "version {} installed, restarting once commands finished",
+            &manifest.version
+        );
+        self.restart.trigger();
+        Ok(ExecutionResult {
+            stdout: Some(format!("installed version {}", manifest.version)),
+            ..Default::default()
+        })
+    }
+
+    /// whether `command_id` is the update this process is about to restart into
+    pub fn is_staged(&self, command_id: &Id) -> bool {
+        self.staged_here.load(Ordering::SeqCst)
+            && matches!(
+                &*self.pending.lock().unwrap(),
+                Some(pending) if pending.command.get_id() == command_id
+            )
+    }
+
+    /**
+     * after the shutdown: starts the watchdog and replaces this process with the staged
+     * version. nothing to do without a staged update; returns only if the exec failed, after
+     * putting the previous binary back.
+     */
+    pub fn restart(&self) -> Result<(), HandlerError> {
+        if !self.staged_here.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut guard = self.pending.lock().unwrap();
+        let Some(pending) = guard.as_mut() else {
+            return Ok(());
+        };
+        pending.own();
+        pending.deadline_ms = now_ms() + pending.health_deadline_secs * 1000;
+        pending.write(&self.state_path)?;
+        pending.spawn_watchdog(&self.state_path);
+        pending.write(&self.state_path)?;
+
+        info!("restarting into version {}", &pending.to_version);
+        let args: Vec<OsString> = std::env::args_os().skip(1).collect();
+        let e = std::process::Command::new(&pending.binary)
+            .args(args)
+            .exec();
+        let reason = format!("cannot start version {}: {}", pending.to_version, e);
+        error!("{}", &reason);
+        pending.stop_watchdog();
+        pending.roll_back(reason, &self.state_path)?;
+        Err(HandlerError::IoError(e))
+    }
+
+    /**
+     * called whenever the daemon reached the server: the first time after a restart for an
+     * update, that update is settled, i.e. confirmed if this is the new version or failed if
+     * it was rolled back.
+     */
+    pub fn check_in(&self) -> Option<UpdateOutcome> {
+        if self.staged_here.load(Ordering::SeqCst) {
+            return None;
+        }
+        let mut guard = self.pending.lock().unwrap();
+        guard.as_ref()?;
+        // the watchdog may have given up on this process in the meantime
+        let pending = PendingUpdate::read(&self.state_path).or(guard.take())?;
+        *guard = None;
+        if let Err(e) = std::fs::remove_file(&self.state_path) {
+            warn!("cannot remove the update state: {}", e);
+        }
+
+        let (result, status) = match &pending.phase {
+            UpdatePhase::Staged if pending.to_version == VERSION => {
+                pending.stop_watchdog();
+                let _ = std::fs::remove_file(&pending.previous);
+                info!("update to version {} confirmed", VERSION);
+                let result = ExecutionResult {
+                    stdout: Some(format!(
+                        "updated from version {} to {}",
+                        pending.from_version, VERSION
+                    )),
+                    ..Default::default()
+                };
+                (result, CommandStatus::Terminated)
+            }
+            UpdatePhase::Staged => {
+                let reason = format!(
+                    "running version {} instead of {}",
+                    VERSION, pending.to_version
+                );
+                (failure(reason), CommandStatus::Failed)
+            }
+            UpdatePhase::RolledBack { reason } => (failure(reason.clone()), CommandStatus::Failed),
+        };
+        Some(UpdateOutcome {
+            command: pending.command,
+            result,
+            status,
+        })
+    }
+}
+
+/**
+ * `<previous binary> --update-watchdog <state path>`: waits for the new version to confirm
+ * the update (removing the state) until the deadline, and otherwise restores the previous
+ * binary and stops the daemon.
+ */
+pub fn run_watchdog(state_path: Option<&str>) -> ExitCode {
+    let Some(state_path) = state_path.map(Path::new) else {
+        eprintln!("usage: {} <update state path>", WATCHDOG_FLAG);
+        return ExitCode::from(EXIT_CONFIG);
+    };
+    let mut pending = loop {
+        match PendingUpdate::read(state_path) {
+            Some(pending) if pending.phase == UpdatePhase::Staged => {
+                if now_ms() >= pending.deadline_ms {
+                    break pending;
+                }
+            }
+            _ => return ExitCode::from(EXIT_OK),
+        }
+        std::thread::sleep(WATCHDOG_POLL);
+    };
+
+    let reason = pending.missed_deadline();
+    if let Err(e) = pending.roll_back(reason, state_path) {
+        error!("cannot roll back the update: {}", e);
+        return ExitCode::from(EXIT_CONFIG);
+    }
+    signal(pending.pid, pending.pid_started, libc::SIGTERM);
+    let deadline = std::time::Instant::now() + KILL_GRACE;
+    while is_alive(pending.pid, pending.pid_started) && std::time::Instant::now() < deadline {
+        std::thread::sleep(WATCHDOG_POLL);
+    }
+    signal(pending.pid, pending.pid_started, libc::SIGKILL);
+    ExitCode::from(EXIT_OK)
+}
+
+/// `binary` in place of `target`, with the old one kept at `previous`
+fn install(target: &Path, previous: &Path, binary: &[u8]) -> Result<(), HandlerError> {
+    let staged = sibling(target, ".new");
+    let _ = std::fs::remove_file(&staged);
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o755)
+        .open(&staged)?;
+    file.write_all(binary)?;
+    file.sync_all()?;
+    std::fs::copy(target, previous)?;
+    // same directory, so the binary is never missing or half written
+    std::fs::rename(&staged, target)?;
+    Ok(())
+}
+
+/// `path` with `suffix` appended to its file name
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn failure(reason: String) -> ExecutionResult {
+    ExecutionResult {
+        error: Some(reason),
+        ..Default::default()
+    }
+}
+
+fn failed(reason: &str) -> HandlerError {
+    HandlerError::UpdateFailed(reason.to_string())
+}
+
+/// when `pid` started, in clock ticks since boot (field 22 of `/proc/<pid>/stat`)
+fn start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // field 2 is the command name in parentheses, which may itself contain spaces or ')'
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/**
+ * whether `pid` is still the process that started at `started`. a pid is reused once its
+ * process is gone, and the state file outlives restarts, so a bare pid could name anything by
+ * now; only state from versions that did not record the start time is taken at its word.
+ */
+fn is_alive(pid: u32, started: Option<u64>) -> bool {
+    match started {
+        Some(started) => start_time(pid) == Some(started),
+        // SAFETY: kill has no memory safety preconditions; signal 0 only checks that the
+        // process exists
+        None => unsafe { libc::kill(pid as i32, 0) == 0 },
+    }
+}
+
+/// sends `signal` to `pid` if it is still the process that started at `started`
+fn signal(pid: u32, started: Option<u64>, signal: i32) -> bool {
+    if !is_alive(pid, started) {
+        return false;
+    }
+    // SAFETY: as above
+    unsafe { libc::kill(pid as i32, signal) == 0 }
+}
+
+/// the watchdog is this process's child since the exec, so it has to be waited for; only
+/// called after `signal` found it, so no other process with its pid is waited on
+fn reap(pid: u32) {
+    // SAFETY: waitpid with a null status pointer has no memory safety preconditions
+    unsafe {
+        libc::waitpid(pid as i32, std::ptr::null_mut(), 0);
+    }
+}
+
+#[cfg(test)]
+pub mod test_release {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine as _;
+    use openssl::pkey::{PKey, Private};
+    use openssl::sha::sha256;
+    use openssl::sign::Signer;
+
+    use super::UpdateManifest;
+
+    /// the manifest the release pipeline would publish for `binary`
+    pub fn manifest(key: &PKey<Private>, version: &str, binary: &[u8]) -> UpdateManifest {
+        let mut manifest = UpdateManifest {
+            version: version.to_string(),
+            sha256: hex::encode(sha256(binary)),
+            signature: String::new(),
+        };
+        let mut signer = Signer::new_without_digest(key).unwrap();
+        let payload = manifest.signed_payload().unwrap();
+        manifest.signature = STANDARD.encode(signer.sign_oneshot_to_vec(&payload).unwrap());
+        manifest
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt as _;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use tempdir::TempDir;
+
+    use crate::{
+        api::control_plane::InMemoryControlPlane,
+        config::DaemonConfig,
+        executor::now_ms,
+        models::{
+            db::commands::{Command, CommandNames, CommandStatus},
+            HandlerError,
+        },
+        shutdown::{self, Shutdown},
+        verification::test_keys,
+    };
+
+    use super::{
+        is_alive, run_watchdog, start_time, test_release, PendingUpdate, UpdateManifest,
+        UpdatePhase, Updater, VERSION, WATCHDOG_FLAG,
+    };
+
+    fn updater(dir: &TempDir, public_key: Option<String>) -> Updater {
+        updater_with_restart(dir, public_key).0
+    }
+
+    fn updater_with_restart(dir: &TempDir, public_key: Option<String>) -> (Updater, Shutdown) {
+        let mut config = DaemonConfig::default();
+        config.localstore.path = dir.path().join("localstore.json").display().to_string();
+        config.update.public_key = public_key;
+        config.update.binary_path = Some(dir.path().join("itx-daemon").display().to_string());
+        let (trigger, restart) = shutdown::channel();
+        let control_plane = Arc::new(InMemoryControlPlane::new("testdeviceid"));
+        let updater = Updater::new(&config, trigger).unwrap();
+        updater.connect(control_plane);
+        (updater, restart)
+    }
+
+    /// the state a restart into `to_version` leaves behind
+    fn pending(dir: &Path, to_version: &str, phase: UpdatePhase) -> PendingUpdate {
+        let mut command = Command::default();
+        command.name = CommandNames::Update;
+        PendingUpdate {
+            command,
+            from_version: "0.0.1".to_string(),
+            to_version: to_version.to_string(),
+            binary: dir.join("itx-daemon"),
+            previous: dir.join("itx-daemon.previous"),
+            pid: 1,
+            pid_started: None,
+            watchdog_pid: None,
+            watchdog_started: None,
+            deadline_ms: 0,
+            health_deadline_secs: 300,
+            phase,
+        }
+    }
+
+    #[test]
+    fn test_manifest_signature_and_checksum() {
+        let (key, public_key) = test_keys::generate();
+        let public_key = crate::verification::parse_public_key("key", &public_key).unwrap();
+        let manifest = test_release::manifest(&key, "1.2.0", b"binary");
+
+        assert!(manifest.verify(&public_key).is_ok());
+        assert!(manifest.check(b"binary").is_ok());
+        assert!(matches!(
+            manifest.check(b"other"),
+            Err(HandlerError::UpdateFailed(_))
+        ));
+
+        // a signed binary cannot be passed off as another version
+        let relabelled = UpdateManifest {
+            version: "9.9.9".to_string(),
+            ..manifest
+        };
+        assert!(matches!(
+            relabelled.verify(&public_key),
+            Err(HandlerError::UpdateFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_manifest_version_must_be_safe() {
+        let (key, _) = test_keys::generate();
+        for version in ["../../etc", "", "1.0/x"] {
+            let manifest = test_release::manifest(&key, version, b"binary");
+            let args = serde_json::to_string(&manifest).unwrap();
+            assert!(UpdateManifest::parse(Some(&args)).is_err());
+        }
+        assert!(UpdateManifest::parse(None).is_err());
+        assert!(UpdateManifest::parse(Some("not json")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update_needs_release_key() {
+        let dir = TempDir::new("test-updater").unwrap();
+        let (key, _) = test_keys::generate();
+        let mut command = Command::default();
+        command.args =
+            Some(serde_json::to_string(&test_release::manifest(&key, "9.0.0", b"x")).unwrap());
+
+        let result = updater(&dir, None).stage(&command).await;
+
+        assert!(matches!(result, Err(HandlerError::UpdateFailed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_update_to_running_version_is_a_no_op() {
+        let dir = TempDir::new("test-updater").unwrap();
+        let (key, public_key) = test_keys::generate();
+        let mut command = Command::default();
+        command.args =
+            Some(serde_json::to_string(&test_release::manifest(&key, VERSION, b"x")).unwrap());
+        let updater = updater(&dir, Some(public_key));
+
+        let result = updater.stage(&command).await.unwrap();
+
+        assert!(result.is_success());
+        assert!(!dir.path().join("update.json").exists());
+        assert!(updater.restart().is_ok());
+    }
+
+    #[test]
+    fn test_new_version_confirms_update_on_check_in() {
+        let dir = TempDir::new("test-updater").unwrap();
+        std::fs::write(dir.path().join("itx-daemon.previous"), "old").unwrap();
+        pending(dir.path(), VERSION, UpdatePhase::Staged)
+            .write(&dir.path().join("update.json"))
+            .unwrap();
+        let updater = updater(&dir, None);
+
+        let outcome = updater.check_in().unwrap();
+
+        assert_eq!(outcome.status, CommandStatus::Terminated);
+        assert!(outcome.result.stdout.unwrap().contains(VERSION));
+        assert!(!dir.path().join("update.json").exists());
+        assert!(!dir.path().join("itx-daemon.previous").exists());
+        assert!(updater.check_in().is_none());
+    }
+
+    #[test]
+    fn test_rolled_back_update_is_reported_failed() {
+        let dir = TempDir::new("test-updater").unwrap();
+        let reason = "version 9.0.0 did not reach the server within 300s".to_string();
+        pending(dir.path(), "9.0.0", UpdatePhase::RolledBack { reason })
+            .write(&dir.path().join("update.json"))
+            .unwrap();
+
+        let outcome = updater(&dir, None).check_in().unwrap();
+
+        assert_eq!(outcome.status, CommandStatus::Failed);
+        assert!(outcome.result.error.unwrap().contains("did not reach"));
+        assert!(!dir.path().join("update.json").exists());
+    }
+
+    #[test]
+    fn test_watchdog_rolls_back_after_deadline() {
+        let dir = TempDir::new("test-updater").unwrap();
+        let state_path = dir.path().join("update.json");
+        std::fs::write(dir.path().join("itx-daemon"), "new").unwrap();
+        std::fs::write(dir.path().join("itx-daemon.previous"), "old").unwrap();
+        // stands in for a new version that hangs before reaching the server
+        let mut daemon = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let mut state = pending(dir.path(), "9.0.0", UpdatePhase::Staged);
+        state.pid = daemon.id();
+        state.pid_started = start_time(daemon.id());
+        state.write(&state_path).unwrap();
+        let reaper = std::thread::spawn(move || daemon.wait().unwrap());
+
+        run_watchdog(state_path.to_str());
+
+        assert!(!reaper.join().unwrap().success());
+        assert_eq!(
+            std::fs::read(dir.path().join("itx-daemon")).unwrap(),
+            b"old"
+        );
+        let state = PendingUpdate::read(&state_path).unwrap();
+        assert!(matches!(state.phase, UpdatePhase::RolledBack { .. }));
+    }
+
+    #[test]
+    fn test_watchdog_leaves_a_reused_pid_alone() {
+        let dir = TempDir::new("test-updater").unwrap();
+        let state_path = dir.path().join("update.json");
+        std::fs::write(dir.path().join("itx-daemon"), "new").unwrap();
+        std::fs::write(dir.path().join("itx-daemon.previous"), "old").unwrap();
+        // the daemon is gone and its pid now belongs to an unrelated process
+        let mut unrelated = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let mut state = pending(dir.path(), "9.0.0", UpdatePhase::Staged);
+        state.pid = unrelated.id();
+        state.pid_started = start_time(unrelated.id()).map(|started| started + 1);
+        state.write(&state_path).unwrap();
+
+        run_watchdog(state_path.to_str());
+
+        assert!(unrelated.try_wait().unwrap().is_none());
+        unrelated.kill().unwrap();
+        unrelated.wait().unwrap();
+        let state = PendingUpdate::read(&state_path).unwrap();
+        assert!(matches!(state.phase, UpdatePhase::RolledBack { .. }));
+    }
+
+    #[test]
+    fn test_start_time_identifies_the_process() {
+        let started = start_time(std::process::id());
+        assert!(started.is_some());
+        assert!(is_alive(std::process::id(), started));
+        assert!(!is_alive(
+            std::process::id(),
+            started.map(|started| started + 1)
+        ));
+        assert!(is_alive(std::process::id(), None));
+    }
+
+    #[test]
+    fn test_restart_past_deadline_rolls_back_without_watchdog() {
+        let dir = TempDir::new("test-updater").unwrap();
+        let state_path = dir.path().join("update.json");
+        std::fs::write(dir.path().join("itx-daemon"), "new").unwrap();
+        std::fs::write(dir.path().join("itx-daemon.previous"), "old").unwrap();
+        // the watchdog was killed along with the daemon, which is now started again too late
+        let mut state = pending(dir.path(), VERSION, UpdatePhase::Staged);
+        state.deadline_ms = 1;
+        state.write(&state_path).unwrap();
+
+        let (_updater, restart) = updater_with_restart(&dir, None);
+
+        assert!(restart.is_triggered());
+        assert_eq!(
+            std::fs::read(dir.path().join("itx-daemon")).unwrap(),
+            b"old"
+        );
+        let state = PendingUpdate::read(&state_path).unwrap();
+        assert!(matches!(state.phase, UpdatePhase::RolledBack { .. }));
+    }
+
+    #[test]
+    fn test_restart_before_deadline_replaces_dead_watchdog() {
+        let dir = TempDir::new("test-updater").unwrap();
+        let state_path = dir.path().join("update.json");
+        let started = dir.path().join("watchdog-started");
+        std::fs::write(dir.path().join("itx-daemon"), "new").unwrap();
+        // stands in for the previous version, noting how it was started
+        std::fs::write(
+            dir.path().join("itx-daemon.previous"),
+            format!("#!/bin/sh\necho \"$@\" > {}\n", started.display()),
+        )
+        .unwrap();
+        std::fs::set_permissions(
+            dir.path().join("itx-daemon.previous"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        let mut dead = std::process::Command::new("true").spawn().unwrap();
+        dead.wait().unwrap();
+        let mut state = pending(dir.path(), VERSION, UpdatePhase::Staged);
+        state.deadline_ms = now_ms() + 300_000;
+        state.watchdog_pid = Some(dead.id());
+        state.write(&state_path).unwrap();
+
+        let (_updater, restart) = updater_with_restart(&dir, None);
+
+        assert!(!restart.is_triggered());
+        let state = PendingUpdate::read(&state_path).unwrap();
+        assert_eq!(state.phase, UpdatePhase::Staged);
+        assert_eq!(state.pid, std::process::id());
+        assert_eq!(state.pid_started, start_time(std::process::id()));
+        assert!(state.watchdog_pid.is_some_and(|pid| pid != dead.id()));
+        for _ in 0..50 {
+            if started.exists() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        let args = std::fs::read_to_string(&started).unwrap();
+        assert_eq!(
+            args.trim(),
+            format!("{} {}", WATCHDOG_FLAG, state_path.display())
+        );
+    }
+
+    #[test]
+    fn test_watchdog_leaves_confirmed_update_alone() {
+        let dir = TempDir::new("test-updater").unwrap();
+        std::fs::write(dir.path().join("itx-daemon"), "new").unwrap();
+
+        // no state: the new version already checked in
+        run_watchdog(dir.path().join("update.json").to_str());
+
+        assert_eq!(
+            std::fs::read(dir.path().join("itx-daemon")).unwrap(),
+            b"new"
+        );
+    }
+}
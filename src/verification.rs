@@ -0,0 +1,250 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine as _;
+use log::warn;
+use openssl::pkey::{Id as KeyType, PKey, Public};
+use openssl::sign::Verifier;
+
+use crate::config::SecuritySettings;
+use crate::models::db::commands::Command;
+use crate::models::db::common::{HasId, Id};
+use crate::models::HandlerError;
+
+/**
+ * checks the server's ed25519 signature on a command before it is executed.
+ *
+ * the signature (base64, in `Command.signature`) covers the json array
+ *     [id, name, args, device_id, sandbox, timeout_secs, idempotent]
+ * so a command cannot be altered (e.g. taken out of the sandbox, left to run forever or
+ * marked safe to run again after a crash), or replayed against another device, without the server's
+ * private key. a verifier without a `security.command_public_key` can only be built with
+ * `security.allow_unsigned_commands` set (or as `Default`, in tests) and then lets everything
+ * through.
+ */
+#[derive(Default)]
+pub struct CommandVerifier {
+    public_key: Option<PKey<Public>>,
+    device_id: Id,
+}
+
+impl CommandVerifier {
+    pub fn new(settings: &SecuritySettings, device_id: &Id) -> Result<Self, HandlerError> {
+        let public_key = match &settings.command_public_key {
+            Some(encoded) => Some(parse_public_key("security.command_public_key", encoded)?),
+            None if settings.allow_unsigned_commands => {
+                warn!("security.command_public_key is not set, commands are not verified");
+                None
+            }
+            None => {
+                return Err(HandlerError::ConfigError(
+                    "security.command_public_key is not set".to_string(),
+                ))
+            }
+        };
+        Ok(CommandVerifier {
+            public_key,
+            device_id: device_id.clone(),
+        })
+    }
+
+    pub fn verify(&self, command: &Command) -> Result<(), HandlerError> {
+        let public_key = match &self.public_key {
+            Some(public_key) => public_key,
+            None => return Ok(()),
+        };
+
+        let signature = command
+            .signature
+            .as_ref()
+            .ok_or_else(|| invalid("command is not signed"))?;
+        let signature = STANDARD
+            .decode(signature)
+            .map_err(|_| invalid("command signature is not base64"))?;
+        let mut verifier = Verifier::new_without_digest(public_key)?;
+        if !verifier
+            .verify_oneshot(&signature, &signed_payload(command)?)
+            .unwrap_or(false)
+        {
+            return Err(invalid("command signature does not match"));
+        }
+
+        if command.device_id != self.device_id {
+            return Err(invalid(&format!(
+                "command was issued for device {}",
+                command.device_id
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// bytes the server signs for `command`
+pub fn signed_payload(command: &Command) -> Result<Vec<u8>, HandlerError> {
+    let payload = (
+        command.get_id(),
+        command.name.as_str(),
+        &command.args,
+        &command.device_id,
+        command.sandbox,
+        command.timeout_secs,
+        command.idempotent,
+    );
+    Ok(serde_json::to_vec(&payload)?)
+}
+
+/// base64 of the raw 32 byte ed25519 public key, configured as `setting`
+pub fn parse_public_key(setting: &str, encoded: &str) -> Result<PKey<Public>, HandlerError> {
+    let bytes = STANDARD
+        .decode(encoded.trim())
+        .map_err(|_| HandlerError::ConfigError(format!("{} is not base64", setting)))?;
+    PKey::public_key_from_raw_bytes(&bytes, KeyType::ED25519)
+        .map_err(|_| HandlerError::ConfigError(format!("{} is not an ed25519 public key", setting)))
+}
+
+fn invalid(reason: &str) -> HandlerError {
+    HandlerError::InvalidSignature(reason.to_string())
+}
+
+#[cfg(test)]
+pub mod test_keys {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine as _;
+    use openssl::pkey::{PKey, Private};
+    use openssl::sign::Signer;
+
+    use crate::models::db::commands::Command;
+
+    pub fn generate() -> (PKey<Private>, String) {
+        let key = PKey::generate_ed25519().unwrap();
+        let public = STANDARD.encode(key.raw_public_key().unwrap());
+        (key, public)
+    }
+
+    pub fn sign(key: &PKey<Private>, command: &mut Command) {
+        let mut signer = Signer::new_without_digest(key).unwrap();
+        let payload = super::signed_payload(command).unwrap();
+        command.signature = Some(STANDARD.encode(signer.sign_oneshot_to_vec(&payload).unwrap()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        config::SecuritySettings,
+        models::{
+            db::commands::{Command, CommandNames},
+            HandlerError,
+        },
+    };
+
+    use super::{test_keys, CommandVerifier};
+
+    fn shell_command(device_id: &str) -> Command {
+        let mut command = Command::default();
+        command.name = CommandNames::ShellCmd;
+        command.args = Some("echo hi".to_string());
+        command.device_id = device_id.to_string();
+        command
+    }
+
+    fn get_verifier(public_key: String) -> CommandVerifier {
+        let settings = SecuritySettings {
+            command_public_key: Some(public_key),
+            ..Default::default()
+        };
+        CommandVerifier::new(&settings, &"testdeviceid".to_string()).unwrap()
+    }
+
+    #[test]
+    fn test_signed_command_is_accepted() {
+        let (key, public_key) = test_keys::generate();
+        let mut command = shell_command("testdeviceid");
+        test_keys::sign(&key, &mut command);
+
+        assert!(get_verifier(public_key).verify(&command).is_ok());
+    }
+
+    #[test]
+    fn test_unsigned_or_tampered_command_is_rejected() {
+        let (key, public_key) = test_keys::generate();
+        let verifier = get_verifier(public_key);
+
+        let command = shell_command("testdeviceid");
+        assert!(matches!(
+            verifier.verify(&command),
+            Err(HandlerError::InvalidSignature(_))
+        ));
+
+        let mut command = shell_command("testdeviceid");
+        test_keys::sign(&key, &mut command);
+        command.args = Some("rm -rf /".to_string());
+        assert!(matches!(
+            verifier.verify(&command),
+            Err(HandlerError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_sandbox_and_timeout_are_signed() {
+        let (key, public_key) = test_keys::generate();
+        let verifier = get_verifier(public_key);
+        let mut command = shell_command("testdeviceid");
+        command.sandbox = true;
+        command.timeout_secs = Some(60);
+        test_keys::sign(&key, &mut command);
+        assert!(verifier.verify(&command).is_ok());
+
+        let mut unsandboxed = command.clone();
+        unsandboxed.sandbox = false;
+        assert!(verifier.verify(&unsandboxed).is_err());
+
+        let mut untimed = command.clone();
+        untimed.timeout_secs = None;
+        assert!(verifier.verify(&untimed).is_err());
+    }
+
+    #[test]
+    fn test_idempotent_flag_is_signed() {
+        let (key, public_key) = test_keys::generate();
+        let verifier = get_verifier(public_key);
+        let mut command = shell_command("testdeviceid");
+        test_keys::sign(&key, &mut command);
+
+        command.idempotent = true;
+        assert!(verifier.verify(&command).is_err());
+    }
+
+    #[test]
+    fn test_command_signed_by_another_key_is_rejected() {
+        let (_, public_key) = test_keys::generate();
+        let (other_key, _) = test_keys::generate();
+        let mut command = shell_command("testdeviceid");
+        test_keys::sign(&other_key, &mut command);
+
+        assert!(get_verifier(public_key).verify(&command).is_err());
+    }
+
+    #[test]
+    fn test_command_for_another_device_is_rejected() {
+        let (key, public_key) = test_keys::generate();
+        let mut command = shell_command("otherdeviceid");
+        test_keys::sign(&key, &mut command);
+
+        assert!(get_verifier(public_key).verify(&command).is_err());
+    }
+
+    #[test]
+    fn test_key_required_unless_unsigned_commands_allowed() {
+        let device_id = "testdeviceid".to_string();
+        assert!(matches!(
+            CommandVerifier::new(&SecuritySettings::default(), &device_id),
+            Err(HandlerError::ConfigError(_))
+        ));
+
+        let settings = SecuritySettings {
+            allow_unsigned_commands: true,
+            ..Default::default()
+        };
+        let verifier = CommandVerifier::new(&settings, &device_id).unwrap();
+        assert!(verifier.verify(&shell_command("otherdeviceid")).is_ok());
+    }
+}
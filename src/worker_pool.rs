@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::FutureExt as _;
+use log::{debug, error, info, warn};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+
+use crate::config::ExecutorSettings;
+use crate::executor::CancelSignal;
+use crate::models::db::commands::Command;
+use crate::models::db::common::{HasId, Id};
+
+/**
+ * bounded pool of executor workers:
+ * - the fetcher reserves a slot before pulling a new command, so it stops fetching while
+ *   `max_concurrent` commands are in flight
+ * - each command then runs on its own task, which additionally waits on the per-command-name
+ *   limit (e.g. only one `Update` at a time) before starting. it gives its slot back while it
+ *   waits and takes a new one once the limit lets it through, so commands queued behind a
+ *   limit do not keep others from running
+ * - every in-flight command gets a `CancelSignal`, fired through `Canceller::cancel`
+ */
+pub struct WorkerPool {
+    slots: Arc<Semaphore>,
+    per_command: HashMap<String, Arc<Semaphore>>,
+    in_flight: InFlight,
+    workers: JoinSet<()>,
+}
+
+type InFlight = Arc<Mutex<HashMap<Id, (Command, watch::Sender<bool>)>>>;
+
+/// takes a command out of `InFlight` when its worker ends, even by panicking or being aborted
+struct InFlightGuard {
+    in_flight: InFlight,
+    command_id: Id,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.command_id);
+    }
+}
+
+/// cancels in-flight commands from outside the pool
+#[derive(Clone)]
+pub struct Canceller {
+    in_flight: InFlight,
+}
+
+impl Canceller {
+    /// false when the command is not (or no longer) in flight
+    pub fn cancel(&self, command_id: &Id) -> bool {
+        match self.in_flight.lock().unwrap().get(command_id) {
+            Some((_, cancel)) => {
+                let _ = cancel.send(true);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.in_flight.lock().unwrap().is_empty()
+    }
+}
+
+impl WorkerPool {
+    pub fn new(settings: &ExecutorSettings) -> Self {
+        let per_command = settings
+            .per_command_limits
+            .iter()
+            .map(|(name, limit)| (name.clone(), Arc::new(Semaphore::new(*limit))))
+            .collect();
+        WorkerPool {
+            slots: Arc::new(Semaphore::new(settings.max_concurrent)),
+            per_command,
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+            workers: JoinSet::new(),
+        }
+    }
+
+    /// waits until a worker slot is free
+    pub async fn reserve(&self) -> OwnedSemaphorePermit {
+        self.slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("worker pool semaphore is never closed")
+    }
+
+    pub fn is_in_flight(&self, command_id: &Id) -> bool {
+        self.in_flight.lock().unwrap().contains_key(command_id)
+    }
+
+    pub fn canceller(&self) -> Canceller {
+        Canceller {
+            in_flight: self.in_flight.clone(),
+        }
+    }
+
+    /// number of commands queued or running
+    pub fn active(&mut self) -> usize {
+        self.reap();
+        self.workers.len()
+    }
+
+    pub fn spawn<F, Fut>(&mut self, slot: OwnedSemaphorePermit, command: Command, job: F)
+    where
+        F: FnOnce(Command, CancelSignal) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.reap();
+        let command_id = command.get_id().clone();
+        let (cancel_tx, cancel) = CancelSignal::channel();
+        self.in_flight
+            .lock()
+            .unwrap()
+            .insert(command_id.clone(), (command.clone(), cancel_tx));
+
+        let limit = self.per_command.get(command.name.as_str()).cloned();
+        let slots = self.slots.clone();
+        let guard = InFlightGuard {
+            in_flight: self.in_flight.clone(),
+            command_id,
+        };
+        self.workers.spawn(async move {
+            let guard = guard;
+            let (_slot, _limit) = match limit {
+                None => (slot, None),
+                Some(limit) => match limit.clone().try_acquire_owned() {
+                    Ok(permit) => (slot, Some(permit)),
+                    Err(_) => {
+                        debug!(
+                            "waiting on {} limit for {}",
+                            command.name.as_str(),
+                            &guard.command_id
+                        );
+                        drop(slot);
+                        let permit = limit.acquire_owned().await.expect("never closed");
+                        let slot = slots.acquire_owned().await.expect("never closed");
+                        (slot, Some(permit))
+                    }
+                },
+            };
+            job(command, cancel).await;
+        });
+    }
+
+    /**
+     * waits up to `deadline` for every queued or running command to finish. anything still
+     * running afterwards is aborted (killing its child process) and returned so the caller
+     * can report it.
+     */
+    pub async fn drain(&mut self, deadline: Duration) -> Vec<Command> {
+        info!(
+            "waiting up to {:?} for {} commands",
+            deadline,
+            self.workers.len()
+        );
+        let workers = &mut self.workers;
+        let finished = tokio::time::timeout(deadline, async {
+            while let Some(joined) = workers.join_next().await {
+                if let Err(e) = joined {
+                    error!("command worker panicked: {:?}", e);
+                }
+            }
+        })
+        .await
+        .is_ok();
+        if finished {
+            return vec![];
+        }
+
+        // aborting drops the workers' in-flight entries, so collect them first
+        let abandoned: Vec<Command> = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, (command, _))| command)
+            .collect();
+        self.workers.abort_all();
+        while self.workers.join_next().await.is_some() {}
+        warn!("abandoned {} commands at drain deadline", abandoned.len());
+        abandoned
+    }
+
+    fn reap(&mut self) {
+        while let Some(Some(joined)) = self.workers.join_next().now_or_never() {
+            if let Err(e) = joined {
+                error!("command worker panicked: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::{
+        config::ExecutorSettings,
+        models::db::{
+            commands::{Command, CommandNames},
+            common::HasId,
+        },
+    };
+
+    use super::WorkerPool;
+
+    fn get_settings(max_concurrent: usize, update_limit: usize) -> ExecutorSettings {
+        ExecutorSettings {
+            max_concurrent,
+            per_command_limits: HashMap::from([("Update".to_string(), update_limit)]),
+            ..Default::default()
+        }
+    }
+
+    fn command_named(name: CommandNames) -> Command {
+        let mut command = Command::default();
+        command.name = name;
+        command
+    }
+
+    /// runs six commands and returns the peak number running at once
+    async fn peak_concurrency(pool: &mut WorkerPool, name: fn() -> CommandNames) -> usize {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        for _ in 0..6 {
+            let slot = pool.reserve().await;
+            let (running, peak) = (running.clone(), peak.clone());
+            pool.spawn(slot, command_named(name()), move |_, _| async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+        while pool.active() > 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        peak.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn test_global_limit() {
+        let mut pool = WorkerPool::new(&get_settings(3, 1));
+
+        let peak = peak_concurrency(&mut pool, || CommandNames::ShellCmd).await;
+
+        assert_eq!(peak, 3);
+    }
+
+    #[tokio::test]
+    async fn test_per_command_limit() {
+        let mut pool = WorkerPool::new(&get_settings(3, 1));
+
+        let peak = peak_concurrency(&mut pool, || CommandNames::Update).await;
+
+        assert_eq!(peak, 1);
+    }
+
+    #[tokio::test]
+    async fn test_commands_queued_on_a_limit_do_not_hold_slots() {
+        let mut pool = WorkerPool::new(&get_settings(2, 1));
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+
+        let slot = pool.reserve().await;
+        pool.spawn(
+            slot,
+            command_named(CommandNames::Update),
+            move |_, _| async move {
+                let _ = rx.await;
+            },
+        );
+        for _ in 0..3 {
+            let slot = pool.reserve().await;
+            pool.spawn(slot, command_named(CommandNames::Update), |_, _| async {});
+        }
+
+        let (ran_tx, ran_rx) = tokio::sync::oneshot::channel::<()>();
+        let slot = tokio::time::timeout(Duration::from_secs(5), pool.reserve())
+            .await
+            .expect("a slot is free while updates are queued");
+        pool.spawn(
+            slot,
+            command_named(CommandNames::ShellCmd),
+            |_, _| async move {
+                let _ = ran_tx.send(());
+            },
+        );
+        tokio::time::timeout(Duration::from_secs(5), ran_rx)
+            .await
+            .unwrap()
+            .unwrap();
+
+        tx.send(()).unwrap();
+        assert!(pool.drain(Duration::from_secs(5)).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_panicking_command_leaves_in_flight() {
+        let mut pool = WorkerPool::new(&get_settings(2, 1));
+        let command = Command::default();
+        let command_id = command.get_id().clone();
+
+        let slot = pool.reserve().await;
+        pool.spawn(slot, command, |_, _| async { panic!("worker failed") });
+
+        assert!(pool.drain(Duration::from_secs(5)).await.is_empty());
+        assert!(!pool.is_in_flight(&command_id));
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_tracking() {
+        let mut pool = WorkerPool::new(&get_settings(2, 1));
+        let command = Command::default();
+        let command_id = command.get_id().clone();
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+
+        let slot = pool.reserve().await;
+        pool.spawn(slot, command, move |_, _| async move {
+            let _ = rx.await;
+        });
+        assert!(pool.is_in_flight(&command_id));
+
+        tx.send(()).unwrap();
+        while pool.active() > 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(!pool.is_in_flight(&command_id));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_signals_in_flight_command() {
+        let mut pool = WorkerPool::new(&get_settings(2, 1));
+        let command = Command::default();
+        let command_id = command.get_id().clone();
+        let canceller = pool.canceller();
+
+        let slot = pool.reserve().await;
+        pool.spawn(slot, command, |_, cancel| async move {
+            cancel.cancelled().await;
+        });
+        assert!(!canceller.is_idle());
+        assert!(canceller.cancel(&command_id));
+
+        let abandoned = pool.drain(Duration::from_secs(5)).await;
+        assert!(abandoned.is_empty());
+        assert!(canceller.is_idle());
+        assert!(!canceller.cancel(&command_id));
+    }
+
+    #[tokio::test]
+    async fn test_drain_waits_for_commands() {
+        let mut pool = WorkerPool::new(&get_settings(2, 1));
+
+        let slot = pool.reserve().await;
+        pool.spawn(slot, Command::default(), |_, _| async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        });
+
+        let abandoned = pool.drain(Duration::from_secs(5)).await;
+        assert!(abandoned.is_empty());
+        assert_eq!(pool.active(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_drain_abandons_commands_past_deadline() {
+        let mut pool = WorkerPool::new(&get_settings(2, 1));
+        let command = Command::default();
+        let command_id = command.get_id().clone();
+
+        let slot = pool.reserve().await;
+        pool.spawn(slot, command, |_, _| async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let abandoned = pool.drain(Duration::from_millis(20)).await;
+        assert_eq!(abandoned.len(), 1);
+        assert_eq!(abandoned[0].get_id(), &command_id);
+        assert!(!pool.is_in_flight(&command_id));
+    }
+}